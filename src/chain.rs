@@ -1,3 +1,4 @@
+use crate::db::{seed_from_record, PackedSeeds, SEED_RECORD_SIZE};
 use crate::params::*;
 use crate::types::*;
 use bio::data_structures::interval_tree::IntervalTree;
@@ -99,14 +100,36 @@ pub fn map_params_from_sketch(
     ref_sketch: &Sketch,
     amino_acid: bool,
     command_params: &CommandParams,
+) -> MapParams {
+    map_params_from_values(
+        ref_sketch.c,
+        ref_sketch.k,
+        ref_sketch.total_sequence_length,
+        amino_acid,
+        command_params.min_aligned_frac,
+        command_params.robust,
+        command_params.median,
+    )
+}
+
+//Same as map_params_from_sketch, but without needing a CommandParams. Used by the library API
+//where there is no command line.
+pub fn map_params_from_values(
+    c: usize,
+    k: usize,
+    total_sequence_length: usize,
+    amino_acid: bool,
+    min_aligned_frac: f64,
+    robust: bool,
+    median: bool,
 ) -> MapParams {
     let max_gap_length = if amino_acid{D_MAX_GAP_LENGTH_AAI} else {D_MAX_GAP_LENGTH};
     let anchor_score = if amino_acid{D_ANCHOR_SCORE_AAI} else {D_ANCHOR_SCORE_ANI};
     let min_anchors = if amino_acid{D_MIN_ANCHORS_AAI} else {D_MIN_ANCHORS_ANI};
     let min_length_cover = if amino_acid{MIN_LENGTH_COVER_AAI} else {MIN_LENGTH_COVER};
-    let fragment_length = fragment_length_formula(ref_sketch.total_sequence_length, amino_acid);
+    let fragment_length = fragment_length_formula(total_sequence_length, amino_acid);
     let length_cutoff = fragment_length;
-    let mut frac_cover_cutoff = command_params.min_aligned_frac;
+    let mut frac_cover_cutoff = min_aligned_frac;
     if frac_cover_cutoff < 0.{
         if amino_acid {
             frac_cover_cutoff = D_FRAC_COVER_CUTOFF_AA.parse::<f64>().unwrap()/100.;
@@ -116,10 +139,9 @@ pub fn map_params_from_sketch(
     }
     let length_cover_cutoff = 5000000;
     let bp_chain_band = if amino_acid {BP_CHAIN_BAND_AAI} else {BP_CHAIN_BAND};
-    let index_chain_band = bp_chain_band/ref_sketch.c;
+    let index_chain_band = bp_chain_band/c;
     let min_score = min_anchors as f64 * anchor_score * 0.75;
 //    let min_score = 0.;
-    let k = ref_sketch.k;
     MapParams {
         fragment_length,
         max_gap_length,
@@ -132,8 +154,8 @@ pub fn map_params_from_sketch(
         k,
         amino_acid,
         min_score,
-        robust: command_params.robust,
-        median: command_params.median,
+        robust,
+        median,
         bp_chain_band,
         min_length_cover,
     }
@@ -144,25 +166,201 @@ pub fn chain_seeds(
     query_sketch: &Sketch,
     map_params: MapParams,
 ) -> AniEstResult {
-    let (anchor_chunks, switched) = get_anchors(ref_sketch, query_sketch, &map_params);
-    let chain_results = chain_anchors_ani(&anchor_chunks, &map_params);
+    chain_seeds_intervals(ref_sketch, query_sketch, &map_params).0
+}
+
+//Same as chain_seeds, but also returns every chain used for the ANI estimate
+//(see get_nonoverlapping_chains) and the identity of every fragment that
+//passed filtering, in the query's and reference's coordinates.
+pub fn chain_seeds_detailed(
+    ref_sketch: &Sketch,
+    query_sketch: &Sketch,
+    map_params: MapParams,
+) -> (AniEstResult, AlignmentDetails) {
+    let (ani_res, int_chunks, anchor_chunks, fragment_anis, switched) =
+        chain_seeds_intervals(ref_sketch, query_sketch, &map_params);
+    //Chains, fragments and aligned regions are padded the same way as the
+    //aligned fraction in calculate_ani: k + c before the first seed and c after
+    //the last, so the aligned regions add up to it.
+    let af_padding = map_params.k as GnPosition + ref_sketch.c as GnPosition;
+    let to_range = |interval: (GnPosition, GnPosition), contig_len: GnPosition| {
+        (
+            (interval.0 + 1).saturating_sub(af_padding),
+            GnPosition::min(interval.1 + 1 + ref_sketch.c as GnPosition, contig_len),
+        )
+    };
+    //The sketch with longer contigs is used as the "query" for chaining, so
+    //chunks tile the reference if switched.
+    let (chunked_sketch, other_sketch) = if switched {
+        (ref_sketch, query_sketch)
+    } else {
+        (query_sketch, ref_sketch)
+    };
+
+    let mut details = AlignmentDetails {
+        query_file: query_sketch.file_name.clone(),
+        ref_file: ref_sketch.file_name.clone(),
+        ..Default::default()
+    };
+    let mut query_covered = vec![];
+    let mut ref_covered = vec![];
+    for (i, intervals) in int_chunks.iter().enumerate() {
+        let fragment_identity = if fragment_anis[i].is_nan() {
+            None
+        } else {
+            Some(fragment_anis[i] as f32)
+        };
+        for int in intervals {
+            let (q_contig, q_interval, r_contig, r_interval) = if switched {
+                (int.ref_contig, int.interval_on_ref, int.query_contig, int.interval_on_query)
+            } else {
+                (int.query_contig, int.interval_on_query, int.ref_contig, int.interval_on_ref)
+            };
+            let query_contig_len = query_sketch.contig_lengths[q_contig];
+            let ref_contig_len = ref_sketch.contig_lengths[r_contig];
+            let (query_start, query_end) = to_range(q_interval, query_contig_len);
+            let (ref_start, ref_end) = to_range(r_interval, ref_contig_len);
+            query_covered.push((q_contig, query_start, query_end));
+            ref_covered.push((r_contig, ref_start, ref_end));
+            details.chains.push(ChainRecord {
+                query_file: query_sketch.file_name.clone(),
+                query_contig: query_sketch.contigs[q_contig].clone(),
+                query_contig_len,
+                query_start,
+                query_end,
+                ref_file: ref_sketch.file_name.clone(),
+                ref_contig: ref_sketch.contigs[r_contig].clone(),
+                ref_contig_len,
+                ref_start,
+                ref_end,
+                reverse: int.reverse_chain,
+                num_anchors: int.num_anchors,
+                score: int.score,
+                fragment_identity,
+            });
+        }
+
+        let identity = match fragment_identity {
+            Some(identity) => identity,
+            None => continue,
+        };
+        let seeds = &anchor_chunks.seeds_in_chunk[i];
+        let chunk_contig = intervals[0].query_contig;
+        let chunk_range = to_range(
+            (*seeds.iter().min().unwrap(), *seeds.iter().max().unwrap()),
+            chunked_sketch.contig_lengths[chunk_contig],
+        );
+        //The other genome's range is where the chains of the fragment land on
+        //the contig with the most anchors.
+        let mut anchors_per_contig = FxHashMap::default();
+        for int in intervals {
+            *anchors_per_contig.entry(int.ref_contig).or_insert(0) += int.num_anchors;
+        }
+        let other_contig = *anchors_per_contig
+            .iter()
+            .max_by_key(|x| (x.1, std::cmp::Reverse(x.0)))
+            .unwrap()
+            .0;
+        let mut other_interval = (GnPosition::MAX, GnPosition::MIN);
+        for int in intervals.iter().filter(|x| x.ref_contig == other_contig) {
+            other_interval.0 = GnPosition::min(other_interval.0, int.interval_on_ref.0);
+            other_interval.1 = GnPosition::max(other_interval.1, int.interval_on_ref.1);
+        }
+        let other_range = to_range(other_interval, other_sketch.contig_lengths[other_contig]);
+        let (q_contig, q_range, r_contig, r_range) = if switched {
+            (other_contig, other_range, chunk_contig, chunk_range)
+        } else {
+            (chunk_contig, chunk_range, other_contig, other_range)
+        };
+        details.fragments.push(FragmentRecord {
+            query_file: query_sketch.file_name.clone(),
+            query_contig: query_sketch.contigs[q_contig].clone(),
+            query_start: q_range.0,
+            query_end: q_range.1,
+            ref_file: ref_sketch.file_name.clone(),
+            ref_contig: ref_sketch.contigs[r_contig].clone(),
+            ref_start: r_range.0,
+            ref_end: r_range.1,
+            identity,
+            num_seeds: seeds.len(),
+            num_anchors: intervals.iter().map(|x| x.num_anchors).sum(),
+            fragmented_query: !switched,
+        });
+    }
+    (details.query_aligned, details.query_unaligned) = merge_regions(query_covered, query_sketch);
+    (details.ref_aligned, details.ref_unaligned) = merge_regions(ref_covered, ref_sketch);
+    (ani_res, details)
+}
+
+//Merges (contig index, start, end) ranges and returns them along with their
+//complement over every contig of the sketch.
+fn merge_regions(
+    mut ranges: Vec<(usize, GnPosition, GnPosition)>,
+    sketch: &Sketch,
+) -> (Vec<GenomeRegion>, Vec<GenomeRegion>) {
+    ranges.sort_unstable();
+    let mut merged: Vec<(usize, GnPosition, GnPosition)> = vec![];
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if last.0 == range.0 && range.1 <= last.2 => {
+                last.2 = GnPosition::max(last.2, range.2);
+            }
+            _ => merged.push(range),
+        }
+    }
+    let region = |contig: usize, start, end| GenomeRegion {
+        contig: sketch.contigs[contig].clone(),
+        start,
+        end,
+    };
+    let mut aligned = vec![];
+    let mut unaligned = vec![];
+    let mut merged_iter = merged.into_iter().peekable();
+    for contig in 0..sketch.contigs.len() {
+        let mut curr = 0;
+        while let Some(range) = merged_iter.next_if(|x| x.0 == contig) {
+            if range.1 > curr {
+                unaligned.push(region(contig, curr, range.1));
+            }
+            curr = range.2;
+            aligned.push(region(contig, range.1, range.2));
+        }
+        if curr < sketch.contig_lengths[contig] {
+            unaligned.push(region(contig, curr, sketch.contig_lengths[contig]));
+        }
+    }
+    (aligned, unaligned)
+}
+
+//Returns the ANI, the non-overlapping chains for each fragment (chunk), the
+//anchor chunks, the identity estimate of each fragment (NaN if the fragment
+//was not used) and whether the query and reference were switched for chaining.
+fn chain_seeds_intervals(
+    ref_sketch: &Sketch,
+    query_sketch: &Sketch,
+    map_params: &MapParams,
+) -> (AniEstResult, Vec<Vec<ChainInterval>>, AnchorChunks, Vec<f64>, bool) {
+    let (anchor_chunks, switched) = get_anchors(ref_sketch, query_sketch, map_params);
+    let chain_results = chain_anchors_ani(&anchor_chunks, map_params);
     let mut good_intervals = vec![];
     for i in 0..anchor_chunks.chunks.len() {
         let chain_result = &chain_results[i];
         let anchors = &anchor_chunks.chunks[i];
-        get_chain_intervals(&mut good_intervals, chain_result, anchors, &map_params, i);
+        get_chain_intervals(&mut good_intervals, chain_result, anchors, map_params, i);
     }
     let good_interval_chunks =
         get_nonoverlapping_chains(&mut good_intervals, anchor_chunks.chunks.len());
+    let mut fragment_anis = vec![f64::NAN; good_interval_chunks.len()];
     let ani = calculate_ani(
         &good_interval_chunks,
         ref_sketch,
         query_sketch,
         &anchor_chunks,
-        &map_params,
+        map_params,
         switched,
+        &mut fragment_anis,
     );
-    ani
+    (ani, good_interval_chunks, anchor_chunks, fragment_anis, switched)
 }
 
 fn calculate_ani(
@@ -172,6 +370,7 @@ fn calculate_ani(
     anchor_chunks: &AnchorChunks,
     map_params: &MapParams,
     switched: bool,
+    fragment_anis: &mut [f64],
 ) -> AniEstResult {
     let k = map_params.k;
     let mut ani_ests = vec![];
@@ -381,6 +580,7 @@ fn calculate_ani(
         //        total_ref_range += total_bases_contained_ref;
 
         //        ani_ests.push((ani_est, anchor_chunks.seeds_in_chunk[i]));
+        fragment_anis[i] = ani_est;
         if map_params.amino_acid {
 //            ani_ests.push((ani_est, anchor_chunks.seeds_in_chunk[i].len() / 6));
             ani_ests.push((ani_est, anchor_chunks.seeds_in_chunk[i].len() / 6));
@@ -628,6 +828,66 @@ pub fn check_markers_quickly(
     false
 }
 
+//Seeds of one genome: the KmerSeeds map of a sketch, or the sorted records of
+//a reference in a packed database.
+enum SeedTable<'a> {
+    Map(&'a KmerSeeds),
+    Packed(&'a PackedSeeds),
+}
+
+enum Positions<'a> {
+    Slice(&'a [SeedPosition]),
+    Records(&'a [u8]),
+}
+
+impl<'a> Positions<'a> {
+    fn len(&self) -> usize {
+        match self {
+            Positions::Slice(x) => x.len(),
+            Positions::Records(x) => x.len() / SEED_RECORD_SIZE,
+        }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = SeedPosition> + 'a {
+        let (slice, records) = match *self {
+            Positions::Slice(x) => (Some(x.iter().cloned()), None),
+            Positions::Records(x) => (None, Some(x.chunks_exact(SEED_RECORD_SIZE).map(seed_from_record))),
+        };
+        slice.into_iter().flatten().chain(records.into_iter().flatten())
+    }
+}
+
+impl<'a> SeedTable<'a> {
+    fn of(sketch: &'a Sketch) -> SeedTable<'a> {
+        match &sketch.packed_seeds {
+            Some(x) => SeedTable::Packed(x),
+            None => SeedTable::Map(sketch.kmer_seeds_k.as_ref().unwrap()),
+        }
+    }
+
+    //Number of distinct k-mers.
+    fn len(&self) -> usize {
+        match self {
+            SeedTable::Map(x) => x.len(),
+            SeedTable::Packed(x) => x.iter().count(),
+        }
+    }
+
+    fn get(&self, kmer: &SeedBits) -> Option<Positions<'a>> {
+        match *self {
+            SeedTable::Map(x) => x.get(kmer).map(|x| Positions::Slice(x)),
+            SeedTable::Packed(x) => x.get(*kmer).map(Positions::Records),
+        }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (SeedBits, Positions<'a>)> + 'a {
+        let (map, packed) = match *self {
+            SeedTable::Map(x) => (Some(x.iter().map(|(k, v)| (*k, Positions::Slice(v)))), None),
+            SeedTable::Packed(x) => (None, Some(x.iter().map(|(k, v)| (k, Positions::Records(v))))),
+        };
+        map.into_iter().flatten().chain(packed.into_iter().flatten())
+    }
+}
 
 fn get_anchors(
     ref_sketch: &Sketch,
@@ -664,16 +924,16 @@ fn get_anchors(
         if !check_markers_quickly(query_sketch, ref_sketch, 0.0){
             return (AnchorChunks::default(), switched);
         }
-        kmer_seeds_ref = query_sketch.kmer_seeds_k.as_ref().unwrap();
-        kmer_seeds_query = ref_sketch.kmer_seeds_k.as_ref().unwrap();
+        kmer_seeds_ref = SeedTable::of(query_sketch);
+        kmer_seeds_query = SeedTable::of(ref_sketch);
         query_positions_all = vec![vec![]; ref_sketch.contigs.len()];
     } else {
         switched = false;
         if !check_markers_quickly(ref_sketch, query_sketch, 0.0){
             return (AnchorChunks::default(), switched);
         }
-        kmer_seeds_ref = ref_sketch.kmer_seeds_k.as_ref().unwrap();
-        kmer_seeds_query = query_sketch.kmer_seeds_k.as_ref().unwrap();
+        kmer_seeds_ref = SeedTable::of(ref_sketch);
+        kmer_seeds_query = SeedTable::of(query_sketch);
         query_positions_all = vec![vec![]; query_sketch.contigs.len()];
     }
     //    let kmer_seeds_ref = &ref_sketch.kmer_seeds_k[k];
@@ -684,15 +944,7 @@ fn get_anchors(
         if query_pos.len() > map_params.index_chain_band{
             continue;
         }
-        let contains = kmer_seeds_ref.contains_key(canon_kmer);
-
-        if !contains {
-            for qpos in query_pos.iter() {
-                query_positions_all[qpos.contig_index as usize].push(qpos.pos);
-            }
-        } else {
-            let ref_pos = &kmer_seeds_ref[canon_kmer];
-
+        if let Some(ref_pos) = kmer_seeds_ref.get(&canon_kmer) {
             if ref_pos.len() > map_params.index_chain_band{
                 continue;
             }
@@ -702,8 +954,8 @@ fn get_anchors(
             }
 
             query_kmers_with_hits += 1;
-            for qpos in query_pos {
-                for rpos in ref_pos {
+            for qpos in query_pos.iter() {
+                for rpos in ref_pos.iter() {
                     anchors.push(Anchor::new(
                         &(rpos.pos, rpos.contig_index),
                         &(qpos.pos, qpos.contig_index),
@@ -713,6 +965,10 @@ fn get_anchors(
                     ));
                 }
             }
+        } else {
+            for qpos in query_pos.iter() {
+                query_positions_all[qpos.contig_index as usize].push(qpos.pos);
+            }
         }
     }
     if anchors.is_empty() {
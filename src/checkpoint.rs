@@ -0,0 +1,123 @@
+use crate::params::*;
+use crate::types::*;
+use fxhash::FxHashSet;
+use log::*;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+
+//Names of the queries (dist, search) or rows (triangle) whose results are
+//already in the output file, one per line in <output>.checkpoint. Names are
+//appended after every intermediate write, so `--resume` can skip them and
+//append to the output. A run killed in the middle of a write may have the
+//results of its last batch written twice. The file is removed once the run
+//finishes.
+pub struct Checkpoint {
+    file: Option<File>,
+    file_name: String,
+    done: FxHashSet<String>,
+    resumed: bool,
+}
+
+pub fn checkpoint_file(out_file_name: &str) -> String {
+    format!("{}.checkpoint", out_file_name)
+}
+
+//Queries are named by file, or by file and contig with -i.
+pub fn query_name(sketch: &Sketch, individual_contig: bool) -> String {
+    if individual_contig {
+        format!("{}\t{}", sketch.file_name, sketch.contigs[0])
+    } else {
+        sketch.file_name.clone()
+    }
+}
+
+impl Checkpoint {
+    //Nothing is recorded if `enabled` is false, e.g. for stdout or JSON output.
+    pub fn open(command_params: &CommandParams, enabled: bool) -> Checkpoint {
+        let mut checkpoint = Checkpoint {
+            file: None,
+            file_name: String::new(),
+            done: FxHashSet::default(),
+            resumed: false,
+        };
+        if !enabled {
+            return checkpoint;
+        }
+        let file_name = checkpoint_file(&command_params.out_file_name);
+        if command_params.resume {
+            if !Path::new(&command_params.out_file_name).exists() {
+                warn!("{} not found; starting from the beginning.", command_params.out_file_name);
+            } else if let Ok(file) = File::open(&file_name) {
+                for line in BufReader::new(file).lines() {
+                    match line {
+                        Ok(line) => {
+                            checkpoint.done.insert(line);
+                        }
+                        Err(e) => {
+                            error!("Could not read {}: {}. Exiting.", file_name, e);
+                            std::process::exit(1)
+                        }
+                    }
+                }
+                checkpoint.resumed = true;
+                info!(
+                    "Resuming from {}; {} finished queries are skipped.",
+                    file_name,
+                    checkpoint.done.len()
+                );
+            } else {
+                warn!("{} not found; starting from the beginning.", file_name);
+            }
+        }
+        let file = if checkpoint.resumed {
+            OpenOptions::new().append(true).open(&file_name)
+        } else {
+            File::create(&file_name)
+        };
+        match file {
+            Ok(file) => {
+                checkpoint.file = Some(file);
+                checkpoint.file_name = file_name;
+            }
+            Err(e) => {
+                error!("Could not create {}: {}. Exiting.", file_name, e);
+                std::process::exit(1)
+            }
+        }
+        checkpoint
+    }
+
+    //True if the output file is being appended to.
+    pub fn resumed(&self) -> bool {
+        self.resumed
+    }
+
+    pub fn is_done(&self, name: &str) -> bool {
+        self.done.contains(name)
+    }
+
+    //Call once the results for `names` are written to the output file.
+    pub fn record(&mut self, names: &[String]) {
+        if let Some(file) = self.file.as_mut() {
+            let mut lines = String::new();
+            for name in names.iter() {
+                lines.push_str(name);
+                lines.push('\n');
+            }
+            if let Err(e) = file.write_all(lines.as_bytes()).and_then(|_| file.flush()) {
+                warn!("Could not write the checkpoint file: {}", e);
+            }
+        }
+    }
+
+    //Call after the last results are written; a finished run has nothing
+    //left to resume.
+    pub fn finish(mut self) {
+        if self.file.take().is_some() {
+            if let Err(e) = fs::remove_file(&self.file_name) {
+                warn!("Could not remove {}: {}", self.file_name, e);
+            }
+        }
+    }
+}
@@ -0,0 +1,399 @@
+use crate::chain;
+use crate::file_io;
+use crate::params::*;
+use crate::regression;
+use crate::screen;
+use crate::types::*;
+use fxhash::{FxHashMap, FxHashSet};
+use log::*;
+use rayon::prelude::*;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::sync::Mutex;
+use std::time::Instant;
+
+//A pair of genomes passing the ANI and AF thresholds; i < j.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClusterEdge {
+    pub i: usize,
+    pub j: usize,
+    pub ani: f32,
+    pub af_i: f32,
+    pub af_j: f32,
+}
+
+//Runs the triangle comparisons (screen_refs then chain_seeds) but only keeps
+//pairs with ANI >= min_ani and aligned fraction >= min_aligned_frac for both
+//genomes, so memory scales with the number of passing pairs.
+pub fn passing_edges(
+    sketches: &[Sketch],
+    sketch_params: &SketchParams,
+    command_params: &CommandParams,
+    min_ani: f64,
+) -> Vec<ClusterEdge> {
+    let screen_val = if command_params.screen_val == 0. {
+        let default = if sketch_params.use_aa {
+            SEARCH_AAI_CUTOFF_DEFAULT
+        } else {
+            SEARCH_ANI_CUTOFF_DEFAULT
+        };
+        f64::min(default, min_ani - 0.05)
+    } else {
+        command_params.screen_val
+    };
+    let model = regression::get_model(sketch_params.c, command_params.learned_ani);
+    if model.is_some() {
+        info!("{}", LEARNED_INFO_HELP);
+    }
+    let kmer_to_sketch = screen::kmer_to_sketch_from_refs(sketches);
+    let edges: Mutex<Vec<ClusterEdge>> = Mutex::new(vec![]);
+    let counter: Mutex<usize> = Mutex::new(0);
+    (0..sketches.len().saturating_sub(1))
+        .collect::<Vec<usize>>()
+        .into_par_iter()
+        .for_each(|i| {
+            let screened_refs = screen::screen_refs(
+                screen_val,
+                &kmer_to_sketch,
+                &sketches[i],
+                sketch_params,
+                sketches,
+            );
+            screened_refs.into_par_iter().for_each(|j| {
+                if j <= i {
+                    return;
+                }
+                let map_params =
+                    chain::map_params_from_sketch(&sketches[i], sketch_params.use_aa, command_params);
+                let mut ani_res = chain::chain_seeds(&sketches[i], &sketches[j], map_params);
+                if ani_res.ani > 0.1 {
+                    if command_params.learned_ani {
+                        regression::predict_from_ani_res(&mut ani_res, model.as_ref().unwrap());
+                    }
+                    let min_af = f32::min(ani_res.align_fraction_ref, ani_res.align_fraction_query);
+                    if ani_res.ani as f64 >= min_ani
+                        && min_af as f64 >= command_params.min_aligned_frac
+                    {
+                        edges.lock().unwrap().push(ClusterEdge {
+                            i,
+                            j,
+                            ani: ani_res.ani,
+                            af_i: ani_res.align_fraction_ref,
+                            af_j: ani_res.align_fraction_query,
+                        });
+                    }
+                }
+            });
+            let mut locked = counter.lock().unwrap();
+            *locked += 1;
+            if locked.is_multiple_of(100) {
+                info!("{} query sequences processed.", *locked);
+            }
+        });
+    let mut edges = edges.into_inner().unwrap();
+    edges.sort_by_key(|x| (x.i, x.j));
+    edges
+}
+
+//Pairs of a binary sparse matrix (`skani triangle --binary` or `--stream`)
+//passing the same thresholds as passing_edges.
+pub fn matrix_edges(matrix: &file_io::SparseCoo, min_ani: f64, min_aligned_frac: f64) -> Vec<ClusterEdge> {
+    matrix
+        .entries
+        .iter()
+        .filter(|x| {
+            x.ani as f64 >= min_ani && f32::min(x.af_i, x.af_j) as f64 >= min_aligned_frac
+        })
+        .map(|x| ClusterEdge {
+            i: x.i as usize,
+            j: x.j as usize,
+            ani: x.ani,
+            af_i: x.af_i,
+            af_j: x.af_j,
+        })
+        .collect()
+}
+
+//Genome lengths are not stored in a sparse matrix, so genomes with more
+//passing pairs come first instead, then genomes earlier in the matrix.
+pub fn degree_order(num_genomes: usize, edges: &[ClusterEdge]) -> Vec<usize> {
+    let mut degree = vec![0; num_genomes];
+    for edge in edges.iter() {
+        degree[edge.i] += 1;
+        degree[edge.j] += 1;
+    }
+    let mut order = (0..num_genomes).collect::<Vec<usize>>();
+    order.sort_by(|x, y| degree[*y].cmp(&degree[*x]).then(x.cmp(y)));
+    order
+}
+
+//Returns the representative of every genome. `order` ranks the genomes, best
+//first; greedy clustering visits genomes in this order and it breaks ties when
+//picking representatives for the linkage methods.
+pub fn cluster_genomes(
+    num_genomes: usize,
+    edges: &[ClusterEdge],
+    method: ClusterMethod,
+    order: &[usize],
+) -> Vec<usize> {
+    let mut rank = vec![0; num_genomes];
+    for (r, i) in order.iter().enumerate() {
+        rank[*i] = r;
+    }
+    if method == ClusterMethod::Greedy {
+        return greedy_centroids(num_genomes, edges, &rank, order);
+    }
+
+    let mut cluster_of = (0..num_genomes).collect::<Vec<usize>>();
+    let mut members = (0..num_genomes).map(|x| vec![x]).collect::<Vec<Vec<usize>>>();
+    let edge_set = edges.iter().map(|x| (x.i, x.j)).collect::<FxHashSet<_>>();
+    let mut sorted_edges = edges.iter().collect::<Vec<&ClusterEdge>>();
+    sorted_edges.sort_by(|x, y| y.ani.partial_cmp(&x.ani).unwrap().then((x.i, x.j).cmp(&(y.i, y.j))));
+    for edge in sorted_edges {
+        let (ci, cj) = (cluster_of[edge.i], cluster_of[edge.j]);
+        if ci == cj {
+            continue;
+        }
+        //Complete linkage only merges clusters if every cross pair passes.
+        if method == ClusterMethod::Complete
+            && !members[ci].iter().all(|x| {
+                members[cj]
+                    .iter()
+                    .all(|y| edge_set.contains(&(usize::min(*x, *y), usize::max(*x, *y))))
+            })
+        {
+            continue;
+        }
+        let (big, small) = if members[ci].len() >= members[cj].len() {
+            (ci, cj)
+        } else {
+            (cj, ci)
+        };
+        let moved = std::mem::take(&mut members[small]);
+        for x in moved.iter() {
+            cluster_of[*x] = big;
+        }
+        members[big].extend(moved);
+    }
+
+    //The representative has the highest summed ANI to the rest of its cluster.
+    let mut ani_sum = vec![0.; num_genomes];
+    for edge in edges.iter() {
+        if cluster_of[edge.i] == cluster_of[edge.j] {
+            ani_sum[edge.i] += edge.ani as f64;
+            ani_sum[edge.j] += edge.ani as f64;
+        }
+    }
+    let mut reps = vec![0; num_genomes];
+    for cluster in members.iter().filter(|x| !x.is_empty()) {
+        let rep = *cluster
+            .iter()
+            .max_by(|x, y| {
+                ani_sum[**x]
+                    .partial_cmp(&ani_sum[**y])
+                    .unwrap()
+                    .then(rank[**y].cmp(&rank[**x]))
+            })
+            .unwrap();
+        for x in cluster.iter() {
+            reps[*x] = rep;
+        }
+    }
+    reps
+}
+
+fn greedy_centroids(
+    num_genomes: usize,
+    edges: &[ClusterEdge],
+    rank: &[usize],
+    order: &[usize],
+) -> Vec<usize> {
+    let mut neighbours: Vec<Vec<(usize, f32)>> = vec![vec![]; num_genomes];
+    for edge in edges.iter() {
+        neighbours[edge.i].push((edge.j, edge.ani));
+        neighbours[edge.j].push((edge.i, edge.ani));
+    }
+    let mut reps = vec![usize::MAX; num_genomes];
+    for i in order.iter() {
+        //Join the most similar existing centroid, or become one.
+        let best = neighbours[*i]
+            .iter()
+            .filter(|x| reps[x.0] == x.0)
+            .max_by(|x, y| x.1.partial_cmp(&y.1).unwrap().then(rank[y.0].cmp(&rank[x.0])));
+        reps[*i] = match best {
+            Some(x) => x.0,
+            None => *i,
+        };
+    }
+    reps
+}
+
+//Genomes with more sequence, then higher N50, come first.
+pub fn length_order(sketches: &[Sketch]) -> Vec<usize> {
+    let n50s = sketches.iter().map(n50).collect::<Vec<GnPosition>>();
+    let mut order = (0..sketches.len()).collect::<Vec<usize>>();
+    order.sort_by(|x, y| {
+        sketches[*y]
+            .total_sequence_length
+            .cmp(&sketches[*x].total_sequence_length)
+            .then(n50s[*y].cmp(&n50s[*x]))
+            .then(x.cmp(y))
+    });
+    order
+}
+
+pub fn n50(sketch: &Sketch) -> GnPosition {
+    let mut lengths = sketch.contig_lengths.clone();
+    lengths.sort_unstable_by(|x, y| y.cmp(x));
+    let total = lengths.iter().map(|x| *x as usize).sum::<usize>();
+    let mut running = 0;
+    for length in lengths {
+        running += length as usize;
+        if 2 * running >= total {
+            return length;
+        }
+    }
+    0
+}
+
+//One row per genome: cluster id (largest clusters first), genome,
+//representative and the ANI/AFs between the genome and its representative
+//(NA if the pair did not pass the thresholds), plus the genome's score if given.
+pub fn write_clusters(
+    handle: &mut impl Write,
+    names: &[String],
+    reps: &[usize],
+    edges: &[ClusterEdge],
+    scores: Option<&[f64]>,
+) {
+    let edge_map = edges
+        .iter()
+        .map(|x| ((x.i, x.j), x))
+        .collect::<FxHashMap<_, _>>();
+    let mut clusters: FxHashMap<usize, Vec<usize>> = FxHashMap::default();
+    for (i, rep) in reps.iter().enumerate() {
+        clusters.entry(*rep).or_default().push(i);
+    }
+    let mut clusters = clusters.into_iter().collect::<Vec<(usize, Vec<usize>)>>();
+    clusters.sort_by(|x, y| {
+        y.1.len()
+            .cmp(&x.1.len())
+            .then(names[x.0].cmp(&names[y.0]))
+    });
+
+    write!(
+        handle,
+        "Cluster\tGenome\tRepresentative\tANI\tAlign_fraction_genome\tAlign_fraction_representative"
+    )
+    .unwrap();
+    if scores.is_some() {
+        write!(handle, "\tScore").unwrap();
+    }
+    writeln!(handle).unwrap();
+    for (id, (rep, mut cluster)) in clusters.into_iter().enumerate() {
+        cluster.sort_by(|x, y| {
+            (*x != rep)
+                .cmp(&(*y != rep))
+                .then(names[*x].cmp(&names[*y]))
+        });
+        for i in cluster {
+            let stats = if i == rep {
+                "100.00\t100.00\t100.00".to_string()
+            } else if let Some(edge) = edge_map.get(&(usize::min(i, rep), usize::max(i, rep))) {
+                let (af_genome, af_rep) = if i < rep {
+                    (edge.af_i, edge.af_j)
+                } else {
+                    (edge.af_j, edge.af_i)
+                };
+                format!(
+                    "{:.2}\t{:.2}\t{:.2}",
+                    edge.ani * 100.,
+                    af_genome * 100.,
+                    af_rep * 100.
+                )
+            } else {
+                "NA\tNA\tNA".to_string()
+            };
+            write!(
+                handle,
+                "{}\t{}\t{}\t{}",
+                id, names[i], names[rep], stats
+            )
+            .unwrap();
+            if let Some(scores) = scores {
+                write!(handle, "\t{:.4}", scores[i]).unwrap();
+            }
+            writeln!(handle).unwrap();
+        }
+    }
+}
+
+pub fn cluster(command_params: CommandParams, sketch_params: SketchParams) {
+    let now = Instant::now();
+    let (names, edges, order) = if command_params.ref_files.len() == 1
+        && file_io::is_sparse_coo(&command_params.ref_files[0])
+    {
+        info!("Sparse matrix detected; clustering its pairs without recomputing ANI.");
+        let matrix = file_io::read_sparse_coo(&command_params.ref_files[0]).unwrap_or_else(|e| {
+            error!("{}. Exiting.", e);
+            std::process::exit(1)
+        });
+        let edges = matrix_edges(&matrix, command_params.cluster_ani, command_params.min_aligned_frac);
+        let order = degree_order(matrix.names.len(), &edges);
+        (matrix.names, edges, order)
+    } else {
+        sketch_edges(&command_params, sketch_params)
+    };
+    if names.is_empty() {
+        error!("No genomes/sketches found.");
+        std::process::exit(1)
+    }
+    info!("{} pairs pass the ANI/AF thresholds.", edges.len());
+    let reps = cluster_genomes(names.len(), &edges, command_params.cluster_method, &order);
+    info!(
+        "{} clusters found.",
+        reps.iter().enumerate().filter(|(i, x)| i == *x).count()
+    );
+
+    if command_params.out_file_name.is_empty() {
+        let stdout = io::stdout();
+        let mut handle = stdout.lock();
+        write_clusters(&mut handle, &names, &reps, &edges, None);
+    } else {
+        let file = File::create(&command_params.out_file_name).unwrap_or_else(|e| {
+            error!("Could not create {}: {}. Exiting.", command_params.out_file_name, e);
+            std::process::exit(1)
+        });
+        write_clusters(&mut BufWriter::new(file), &names, &reps, &edges, None);
+    }
+    info!("Clustering time: {}", now.elapsed().as_secs_f32());
+}
+
+//Sketches the inputs and runs the comparisons; returns the genome names, the
+//passing pairs and the genomes ranked by length.
+fn sketch_edges(
+    command_params: &CommandParams,
+    mut sketch_params: SketchParams,
+) -> (Vec<String>, Vec<ClusterEdge>, Vec<usize>) {
+    let mut sketches;
+    if command_params.refs_are_sketch {
+        info!("Sketches detected.");
+        let param_and_sketches = file_io::sketches_from_sketch(&command_params.ref_files);
+        if param_and_sketches.0.c != sketch_params.c {
+            warn!("Input parameter c = {} is not equal to the sketch parameter c = {}. Using sketch parameters.", sketch_params.c, param_and_sketches.0.c);
+        }
+        sketches = param_and_sketches.1;
+        sketch_params = param_and_sketches.0;
+    } else {
+        sketches = file_io::fastx_to_sketches(&command_params.ref_files, &sketch_params, true);
+    }
+    sketches.sort_by(|x, y| x.file_name.cmp(&y.file_name));
+    let edges = passing_edges(
+        &sketches,
+        &sketch_params,
+        command_params,
+        command_params.cluster_ani,
+    );
+    let names = sketches.iter().map(|x| x.file_name.clone()).collect();
+    (names, edges, length_order(&sketches))
+}
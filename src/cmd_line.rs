@@ -10,6 +10,14 @@ pub const IND_CTG_REF: &str = "individual contig ref";
 pub const CMD_IND_CTG_REF: &str = "ri";
 pub const H_IND_CTG_REF: &str = "Use individual sequences for the REFERENCE in a multi-line fasta.";
 
+pub const GENOME_MAP: &str = "genome-map";
+pub const CMD_GENOME_MAP: &str = "genome-map";
+pub const H_GENOME_MAP: &str = "Split multi-genome fastas into genomes with this two-column table of contig id and genome id (e.g. a contig2bin table). Genome ids are used as names in the output. Unlisted contigs are skipped; files with no listed contigs are used as one genome.";
+
+pub const GENOME_REGEX: &str = "genome-regex";
+pub const CMD_GENOME_REGEX: &str = "genome-regex";
+pub const H_GENOME_REGEX: &str = "Split multi-genome fastas into genomes by matching this regex against fasta headers, e.g. '^(bin_\\d+)_'. The genome id is the first capture group, or the whole match. Unmatched contigs are skipped; files with no matching contigs are used as one genome.";
+
 pub const NO_FULL_INDEX: &str = "no marker index";
 pub const CMD_NO_FULL_INDEX: &str = "no-marker-index";
 pub const H_NO_FULL_INDEX: &str = "Do not use hash-table inverted index for faster ANI filtering. \t[default: load index if > 100 query files or using the --qi option]";
@@ -25,6 +33,7 @@ pub const H_FULL_MAT: &str = "Output full matrix instead of lower-triangular mat
 pub const KEEP_REFS: &str = "keep-refs";
 pub const CMD_KEEP_REFS: &str = "keep-refs";
 pub const H_KEEP_REFS: &str = "Keep reference sketches in memory if the sketch passes the marker filter. Takes more memory but is much faster when querying many similar sequences.";
+pub const H_KEEP_REFS_SERVE: &str = "Keep reference sketches in memory between queries once they pass the marker filter. Faster, but memory grows up to the size of the whole database over a long-running server.";
 
 pub const C_FACTOR: &str = "c";
 pub const CMD_C_FACTOR: &str = "c";
@@ -74,3 +83,115 @@ pub const CMD_INT_WRITE: &str = "inter-write";
 pub const H_INT_WRITE: &str = "Write results to output after --inter-write queries are processed (leads to non-deterministic outputs when multi-threading). \t[default: 10000]";
 
 
+
+pub const APPEND: &str = "append";
+pub const CMD_APPEND: &str = "append";
+pub const H_APPEND: &str = "Add fastas to an existing sketch folder (-o). Uses the sketch parameters of the folder; files already in the folder are skipped.";
+
+pub const REMOVE: &str = "remove";
+pub const CMD_REMOVE: &str = "remove";
+pub const H_REMOVE: &str = "Remove the given genomes from an existing sketch folder (-o) instead of sketching. Genomes are given by fasta file name (or -l list) or .sketch file name.";
+
+pub const JSON_OUT: &str = "json";
+pub const CMD_JSON_OUT: &str = "json";
+pub const H_JSON_OUT: &str = "Output JSON instead of a TSV table.";
+
+pub const PACKED: &str = "packed";
+pub const CMD_PACKED: &str = "packed";
+pub const H_PACKED: &str = "Also write a packed, memory-mapped database to the sketch folder. `skani search` uses it automatically for faster loading. Kept up to date by --append and --remove.";
+
+pub const SAVE_MARKER_INDEX: &str = "marker-index";
+pub const CMD_SAVE_MARKER_INDEX: &str = "marker-index";
+pub const H_SAVE_MARKER_INDEX: &str = "Also save the marker hash table (inverted index) to the sketch folder so `skani search` does not rebuild it. Kept up to date by --append and --remove.";
+
+pub const SOCKET: &str = "socket";
+pub const CMD_SOCKET: &str = "socket";
+pub const H_SOCKET: &str = "Path of the Unix domain socket to listen on. Each connection sends one fasta/fastq (gzip ok) or .sketch file, closes its write side, and reads back the same table as `skani search`.";
+
+pub const OUTPUT_FORMAT: &str = "format";
+pub const CMD_OUTPUT_FORMAT: &str = "format";
+pub const H_OUTPUT_FORMAT: &str = "Output format: tsv, json (one array) or jsonl (one object per line). JSON keys are always the same regardless of --ci and --detailed; identities and aligned fractions are between 0 and 1 instead of percentages. json outputs are only written once all comparisons are done.";
+
+pub const CHAINS: &str = "chains";
+pub const CMD_CHAINS: &str = "chains";
+pub const H_CHAINS: &str = "Write the chains (aligned regions) used for each reported ANI to this file in PAF format.";
+
+pub const FRAGMENT_ANI: &str = "fragment-ani";
+pub const CMD_FRAGMENT_ANI: &str = "fragment-ani";
+pub const H_FRAGMENT_ANI: &str = "Write the identity of every fragment used for each reported ANI to this file (BED-like TSV along the query).";
+
+pub const ALIGNED_BED: &str = "aligned-bed";
+pub const CMD_ALIGNED_BED: &str = "aligned-bed";
+pub const H_ALIGNED_BED: &str = "Write BED files of the aligned regions and their complements (unaligned regions) on the query and reference to PREFIX.{query,ref}_{aligned,unaligned}.bed.";
+
+pub const CORE_REF: &str = "core reference";
+pub const CMD_CORE_REF: char = 'r';
+pub const H_CORE_REF: &str = "Reference genome (fasta/sketch) whose windows are tested for presence. May be one of the inputs.\t[default: input with the most sequence]";
+
+pub const CORE_WINDOW: &str = "window";
+pub const CMD_CORE_WINDOW: &str = "window";
+pub const H_CORE_WINDOW: &str = "Window size in bases on the reference. A window is present in a genome if >= 50% of it is covered by chains.\t[default: 1000]";
+
+pub const CORE_THRESHOLD: &str = "core-threshold";
+pub const CMD_CORE_THRESHOLD: &str = "core-threshold";
+pub const H_CORE_THRESHOLD: &str = "Windows present in >= this % of genomes are core.\t[default: 95]";
+
+pub const CLUSTER_METHOD: &str = "method";
+pub const CMD_CLUSTER_METHOD: &str = "method";
+pub const H_CLUSTER_METHOD: &str = "single: single-linkage. complete: merge clusters only if all pairs pass the thresholds. greedy: longest genomes first (most passing pairs first for a matrix), each genome joins the most similar representative or becomes one.\t[default: single]";
+
+pub const CLUSTER_ANI: &str = "cluster ani";
+pub const CMD_CLUSTER_ANI: &str = "ani";
+pub const H_CLUSTER_ANI: &str = "Minimum ANI for two genomes to be linked.\t[default: 95]";
+
+pub const H_CLUSTER_AF: &str = "Minimum aligned fraction of BOTH genomes for two genomes to be linked.\t[default: 15]";
+pub const H_CLUSTER_SCREEN: &str = "Screen out pairs with < % identity using k-mer sketching.\t[default: 80, or --ani minus 5 if lower]";
+
+pub const QUALITY_FILE: &str = "quality";
+pub const CMD_QUALITY_FILE: &str = "quality";
+pub const H_QUALITY_FILE: &str = "Tab-separated file with a header and genomes (file names, with or without extensions) in the first column, e.g. CheckM output. Higher scores are preferred as representatives.";
+
+pub const QUALITY_COLUMN: &str = "quality-column";
+pub const CMD_QUALITY_COLUMN: &str = "quality-column";
+pub const H_QUALITY_COLUMN: &str = "Score column of --quality.\t[default: Completeness - 5 * Contamination]";
+
+pub const DEREP_COPY: &str = "copy";
+pub const CMD_DEREP_COPY: &str = "copy";
+pub const H_DEREP_COPY: &str = "Copy the representative genomes into this folder.";
+
+pub const DEREP_SYMLINK: &str = "symlink";
+pub const CMD_DEREP_SYMLINK: &str = "symlink";
+pub const H_DEREP_SYMLINK: &str = "Symlink the representative genomes into this folder.";
+
+pub const TREE: &str = "tree";
+pub const CMD_TREE: &str = "tree";
+pub const H_TREE: &str = "Also write a Newick tree built from 100 - ANI distances to this file. Leaves are file names, or contig names with -i.";
+
+pub const TREE_METHOD: &str = "tree-method";
+pub const CMD_TREE_METHOD: &str = "tree-method";
+pub const H_TREE_METHOD: &str = "upgma: rooted, ultrametric tree. nj: neighbor-joining, unrooted tree.\t[default: upgma]";
+
+pub const TREE_FILL: &str = "tree-fill";
+pub const CMD_TREE_FILL: &str = "tree-fill";
+pub const H_TREE_FILL: &str = "Distance used for pairs without an ANI (e.g. screened out by -s) when building the tree.\t[default: 100]";
+
+pub const STREAM: &str = "stream";
+pub const CMD_STREAM: &str = "stream";
+pub const H_STREAM: &str = "Low memory mode for very many genomes. Only markers are kept in memory; full sketches are loaded for blocks of rows and results are streamed to a binary sparse matrix (-o; see --binary). Requires -o.";
+
+pub const BLOCK_SIZE: &str = "block-size";
+pub const CMD_BLOCK_SIZE: &str = "block-size";
+pub const H_BLOCK_SIZE: &str = "Number of full sketches held in memory at once by --stream is at most twice this.\t[default: 5000]";
+
+pub const BINARY: &str = "binary";
+pub const CMD_BINARY: &str = "binary";
+pub const H_BINARY: &str = "Write ANI and both align fractions as one binary sparse matrix with the genome names to -o instead of text output. The format is described in the README. Requires -o.";
+
+pub const SHARD: &str = "shard";
+pub const CMD_SHARD: &str = "shard";
+pub const H_SHARD: &str = "Shard I/N with 0 <= I < N: only compare genomes i with i mod N = I to later genomes and write the results to a shard file (-o). Combine shards 0/N, ..., N-1/N with `skani merge`.";
+
+pub const RESUME: &str = "resume";
+pub const CMD_RESUME: &str = "resume";
+pub const H_RESUME: &str = "Skip the queries listed in <-o>.checkpoint, which is written with every intermediate write, and append to the existing output. Use the same inputs and options as the interrupted run.";
+pub const H_RESUME_TRI: &str = "Skip the genomes listed in <-o>.checkpoint, which is written with every intermediate write, and append to the existing output. Use the same inputs and options as the interrupted run. Requires -E.";
@@ -0,0 +1,260 @@
+use crate::chain;
+use crate::file_io;
+use crate::params::*;
+use crate::regression;
+use crate::types::*;
+use log::*;
+use rayon::prelude::*;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::time::Instant;
+
+pub struct CoreGenome {
+    pub file_name: String,
+    pub ani_res: Option<AniEstResult>,
+    pub present: Vec<bool>,
+}
+
+//Splits every contig of the reference into windows of `window` bases; the last
+//window of a contig may be shorter.
+pub fn core_windows(ref_sketch: &Sketch, window: usize) -> Vec<GenomeRegion> {
+    let mut windows = vec![];
+    for (contig, contig_length) in ref_sketch.contigs.iter().zip(ref_sketch.contig_lengths.iter()) {
+        let mut start = 0;
+        while start < *contig_length {
+            let end = GnPosition::min(start + window as GnPosition, *contig_length);
+            windows.push(GenomeRegion {
+                contig: contig.clone(),
+                start,
+                end,
+            });
+            start = end;
+        }
+    }
+    windows
+}
+
+//A window is present if at least CORE_WINDOW_MIN_COVER of it is covered by
+//chains. Regions must be sorted in contig order, as in AlignmentDetails.
+pub fn window_presence(windows: &[GenomeRegion], aligned: &[GenomeRegion]) -> Vec<bool> {
+    let mut covered = vec![0; windows.len()];
+    let mut w = 0;
+    for region in aligned.iter() {
+        while w < windows.len()
+            && (windows[w].contig != region.contig || windows[w].end <= region.start)
+        {
+            w += 1;
+        }
+        let mut v = w;
+        while v < windows.len() && windows[v].contig == region.contig && windows[v].start < region.end {
+            covered[v] += GnPosition::min(windows[v].end, region.end)
+                - GnPosition::max(windows[v].start, region.start);
+            v += 1;
+        }
+    }
+    windows
+        .iter()
+        .zip(covered.iter())
+        .map(|(x, y)| *y as f64 >= CORE_WINDOW_MIN_COVER * (x.end - x.start) as f64)
+        .collect()
+}
+
+pub fn core(command_params: CommandParams, mut sketch_params: SketchParams) {
+    let now = Instant::now();
+    let mut sketches;
+    if command_params.refs_are_sketch {
+        info!("Sketches detected.");
+        let param_and_sketches = file_io::sketches_from_sketch(&command_params.ref_files);
+        if param_and_sketches.0.c != sketch_params.c {
+            warn!("Input parameter c = {} is not equal to the sketch parameter c = {}. Using sketch parameters.", sketch_params.c, param_and_sketches.0.c);
+        }
+        sketches = param_and_sketches.1;
+        sketch_params = param_and_sketches.0;
+    } else {
+        sketches = file_io::fastx_to_sketches(&command_params.ref_files, &sketch_params, true);
+    }
+    if sketches.is_empty() {
+        error!("No genomes/sketches found.");
+        std::process::exit(1)
+    }
+    sketches.sort_by(|x, y| x.file_name.cmp(&y.file_name));
+
+    //Without -r, the genome with the most sequence is the reference.
+    let ref_index;
+    let ref_sketch;
+    if command_params.core_ref.is_empty() {
+        ref_index = (0..sketches.len())
+            .max_by_key(|i| (sketches[*i].total_sequence_length, std::cmp::Reverse(*i)));
+        ref_sketch = sketches[ref_index.unwrap()].clone();
+    } else if let Some(i) = sketches
+        .iter()
+        .position(|x| x.file_name == command_params.core_ref)
+    {
+        ref_index = Some(i);
+        ref_sketch = sketches[i].clone();
+    } else {
+        let core_ref = vec![command_params.core_ref.clone()];
+        let other_sketches = if command_params.refs_are_sketch {
+            let (ref_params, ref_sketches) = file_io::sketches_from_sketch(&core_ref);
+            if ref_params != sketch_params {
+                error!("Reference sketch parameters are not equal to the genome sketch parameters. Exiting.");
+                std::process::exit(1)
+            }
+            ref_sketches
+        } else {
+            file_io::fastx_to_sketches(&core_ref, &sketch_params, true)
+        };
+        if other_sketches.is_empty() {
+            error!("Reference {} could not be read. Exiting.", command_params.core_ref);
+            std::process::exit(1)
+        }
+        ref_sketch = other_sketches.into_iter().next().unwrap();
+        ref_index = sketches
+            .iter()
+            .position(|x| x.file_name == ref_sketch.file_name);
+    }
+    info!("Using {} as the reference.", ref_sketch.file_name);
+
+    let windows = core_windows(&ref_sketch, command_params.core_window);
+    let model = regression::get_model(sketch_params.c, command_params.learned_ani);
+    if model.is_some() {
+        info!("{}", LEARNED_INFO_HELP);
+    }
+    let map_params =
+        chain::map_params_from_sketch(&ref_sketch, sketch_params.use_aa, &command_params);
+    let genomes = (0..sketches.len())
+        .collect::<Vec<usize>>()
+        .into_par_iter()
+        .map(|i| {
+            if Some(i) == ref_index {
+                return CoreGenome {
+                    file_name: sketches[i].file_name.clone(),
+                    ani_res: None,
+                    present: vec![true; windows.len()],
+                };
+            }
+            let (mut ani_res, details) =
+                chain::chain_seeds_detailed(&ref_sketch, &sketches[i], map_params.clone());
+            //Same cutoff as for reporting an ANI in dist/triangle; NaN also fails.
+            if ani_res.ani > 0.1 {
+                if command_params.learned_ani {
+                    regression::predict_from_ani_res(&mut ani_res, model.as_ref().unwrap());
+                }
+                CoreGenome {
+                    file_name: sketches[i].file_name.clone(),
+                    ani_res: Some(ani_res),
+                    present: window_presence(&windows, &details.ref_aligned),
+                }
+            } else {
+                CoreGenome {
+                    file_name: sketches[i].file_name.clone(),
+                    ani_res: None,
+                    present: vec![false; windows.len()],
+                }
+            }
+        })
+        .collect::<Vec<CoreGenome>>();
+
+    let min_present = command_params.core_threshold * genomes.len() as f64;
+    let core = (0..windows.len())
+        .map(|w| genomes.iter().filter(|x| x.present[w]).count() as f64 >= min_present)
+        .collect::<Vec<bool>>();
+    info!(
+        "{} of {} windows are present in >= {}% of genomes.",
+        core.iter().filter(|x| **x).count(),
+        windows.len(),
+        command_params.core_threshold * 100.
+    );
+
+    write_presence_absence(&windows, &genomes, &command_params.out_file_name);
+    write_core_fractions(&ref_sketch, ref_index, &genomes, &core, &command_params.out_file_name);
+    info!("Core genome time: {}", now.elapsed().as_secs_f32());
+}
+
+fn create_file(file_name: &str) -> BufWriter<File> {
+    BufWriter::new(File::create(file_name).unwrap_or_else(|e| {
+        error!("Could not create {}: {}. Exiting.", file_name, e);
+        std::process::exit(1)
+    }))
+}
+
+fn write_presence_absence(windows: &[GenomeRegion], genomes: &[CoreGenome], prefix: &str) {
+    let file_name = format!("{}.presence_absence.tsv", prefix);
+    let mut handle = create_file(&file_name);
+    write!(handle, "Contig\tStart\tEnd\tNum_present").unwrap();
+    for genome in genomes.iter() {
+        write!(handle, "\t{}", genome.file_name).unwrap();
+    }
+    writeln!(handle).unwrap();
+    for (w, window) in windows.iter().enumerate() {
+        write!(
+            handle,
+            "{}\t{}\t{}\t{}",
+            window.contig.split_whitespace().next().unwrap_or(""),
+            window.start,
+            window.end,
+            genomes.iter().filter(|x| x.present[w]).count()
+        )
+        .unwrap();
+        for genome in genomes.iter() {
+            write!(handle, "\t{}", genome.present[w] as u8).unwrap();
+        }
+        writeln!(handle).unwrap();
+    }
+    info!("Presence/absence matrix written to {}", file_name);
+}
+
+fn write_core_fractions(
+    ref_sketch: &Sketch,
+    ref_index: Option<usize>,
+    genomes: &[CoreGenome],
+    core: &[bool],
+    prefix: &str,
+) {
+    let file_name = format!("{}.core_fraction.tsv", prefix);
+    let mut handle = create_file(&file_name);
+    let num_core = core.iter().filter(|x| **x).count();
+    writeln!(
+        handle,
+        "Genome\tReference\tANI\tAlign_fraction_genome\tAlign_fraction_ref\tWindows_present\tWindow_fraction\tCore_fraction"
+    )
+    .unwrap();
+    for (i, genome) in genomes.iter().enumerate() {
+        let num_present = genome.present.iter().filter(|x| **x).count();
+        let num_core_present = genome
+            .present
+            .iter()
+            .zip(core.iter())
+            .filter(|(x, y)| **x && **y)
+            .count();
+        let (ani, af_genome, af_ref) = if Some(i) == ref_index {
+            ("100.00".to_string(), "100.00".to_string(), "100.00".to_string())
+        } else if let Some(ani_res) = &genome.ani_res {
+            (
+                format!("{:.2}", ani_res.ani * 100.),
+                format!("{:.2}", ani_res.align_fraction_query * 100.),
+                format!("{:.2}", ani_res.align_fraction_ref * 100.),
+            )
+        } else {
+            ("NA".to_string(), "NA".to_string(), "NA".to_string())
+        };
+        writeln!(
+            handle,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{:.4}\t{:.4}",
+            genome.file_name,
+            ref_sketch.file_name,
+            ani,
+            af_genome,
+            af_ref,
+            num_present,
+            num_present as f64 / genome.present.len() as f64,
+            if num_core == 0 {
+                0.
+            } else {
+                num_core_present as f64 / num_core as f64
+            }
+        )
+        .unwrap();
+    }
+    info!("Per-genome core fractions written to {}", file_name);
+}
@@ -0,0 +1,562 @@
+use crate::error::*;
+use crate::file_io;
+use crate::params::*;
+use crate::screen::MarkerIndex;
+use crate::types::*;
+use log::*;
+use memmap2::Mmap;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use smallvec::SmallVec;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+//Packed sketch database written next to markers.bin by `skani sketch --packed`.
+//  packed_db.index:   PACKED_DB_MAGIC | version (u32 LE) | PackedMeta (bincode)
+//  packed_db.markers: every genome's sorted markers as u64 LE, one after another
+//  packed_db.seeds:   every genome's seeds as SEED_RECORD_SIZE byte records
+//The index is small and read into memory; markers and seeds are memory-mapped,
+//so opening the database is fast and a reference's seeds are read without
+//decoding a .sketch file. The .sketch files stay the source of truth; the
+//packed files are rebuilt from them on --append/--remove.
+pub const SEED_RECORD_SIZE: usize = 16;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PackedGenome {
+    pub file_name: String,
+    pub contig_order: usize,
+    pub contigs: Vec<String>,
+    pub contig_lengths: Vec<GnPosition>,
+    pub total_sequence_length: usize,
+    pub repetitive_kmers: usize,
+    //Not always the same as the database's marker_c; see Sketch::new.
+    pub marker_c: usize,
+    marker_start: usize,
+    marker_end: usize,
+    seed_start: usize,
+    seed_end: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct PackedMeta {
+    skani_version: String,
+    sketch_params: SketchParams,
+    genomes: Vec<PackedGenome>,
+}
+
+pub struct PackedDb {
+    pub sketch_params: SketchParams,
+    pub genomes: Vec<PackedGenome>,
+    markers: Mmap,
+    seeds: Arc<Mmap>,
+}
+
+//One genome's seed records in packed_db.seeds, sorted by k-mer. Chaining looks
+//k-mers up by binary search in the mapped file, so no KmerSeeds map is built
+//for a reference.
+#[derive(Clone)]
+pub struct PackedSeeds {
+    seeds: Arc<Mmap>,
+    start: usize,
+    end: usize,
+}
+
+impl PartialEq for PackedSeeds {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.seeds, &other.seeds) && self.start == other.start && self.end == other.end
+    }
+}
+
+impl Eq for PackedSeeds {}
+
+pub fn seed_from_record(record: &[u8]) -> SeedPosition {
+    SeedPosition {
+        pos: GnPosition::from_le_bytes(record[4..8].try_into().unwrap()),
+        contig_index: ContigIndex::from_le_bytes(record[8..12].try_into().unwrap()),
+        canonical: record[12] == 1,
+        phase: record[13],
+    }
+}
+
+impl PackedSeeds {
+    fn records(&self) -> &[u8] {
+        &self.seeds[self.start * SEED_RECORD_SIZE..self.end * SEED_RECORD_SIZE]
+    }
+
+    fn kmer_at(&self, i: usize) -> SeedBits {
+        let pos = (self.start + i) * SEED_RECORD_SIZE;
+        SeedBits::from_le_bytes(self.seeds[pos..pos + 4].try_into().unwrap())
+    }
+
+    //First record with a k-mer >= kmer.
+    fn lower_bound(&self, kmer: SeedBits) -> usize {
+        let (mut lo, mut hi) = (0, self.end - self.start);
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            if self.kmer_at(mid) < kmer {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        lo
+    }
+
+    //Records of the k-mer; decode them with seed_from_record.
+    pub fn get(&self, kmer: SeedBits) -> Option<&[u8]> {
+        let lo = self.lower_bound(kmer);
+        let hi = lo + self.records()[lo * SEED_RECORD_SIZE..]
+            .chunks_exact(SEED_RECORD_SIZE)
+            .take_while(|x| SeedBits::from_le_bytes(x[0..4].try_into().unwrap()) == kmer)
+            .count();
+        if lo == hi {
+            None
+        } else {
+            Some(&self.records()[lo * SEED_RECORD_SIZE..hi * SEED_RECORD_SIZE])
+        }
+    }
+
+    //Each k-mer with its records, in k-mer order.
+    pub fn iter(&self) -> impl Iterator<Item = (SeedBits, &[u8])> + '_ {
+        let records = self.records();
+        let mut start = 0;
+        std::iter::from_fn(move || {
+            if start * SEED_RECORD_SIZE == records.len() {
+                return None;
+            }
+            let kmer = self.kmer_at(start);
+            let end = start + records[start * SEED_RECORD_SIZE..]
+                .chunks_exact(SEED_RECORD_SIZE)
+                .take_while(|x| SeedBits::from_le_bytes(x[0..4].try_into().unwrap()) == kmer)
+                .count();
+            let ret = (kmer, &records[start * SEED_RECORD_SIZE..end * SEED_RECORD_SIZE]);
+            start = end;
+            Some(ret)
+        })
+    }
+}
+
+fn packed_file(folder: &Path, ext: &str) -> String {
+    folder
+        .join(format!("{}.{}", PACKED_DB_PREFIX, ext))
+        .to_str()
+        .unwrap()
+        .to_string()
+}
+
+fn map_file(file_name: &str) -> Result<Mmap> {
+    let file = File::open(file_name)?;
+    //The files are only ever replaced by renaming, never modified in place.
+    let mmap = unsafe { Mmap::map(&file)? };
+    Ok(mmap)
+}
+
+pub fn packed_db_exists(folder: &Path) -> bool {
+    Path::new(&packed_file(folder, "index")).exists()
+}
+
+impl PackedDb {
+    pub fn open(folder: &Path) -> Result<PackedDb> {
+        let index_file = packed_file(folder, "index");
+        let mut reader = BufReader::new(File::open(&index_file)?);
+        let mut magic = [0; 8];
+        let mut version = [0; 4];
+        if reader.read_exact(&mut magic).is_err() || &magic != PACKED_DB_MAGIC {
+            return Err(SkaniError::BadSketch {
+                file: index_file,
+                reason: "not a packed skani database".to_string(),
+            });
+        }
+        reader.read_exact(&mut version)?;
+        let version = u32::from_le_bytes(version);
+        if version != PACKED_DB_VERSION {
+            return Err(SkaniError::IncompatibleVersion {
+                file: index_file,
+                found: version,
+                expected: PACKED_DB_VERSION,
+                skani_version: env!("CARGO_PKG_VERSION").to_string(),
+            });
+        }
+        let meta: PackedMeta =
+            bincode::deserialize_from(reader).map_err(|e| SkaniError::BadSketch {
+                file: index_file.clone(),
+                reason: e.to_string(),
+            })?;
+        let markers = map_file(&packed_file(folder, "markers"))?;
+        let seeds = map_file(&packed_file(folder, "seeds"))?;
+        if let Some(last) = meta.genomes.last() {
+            if last.marker_end * 8 > markers.len() || last.seed_end * SEED_RECORD_SIZE > seeds.len() {
+                return Err(SkaniError::BadSketch {
+                    file: index_file,
+                    reason: "packed marker or seed file is truncated".to_string(),
+                });
+            }
+        }
+        Ok(PackedDb {
+            sketch_params: meta.sketch_params,
+            genomes: meta.genomes,
+            markers,
+            seeds: Arc::new(seeds),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.genomes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.genomes.is_empty()
+    }
+
+    pub fn num_markers(&self, i: usize) -> usize {
+        self.genomes[i].marker_end - self.genomes[i].marker_start
+    }
+
+    //Sorted markers of genome i.
+    pub fn markers(&self, i: usize) -> impl Iterator<Item = MarkerBits> + '_ {
+        let genome = &self.genomes[i];
+        self.markers[genome.marker_start * 8..genome.marker_end * 8]
+            .chunks_exact(8)
+            .map(|x| MarkerBits::from_le_bytes(x.try_into().unwrap()))
+    }
+
+    fn marker_at(&self, pos: usize) -> MarkerBits {
+        MarkerBits::from_le_bytes(self.markers[pos * 8..pos * 8 + 8].try_into().unwrap())
+    }
+
+    fn contains_marker(&self, i: usize, marker: MarkerBits) -> bool {
+        let genome = &self.genomes[i];
+        let (mut lo, mut hi) = (genome.marker_start, genome.marker_end);
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            let val = self.marker_at(mid);
+            if val == marker {
+                return true;
+            } else if val < marker {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        false
+    }
+
+    //Same as chain::check_markers_quickly, with genome i as the reference.
+    pub fn check_markers_quickly(&self, i: usize, query_sketch: &Sketch, screen_val: f64) -> bool {
+        let num_markers = self.num_markers(i);
+        if screen_val <= 0.50 || num_markers == 0 || query_sketch.marker_seeds.is_empty() {
+            return true;
+        }
+        let k = if self.sketch_params.use_aa { K_MARKER_AA } else { K_MARKER_DNA };
+        let min_card = usize::min(num_markers, query_sketch.marker_seeds.len());
+        let ratio = usize::max((screen_val.powi(k as i32) * min_card as f64) as usize, 1);
+        let mut intersect_len = 0;
+        if query_sketch.marker_seeds.len() > num_markers {
+            for marker in self.markers(i) {
+                if query_sketch.marker_seeds.contains(&marker) {
+                    intersect_len += 1;
+                }
+                if intersect_len >= ratio {
+                    return true;
+                }
+            }
+        } else {
+            for marker in query_sketch.marker_seeds.iter() {
+                if self.contains_marker(i, *marker) {
+                    intersect_len += 1;
+                }
+                if intersect_len >= ratio {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    //Rebuild the full sketch of genome i from the memory-mapped seeds.
+    pub fn sketch(&self, i: usize) -> Sketch {
+        let mut sketch = self.mapped_sketch(i);
+        let mut kmer_seeds = KmerSeeds::default();
+        for (kmer, records) in sketch.packed_seeds.take().unwrap().iter() {
+            kmer_seeds.insert(kmer, records.chunks_exact(SEED_RECORD_SIZE).map(seed_from_record).collect());
+        }
+        sketch.kmer_seeds_k = Some(kmer_seeds);
+        sketch
+    }
+
+    //Sketch of genome i whose seeds stay in the memory-mapped file; chaining
+    //reads them in place. This is what `skani search` uses.
+    pub fn mapped_sketch(&self, i: usize) -> Sketch {
+        let genome = &self.genomes[i];
+        Sketch {
+            file_name: genome.file_name.clone(),
+            kmer_seeds_k: None,
+            packed_seeds: Some(PackedSeeds {
+                seeds: self.seeds.clone(),
+                start: genome.seed_start,
+                end: genome.seed_end,
+            }),
+            contigs: genome.contigs.clone(),
+            total_sequence_length: genome.total_sequence_length,
+            contig_lengths: genome.contig_lengths.clone(),
+            repetitive_kmers: genome.repetitive_kmers,
+            marker_seeds: self.markers(i).collect(),
+            marker_c: genome.marker_c,
+            c: self.sketch_params.c,
+            k: self.sketch_params.k,
+            contig_order: genome.contig_order,
+            amino_acid: self.sketch_params.use_aa,
+        }
+    }
+
+    pub fn kmer_to_sketch(&self) -> KmerToSketch {
+        let mut ret = KmerToSketch::default();
+        for i in 0..self.len() {
+            for kmer in self.markers(i) {
+                let sketch_set = ret.entry(kmer).or_insert_with(SmallVec::new);
+                sketch_set.push(i as u32);
+            }
+        }
+        ret
+    }
+}
+
+//Write the packed database for the .sketch files in `folder`, in the order of
+//`markers`. Sketches are read a chunk at a time to bound memory.
+pub fn write_packed_db(
+    folder: &Path,
+    sketch_params: &SketchParams,
+    markers: &[Sketch],
+    sketch_files: &[String],
+) -> Result<()> {
+    let tmp = |ext: &str| folder.join(format!(".skani_{}.tmp", ext)).to_str().unwrap().to_string();
+    let mut marker_writer = BufWriter::new(File::create(tmp("markers"))?);
+    let mut seed_writer = BufWriter::new(File::create(tmp("seeds"))?);
+    let mut genomes = vec![];
+    let mut marker_pos = 0;
+    let mut seed_pos = 0;
+    for chunk in sketch_files.chunks(rayon::current_num_threads() * 4) {
+        let sketches = chunk
+            .par_iter()
+            .map(|x| file_io::read_sketch_file(x).map(|x| x.1))
+            .collect::<Vec<Result<Sketch>>>();
+        for sketch in sketches {
+            let sketch = sketch?;
+            let mut sketch_markers = sketch.marker_seeds.iter().copied().collect::<Vec<MarkerBits>>();
+            sketch_markers.sort_unstable();
+            for marker in sketch_markers.iter() {
+                marker_writer.write_all(&marker.to_le_bytes())?;
+            }
+            let mut kmers = sketch
+                .kmer_seeds_k
+                .as_ref()
+                .map(|x| x.iter().collect::<Vec<_>>())
+                .unwrap_or_default();
+            kmers.sort_unstable_by_key(|x| *x.0);
+            let mut num_seeds = 0;
+            for (kmer, positions) in kmers {
+                for seed_position in positions.iter() {
+                    let mut record = [0; SEED_RECORD_SIZE];
+                    record[0..4].copy_from_slice(&kmer.to_le_bytes());
+                    record[4..8].copy_from_slice(&seed_position.pos.to_le_bytes());
+                    record[8..12].copy_from_slice(&seed_position.contig_index.to_le_bytes());
+                    record[12] = seed_position.canonical as u8;
+                    record[13] = seed_position.phase;
+                    seed_writer.write_all(&record)?;
+                    num_seeds += 1;
+                }
+            }
+            genomes.push(PackedGenome {
+                file_name: sketch.file_name,
+                contig_order: sketch.contig_order,
+                contigs: sketch.contigs,
+                contig_lengths: sketch.contig_lengths,
+                total_sequence_length: sketch.total_sequence_length,
+                repetitive_kmers: sketch.repetitive_kmers,
+                marker_c: sketch.marker_c,
+                marker_start: marker_pos,
+                marker_end: marker_pos + sketch_markers.len(),
+                seed_start: seed_pos,
+                seed_end: seed_pos + num_seeds,
+            });
+            marker_pos += sketch_markers.len();
+            seed_pos += num_seeds;
+        }
+    }
+    marker_writer.flush()?;
+    seed_writer.flush()?;
+    if genomes.len() != markers.len() {
+        warn!(
+            "{} genomes in markers.bin but {} in the packed database.",
+            markers.len(),
+            genomes.len()
+        );
+    }
+
+    let meta = PackedMeta {
+        skani_version: env!("CARGO_PKG_VERSION").to_string(),
+        sketch_params: sketch_params.clone(),
+        genomes,
+    };
+    let mut index_writer = BufWriter::new(File::create(tmp("index"))?);
+    index_writer.write_all(PACKED_DB_MAGIC)?;
+    index_writer.write_all(&PACKED_DB_VERSION.to_le_bytes())?;
+    bincode::serialize_into(&mut index_writer, &meta).map_err(|e| SkaniError::BadSketch {
+        file: tmp("index"),
+        reason: e.to_string(),
+    })?;
+    index_writer.flush()?;
+
+    //The index is moved last; it is what marks the database as present.
+    std::fs::rename(tmp("markers"), packed_file(folder, "markers"))?;
+    std::fs::rename(tmp("seeds"), packed_file(folder, "seeds"))?;
+    std::fs::rename(tmp("index"), packed_file(folder, "index"))?;
+    Ok(())
+}
+
+pub fn remove_packed_db(folder: &Path) -> Result<()> {
+    for ext in ["index", "markers", "seeds"] {
+        let file = packed_file(folder, ext);
+        if Path::new(&file).exists() {
+            std::fs::remove_file(file)?;
+        }
+    }
+    Ok(())
+}
+
+//Persisted marker -> genome index (the on-disk version of KmerToSketch),
+//written next to markers.bin by `skani sketch --marker-index`.
+//  MARKER_INDEX_MAGIC | version (u32) | fingerprint (u32) | num_genomes (u64)
+//  | num_kmers (u64) | num_postings (u64) | sorted kmers (u64 x num_kmers)
+//  | offsets (u64 x num_kmers + 1) | genome ids (u32 x num_postings)
+//All integers are little endian. Genome ids are indices into markers.bin;
+//the fingerprint catches an index that is out of date with markers.bin.
+const MARKER_INDEX_HEADER_SIZE: usize = 40;
+
+pub struct MarkerIndexFile {
+    mmap: Mmap,
+    num_kmers: usize,
+}
+
+pub fn marker_index_fingerprint<'a>(file_names: impl Iterator<Item = &'a String>) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    for file_name in file_names {
+        hasher.update(file_name.as_bytes());
+        hasher.update(b"\n");
+    }
+    hasher.finalize()
+}
+
+pub fn marker_index_file(folder: &Path) -> String {
+    folder.join(MARKER_INDEX_FILE).to_str().unwrap().to_string()
+}
+
+pub fn write_marker_index(folder: &Path, markers: &[Sketch]) -> Result<()> {
+    let mut pairs = vec![];
+    for (i, sketch) in markers.iter().enumerate() {
+        for marker in sketch.marker_seeds.iter() {
+            pairs.push((*marker, i as u32));
+        }
+    }
+    pairs.par_sort_unstable();
+    let mut kmers = vec![];
+    let mut offsets = vec![];
+    for (j, pair) in pairs.iter().enumerate() {
+        if kmers.last() != Some(&pair.0) {
+            kmers.push(pair.0);
+            offsets.push(j as u64);
+        }
+    }
+    offsets.push(pairs.len() as u64);
+
+    let tmp_file = folder.join(".skani_marker_index.tmp").to_str().unwrap().to_string();
+    let mut writer = BufWriter::new(File::create(&tmp_file)?);
+    writer.write_all(MARKER_INDEX_MAGIC)?;
+    writer.write_all(&MARKER_INDEX_VERSION.to_le_bytes())?;
+    writer.write_all(&marker_index_fingerprint(markers.iter().map(|x| &x.file_name)).to_le_bytes())?;
+    writer.write_all(&(markers.len() as u64).to_le_bytes())?;
+    writer.write_all(&(kmers.len() as u64).to_le_bytes())?;
+    writer.write_all(&(pairs.len() as u64).to_le_bytes())?;
+    for kmer in kmers.iter() {
+        writer.write_all(&kmer.to_le_bytes())?;
+    }
+    for offset in offsets.iter() {
+        writer.write_all(&offset.to_le_bytes())?;
+    }
+    for pair in pairs.iter() {
+        writer.write_all(&pair.1.to_le_bytes())?;
+    }
+    writer.flush()?;
+    std::fs::rename(tmp_file, marker_index_file(folder))?;
+    Ok(())
+}
+
+fn read_u64(bytes: &[u8], pos: usize) -> u64 {
+    u64::from_le_bytes(bytes[pos..pos + 8].try_into().unwrap())
+}
+
+impl MarkerIndexFile {
+    //`fingerprint` must be marker_index_fingerprint of the genomes in the
+    //order used for searching.
+    pub fn open(file_name: &str, num_genomes: usize, fingerprint: u32) -> Result<MarkerIndexFile> {
+        let bad = |reason: &str| SkaniError::BadSketch {
+            file: file_name.to_string(),
+            reason: reason.to_string(),
+        };
+        let mmap = map_file(file_name)?;
+        if mmap.len() < MARKER_INDEX_HEADER_SIZE || &mmap[0..8] != MARKER_INDEX_MAGIC {
+            return Err(bad("not a skani marker index"));
+        }
+        let version = u32::from_le_bytes(mmap[8..12].try_into().unwrap());
+        if version != MARKER_INDEX_VERSION {
+            return Err(SkaniError::IncompatibleVersion {
+                file: file_name.to_string(),
+                found: version,
+                expected: MARKER_INDEX_VERSION,
+                skani_version: env!("CARGO_PKG_VERSION").to_string(),
+            });
+        }
+        let file_fingerprint = u32::from_le_bytes(mmap[12..16].try_into().unwrap());
+        if file_fingerprint != fingerprint || read_u64(&mmap, 16) as usize != num_genomes {
+            return Err(bad("marker index is out of date with markers.bin"));
+        }
+        let num_kmers = read_u64(&mmap, 24) as usize;
+        let num_postings = read_u64(&mmap, 32) as usize;
+        if mmap.len() != MARKER_INDEX_HEADER_SIZE + num_kmers * 8 + (num_kmers + 1) * 8 + num_postings * 4 {
+            return Err(bad("marker index is truncated"));
+        }
+        Ok(MarkerIndexFile { mmap, num_kmers })
+    }
+
+    fn kmer(&self, i: usize) -> MarkerBits {
+        read_u64(&self.mmap, MARKER_INDEX_HEADER_SIZE + i * 8)
+    }
+
+    fn offset(&self, i: usize) -> usize {
+        read_u64(&self.mmap, MARKER_INDEX_HEADER_SIZE + (self.num_kmers + i) * 8) as usize
+    }
+}
+
+impl MarkerIndex for MarkerIndexFile {
+    fn for_each_sketch_with(&self, marker: MarkerBits, f: &mut dyn FnMut(u32)) {
+        let (mut lo, mut hi) = (0, self.num_kmers);
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            let val = self.kmer(mid);
+            if val == marker {
+                let postings_start = MARKER_INDEX_HEADER_SIZE + (2 * self.num_kmers + 1) * 8;
+                for j in self.offset(mid)..self.offset(mid + 1) {
+                    let pos = postings_start + j * 4;
+                    f(u32::from_le_bytes(self.mmap[pos..pos + 4].try_into().unwrap()));
+                }
+                return;
+            } else if val < marker {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+    }
+}
@@ -0,0 +1,215 @@
+use crate::cluster;
+use crate::file_io;
+use crate::params::*;
+use crate::types::*;
+use fxhash::FxHashMap;
+use log::*;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::Instant;
+
+//Genome name without directories and fasta/compression extensions, used to
+//match quality table rows (e.g. CheckM bin ids) to input files.
+pub fn genome_stem(file_name: &str) -> &str {
+    let mut stem = Path::new(file_name)
+        .file_name()
+        .and_then(|x| x.to_str())
+        .unwrap_or(file_name);
+    for ext in [".gz", ".bz2", ".xz", ".zst"] {
+        stem = stem.strip_suffix(ext).unwrap_or(stem);
+    }
+    for ext in [".fasta", ".fna", ".fa", ".fastq", ".fq", ".ffn", ".faa"] {
+        stem = stem.strip_suffix(ext).unwrap_or(stem);
+    }
+    stem
+}
+
+//Reads a tab-separated table with a header and genomes in the first column.
+//Without a column name, the score is completeness - 5 * contamination.
+pub fn read_quality_file(
+    file_name: &str,
+    column: &str,
+) -> Result<FxHashMap<String, f64>, String> {
+    let file = File::open(file_name).map_err(|e| format!("could not open {}: {}", file_name, e))?;
+    let mut lines = BufReader::new(file).lines();
+    let header = match lines.next() {
+        Some(Ok(header)) => header,
+        _ => return Err(format!("{} is empty", file_name)),
+    };
+    let header = header
+        .trim_end()
+        .split('\t')
+        .map(|x| x.trim().to_lowercase())
+        .collect::<Vec<String>>();
+    let find_column = |name: &str| header.iter().skip(1).position(|x| x == name).map(|x| x + 1);
+    let columns;
+    if column.is_empty() {
+        match (find_column("completeness"), find_column("contamination")) {
+            (Some(x), Some(y)) => columns = vec![(x, 1.), (y, -5.)],
+            _ => {
+                return Err(format!(
+                    "{} has no Completeness and Contamination columns and no score column was given",
+                    file_name
+                ))
+            }
+        }
+    } else {
+        match find_column(&column.to_lowercase()) {
+            Some(x) => columns = vec![(x, 1.)],
+            None => return Err(format!("{} has no column {}", file_name, column)),
+        }
+    }
+
+    let mut scores = FxHashMap::default();
+    for (i, line) in lines.enumerate() {
+        let line = line.map_err(|e| format!("could not read {}: {}", file_name, e))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let fields = line.trim_end().split('\t').collect::<Vec<&str>>();
+        let mut score = 0.;
+        for (column, weight) in columns.iter() {
+            let value = fields
+                .get(*column)
+                .and_then(|x| x.trim().parse::<f64>().ok())
+                .filter(|x| x.is_finite())
+                .ok_or_else(|| format!("line {} of {} has no valid value for {}", i + 2, file_name, header[*column]))?;
+            score += weight * value;
+        }
+        scores.insert(genome_stem(fields[0].trim()).to_string(), score);
+    }
+    Ok(scores)
+}
+
+//Quality score plus 0.5 * log10(N50) and 0.5 * log10(total length), so that
+//contiguity and size break ties between genomes of similar quality.
+pub fn genome_scores(sketches: &[Sketch], quality: &FxHashMap<String, f64>) -> Vec<f64> {
+    let mut missing = 0;
+    let scores = sketches
+        .iter()
+        .map(|x| {
+            let q = match quality.get(genome_stem(&x.file_name)) {
+                Some(q) => *q,
+                None => {
+                    missing += 1;
+                    0.
+                }
+            };
+            q + 0.5 * (cluster::n50(x).max(1) as f64).log10()
+                + 0.5 * (x.total_sequence_length.max(1) as f64).log10()
+        })
+        .collect();
+    if !quality.is_empty() && missing > 0 {
+        warn!("{} genomes are not in the quality file; their quality score is 0.", missing);
+    }
+    scores
+}
+
+fn place_representatives(reps: &[&Sketch], dir: &str, symlink: bool) {
+    if let Err(e) = fs::create_dir_all(dir) {
+        error!("Could not create {}: {}. Exiting.", dir, e);
+        std::process::exit(1)
+    }
+    let mut used_names: FxHashMap<String, usize> = FxHashMap::default();
+    for rep in reps.iter() {
+        let source = Path::new(&rep.file_name);
+        let name = source
+            .file_name()
+            .and_then(|x| x.to_str())
+            .unwrap_or(&rep.file_name)
+            .to_string();
+        //Different folders may have genomes with the same file name.
+        let count = used_names.entry(name.clone()).or_insert(0);
+        let target_name = if *count == 0 {
+            name
+        } else {
+            format!("{}_{}", count, name)
+        };
+        *count += 1;
+        let target = Path::new(dir).join(target_name);
+        let res = if symlink {
+            fs::canonicalize(source).and_then(|x| std::os::unix::fs::symlink(x, &target))
+        } else {
+            fs::copy(source, &target).map(|_| ())
+        };
+        if let Err(e) = res {
+            warn!("Could not place {} in {}: {}", rep.file_name, dir, e);
+        }
+    }
+}
+
+pub fn derep(command_params: CommandParams, mut sketch_params: SketchParams) {
+    let now = Instant::now();
+    let quality = if command_params.quality_file.is_empty() {
+        FxHashMap::default()
+    } else {
+        read_quality_file(&command_params.quality_file, &command_params.quality_column)
+            .unwrap_or_else(|e| {
+                error!("{}. Exiting.", e);
+                std::process::exit(1)
+            })
+    };
+
+    let mut sketches;
+    if command_params.refs_are_sketch {
+        info!("Sketches detected.");
+        let param_and_sketches = file_io::sketches_from_sketch(&command_params.ref_files);
+        if param_and_sketches.0.c != sketch_params.c {
+            warn!("Input parameter c = {} is not equal to the sketch parameter c = {}. Using sketch parameters.", sketch_params.c, param_and_sketches.0.c);
+        }
+        sketches = param_and_sketches.1;
+        sketch_params = param_and_sketches.0;
+    } else {
+        sketches = file_io::fastx_to_sketches(&command_params.ref_files, &sketch_params, true);
+    }
+    if sketches.is_empty() {
+        error!("No genomes/sketches found.");
+        std::process::exit(1)
+    }
+    sketches.sort_by(|x, y| x.file_name.cmp(&y.file_name));
+
+    let scores = genome_scores(&sketches, &quality);
+    let mut order = (0..sketches.len()).collect::<Vec<usize>>();
+    order.sort_by(|x, y| scores[*y].total_cmp(&scores[*x]).then(x.cmp(y)));
+
+    let edges = cluster::passing_edges(
+        &sketches,
+        &sketch_params,
+        &command_params,
+        command_params.cluster_ani,
+    );
+    let reps = cluster::cluster_genomes(sketches.len(), &edges, ClusterMethod::Greedy, &order);
+    let rep_sketches = order
+        .iter()
+        .filter(|x| reps[**x] == **x)
+        .map(|x| &sketches[*x])
+        .collect::<Vec<&Sketch>>();
+    info!(
+        "{} representatives chosen for {} genomes.",
+        rep_sketches.len(),
+        sketches.len()
+    );
+
+    let prefix = &command_params.out_file_name;
+    let create = |file_name: &str| {
+        BufWriter::new(File::create(file_name).unwrap_or_else(|e| {
+            error!("Could not create {}: {}. Exiting.", file_name, e);
+            std::process::exit(1)
+        }))
+    };
+    let reps_file = format!("{}.representatives.txt", prefix);
+    let mut handle = create(&reps_file);
+    for rep in rep_sketches.iter() {
+        writeln!(handle, "{}", rep.file_name).unwrap();
+    }
+    let clusters_file = format!("{}.clusters.tsv", prefix);
+    let names = sketches.iter().map(|x| x.file_name.clone()).collect::<Vec<String>>();
+    cluster::write_clusters(&mut create(&clusters_file), &names, &reps, &edges, Some(&scores));
+    info!("Representatives written to {} and genome to representative mapping to {}", reps_file, clusters_file);
+
+    if !command_params.derep_out_dir.is_empty() {
+        place_representatives(&rep_sketches, &command_params.derep_out_dir, command_params.derep_symlink);
+    }
+    info!("Dereplication time: {}", now.elapsed().as_secs_f32());
+}
@@ -1,9 +1,12 @@
 use crate::chain;
+use crate::checkpoint::{self, Checkpoint};
 use crate::regression;
 use crate::file_io;
+use crate::grouping::GenomeGrouping;
 use crate::params::*;
 use crate::screen;
 use crate::types::*;
+use fxhash::FxHashMap;
 use log::*;
 use rayon::prelude::*;
 use std::sync::Mutex;
@@ -12,8 +15,9 @@ use std::time::Instant;
 pub fn dist(command_params: CommandParams, mut sketch_params: SketchParams) {
     let ref_sketches;
     let query_params;
-    let query_sketches;
+    let mut query_sketches;
     let now = Instant::now();
+    let grouping = GenomeGrouping::from_params(&command_params);
     if command_params.refs_are_sketch {
         let new_sketch_params;
         info!("Sketches detected.");
@@ -24,6 +28,13 @@ pub fn dist(command_params: CommandParams, mut sketch_params: SketchParams) {
             warn!("Parameters from .sketch files not equal to the input parameters. Using parameters from .sketch files.")
         }
         sketch_params = new_sketch_params;
+    } else if let Some(grouping) = &grouping {
+        ref_sketches = file_io::fastx_to_grouped_sketches(
+            &command_params.ref_files,
+            grouping,
+            &sketch_params,
+            true,
+        );
     } else if command_params.individual_contig_r {
         ref_sketches = file_io::fastx_to_multiple_sketch_rewrite(
             &command_params.ref_files,
@@ -44,6 +55,13 @@ pub fn dist(command_params: CommandParams, mut sketch_params: SketchParams) {
         } else if sketch_params != query_params {
             warn!("Parameters from .sketch files not equal to the input parameters. Using parameters from .sketch files.")
         }
+    } else if let Some(grouping) = &grouping {
+        query_sketches = file_io::fastx_to_grouped_sketches(
+            &command_params.query_files,
+            grouping,
+            &sketch_params,
+            true,
+        );
     } else if command_params.individual_contig_q {
         query_sketches = file_io::fastx_to_multiple_sketch_rewrite(
             &command_params.query_files,
@@ -58,6 +76,7 @@ pub fn dist(command_params: CommandParams, mut sketch_params: SketchParams) {
         error!("No reference sketches/genomes or query sketches/genomes found.");
         std::process::exit(1)
     }
+    sort_by_input_order(&mut query_sketches, &command_params.query_files, command_params.queries_are_sketch);
 
     let screen_val;
     if command_params.screen_val == 0. {
@@ -83,59 +102,90 @@ pub fn dist(command_params: CommandParams, mut sketch_params: SketchParams) {
 
     info!("Generating sketch time: {}", now.elapsed().as_secs_f32());
     let now = Instant::now();
+    let mut checkpoint = Checkpoint::open(
+        &command_params,
+        !command_params.out_file_name.is_empty() && command_params.output_format != OutputFormat::Json,
+    );
+    let query_name = |j: usize| checkpoint::query_name(&query_sketches[j], command_params.individual_contig_q);
     let js = (0..query_sketches.len())
-        .into_iter()
+        .filter(|j| !checkpoint.is_done(&query_name(*j)))
         .collect::<Vec<usize>>();
-    let anis: Mutex<Vec<AniEstResult>> = Mutex::new(vec![]);
     let counter: Mutex<usize> = Mutex::new(0);
-    let first_write: Mutex<bool> = Mutex::new(true);
-    js.into_par_iter().for_each(|j| {
+    let mut first_write = !checkpoint.resumed();
+    let model_opt = regression::get_model(sketch_params.c, command_params.learned_ani);
+    if model_opt.is_some(){
+        info!("{}",LEARNED_INFO_HELP);
+    }
+    let write_details = !command_params.chains_file.is_empty()
+        || !command_params.fragment_ani_file.is_empty()
+        || !command_params.aligned_bed_prefix.is_empty();
+    let details: Mutex<Vec<AlignmentDetails>> = Mutex::new(vec![]);
+    let chain_seeds = |ref_sketch: &Sketch, query_sketch: &Sketch, map_params: MapParams| {
+        if !write_details {
+            return chain::chain_seeds(ref_sketch, query_sketch, map_params);
+        }
+        let (ani_res, pair_details) = chain::chain_seeds_detailed(ref_sketch, query_sketch, map_params);
+        if ani_res.ani > 0.1 {
+            details.lock().unwrap().push(pair_details);
+        }
+        ani_res
+    };
+    let query_anis = |j: usize| {
         let query_sketch = &query_sketches[j];
+        let mut query_anis: Vec<AniEstResult>;
         if !command_params.screen {
-            let is = (0..ref_sketches.len()).into_iter().collect::<Vec<usize>>();
-            is.into_par_iter().for_each(|i| {
+            query_anis = (0..ref_sketches.len()).into_par_iter().filter_map(|i| {
                 let ref_sketch = &ref_sketches[i];
                 let passed_screen =
                     chain::check_markers_quickly(query_sketch, ref_sketch, screen_val);
-                if passed_screen {
-                    let map_params = chain::map_params_from_sketch(
-                        ref_sketch,
-                        sketch_params.use_aa,
-                        &command_params,
-                    );
-                    let ani_res;
-                    if map_params != MapParams::default() {
-                        ani_res = chain::chain_seeds(ref_sketch, query_sketch, map_params);
-                    } else {
-                        ani_res = AniEstResult::default();
-                    }
-                    if ani_res.ani > 0.1 {
-                        let mut locked = anis.lock().unwrap();
-                        locked.push(ani_res);
-                    }
+                if !passed_screen {
+                    return None;
+                }
+                let map_params = chain::map_params_from_sketch(
+                    ref_sketch,
+                    sketch_params.use_aa,
+                    &command_params,
+                );
+                let ani_res;
+                if map_params != MapParams::default() {
+                    ani_res = chain_seeds(ref_sketch, query_sketch, map_params);
+                } else {
+                    ani_res = AniEstResult::default();
+                }
+                if ani_res.ani > 0.1 {
+                    Some(ani_res)
+                } else {
+                    None
                 }
-            });
+            }).collect();
         } else {
-            let refs_passing_screen_table = screen::screen_refs(
+            let mut refs_passing_screen = screen::screen_refs(
                 screen_val,
                 &kmer_to_sketch,
                 query_sketch,
                 &sketch_params,
                 &ref_sketches,
-            );
-            refs_passing_screen_table.into_par_iter().for_each(|i| {
+            ).into_iter().collect::<Vec<usize>>();
+            refs_passing_screen.sort_unstable();
+            query_anis = refs_passing_screen.into_par_iter().filter_map(|i| {
                 let ref_sketch = &ref_sketches[i];
                 let map_params = chain::map_params_from_sketch(
                     ref_sketch,
                     sketch_params.use_aa,
                     &command_params,
                 );
-                let ani_res = chain::chain_seeds(ref_sketch, query_sketch, map_params);
-                if ani_res.ani > 0.1{
-                    let mut locked = anis.lock().unwrap();
-                    locked.push(ani_res);
+                let ani_res = chain_seeds(ref_sketch, query_sketch, map_params);
+                if ani_res.ani > 0.1 {
+                    Some(ani_res)
+                } else {
+                    None
                 }
-            });
+            }).collect();
+        }
+        if let Some(model) = model_opt.as_ref() {
+            for ani in query_anis.iter_mut() {
+                regression::predict_from_ani_res(ani, model);
+            }
         }
         let c;
         {
@@ -145,36 +195,47 @@ pub fn dist(command_params: CommandParams, mut sketch_params: SketchParams) {
         }
         if c % 100 == 0 && c != 0{
             info!("{} query sequences processed.", c);
-            if c % INTERMEDIATE_WRITE_COUNT == 0 && c != 0{
-                info!("Writing results for {} query sequences.", INTERMEDIATE_WRITE_COUNT);
-                let moved_anis: Vec<AniEstResult>;
-                {
-                let mut locked = anis.lock().unwrap();
-                moved_anis = std::mem::take(&mut locked);
-                }
-                let mut fw = first_write.lock().unwrap();
-                file_io::write_query_ref_list(
-                    &moved_anis,
-                    &command_params.out_file_name,
-                    command_params.max_results,
-                    sketch_params.use_aa,
-                    command_params.est_ci,
-                    command_params.detailed_out,
-                    !*fw
-                );
-                if *fw == true{
-                    *fw = false;
-                }
-            }
         }
-    });
-    let mut anis = anis.into_inner().unwrap();
-    let model_opt = regression::get_model(sketch_params.c, command_params.learned_ani);
-    if model_opt.is_some(){
-        info!("{}",LEARNED_INFO_HELP);
-        let model = model_opt.as_ref().unwrap();
-        for ani in anis.iter_mut(){
-            regression::predict_from_ani_res(ani, &model);
+        query_anis
+    };
+
+    //Queries are run in chunks of INTERMEDIATE_WRITE_COUNT. Each chunk is
+    //collected in input order and written once it is finished, so the output
+    //does not depend on the number of threads. Alignment details are written
+    //after every chunk so they are not held until the end.
+    let mut anis = vec![];
+    let mut finished = vec![];
+    let mut append_details = checkpoint.resumed();
+    for chunk in js.chunks(INTERMEDIATE_WRITE_COUNT) {
+        let chunk_anis = chunk
+            .par_iter()
+            .map(|j| query_anis(*j))
+            .collect::<Vec<Vec<AniEstResult>>>();
+        if write_details {
+            let mut chunk_details = std::mem::take(&mut *details.lock().unwrap());
+            write_alignment_details(&mut chunk_details, &command_params, append_details);
+            append_details = true;
+        }
+        for (j, query_anis) in chunk.iter().zip(chunk_anis) {
+            anis.extend(query_anis);
+            finished.push(query_name(*j));
+        }
+        if chunk.len() == INTERMEDIATE_WRITE_COUNT && command_params.output_format != OutputFormat::Json {
+            info!("Writing results for {} query sequences.", INTERMEDIATE_WRITE_COUNT);
+            file_io::write_query_ref_list(
+                &anis,
+                &command_params.out_file_name,
+                command_params.max_results,
+                sketch_params.use_aa,
+                command_params.est_ci,
+                command_params.detailed_out,
+                command_params.output_format,
+                !first_write
+            );
+            checkpoint.record(&finished);
+            first_write = false;
+            anis.clear();
+            finished.clear();
         }
     }
     file_io::write_query_ref_list(
@@ -184,7 +245,56 @@ pub fn dist(command_params: CommandParams, mut sketch_params: SketchParams) {
         sketch_params.use_aa,
         command_params.est_ci,
         command_params.detailed_out,
-        !*first_write.lock().unwrap()
+        command_params.output_format,
+        !first_write
     );
+    checkpoint.record(&finished);
+    checkpoint.finish();
+    if !command_params.chains_file.is_empty() {
+        info!("Chains written to {}", command_params.chains_file);
+    }
+    if !command_params.fragment_ani_file.is_empty() {
+        info!("Fragment identities written to {}", command_params.fragment_ani_file);
+    }
+    if !command_params.aligned_bed_prefix.is_empty() {
+        info!("Aligned and unaligned regions written to {}.*.bed", command_params.aligned_bed_prefix);
+    }
     info!("ANI calculation time: {}", now.elapsed().as_secs_f32());
 }
+
+fn write_alignment_details(details: &mut [AlignmentDetails], command_params: &CommandParams, append: bool) {
+    if !command_params.chains_file.is_empty() {
+        let mut chains = details.iter_mut().flat_map(|x| x.chains.drain(..)).collect::<Vec<_>>();
+        file_io::write_chains_paf(&mut chains, &command_params.chains_file, append);
+    }
+    if !command_params.fragment_ani_file.is_empty() {
+        let mut fragments = details.iter_mut().flat_map(|x| x.fragments.drain(..)).collect::<Vec<_>>();
+        file_io::write_fragment_anis(&mut fragments, &command_params.fragment_ani_file, append);
+    }
+    if !command_params.aligned_bed_prefix.is_empty() {
+        file_io::write_aligned_beds(details, &command_params.aligned_bed_prefix, append);
+    }
+}
+
+//The sketch loaders sort by file name; put the queries back in the order of
+//the query files. Records of one file stay in file order. Sketches are matched
+//to .sketch files by name; sketches that match no file (e.g. genomes from
+//--genome-map) go last in name order.
+fn sort_by_input_order(query_sketches: &mut [Sketch], query_files: &[String], are_sketch: bool) {
+    let positions = query_files
+        .iter()
+        .enumerate()
+        .map(|(i, x)| (if are_sketch { file_io::base_name(x) } else { x.as_str() }, i))
+        .collect::<FxHashMap<&str, usize>>();
+    query_sketches.sort_by_cached_key(|x| {
+        let position = if are_sketch {
+            let name = file_io::base_name(&x.file_name);
+            positions
+                .get(format!("{}.sketch", name).as_str())
+                .or_else(|| positions.get(format!("{}_{}.sketch", x.contig_order, name).as_str()))
+        } else {
+            positions.get(x.file_name.as_str())
+        };
+        position.copied().unwrap_or(usize::MAX)
+    });
+}
@@ -0,0 +1,273 @@
+use crate::chain;
+use crate::error::*;
+use crate::file_io;
+use crate::params::*;
+use crate::regression;
+use crate::screen;
+use crate::types::*;
+use fxhash::FxHashMap;
+use rayon::prelude::*;
+use std::sync::Mutex;
+
+//Library entry point for computing ANI without going through the command line.
+//Nothing here sets up logging or builds a rayon thread pool; parallel
+//work runs on whatever pool the caller is in (the global one by default).
+//
+//Usage:
+//  let engine = AniEngine::from_sketch_params(SketchParams::new(1000, 125, 15, false, false))?;
+//  let refs = engine.sketch_files(&ref_files)?;
+//  let queries = vec![engine.sketch_records("bin.1", vec![("contig_1", seq)])?];
+//  let results = engine.dist(&queries, &refs)?;
+pub struct AniEngine {
+    sketch_params: SketchParams,
+    map_params: MapParams,
+    screen_val: f64,
+    learned_ani: bool,
+}
+
+pub type AniMatrix = FxHashMap<usize, FxHashMap<usize, AniEstResult>>;
+
+impl AniEngine {
+    pub fn new(sketch_params: SketchParams, map_params: MapParams) -> Result<AniEngine> {
+        if sketch_params.c == 0 || sketch_params.k == 0 {
+            return Err(SkaniError::ParameterMismatch(
+                "c and k must be non-zero".to_string(),
+            ));
+        }
+        if sketch_params.c > sketch_params.marker_c {
+            return Err(SkaniError::ParameterMismatch(format!(
+                "c ({}) > marker c ({}) is not allowed",
+                sketch_params.c, sketch_params.marker_c
+            )));
+        }
+        if map_params.k != sketch_params.k || map_params.amino_acid != sketch_params.use_aa {
+            return Err(SkaniError::ParameterMismatch(format!(
+                "map parameters (k = {}, amino acid = {}) do not match sketch parameters (k = {}, amino acid = {})",
+                map_params.k, map_params.amino_acid, sketch_params.k, sketch_params.use_aa
+            )));
+        }
+        let screen_val = if sketch_params.use_aa {
+            SEARCH_AAI_CUTOFF_DEFAULT
+        } else {
+            SEARCH_ANI_CUTOFF_DEFAULT
+        };
+        let learned_ani = regression::use_learned_ani(
+            sketch_params.c,
+            false,
+            false,
+            map_params.robust,
+            map_params.median,
+        );
+        Ok(AniEngine {
+            sketch_params,
+            map_params,
+            screen_val,
+            learned_ani,
+        })
+    }
+
+    //Uses the same default mapping parameters as `skani dist`.
+    pub fn from_sketch_params(sketch_params: SketchParams) -> Result<AniEngine> {
+        let map_params = chain::map_params_from_values(
+            sketch_params.c,
+            sketch_params.k,
+            0,
+            sketch_params.use_aa,
+            -1.,
+            false,
+            false,
+        );
+        AniEngine::new(sketch_params, map_params)
+    }
+
+    //Screen out pairs with identity below this value (0 to 1) before chaining.
+    pub fn screen_val(mut self, screen_val: f64) -> AniEngine {
+        self.screen_val = screen_val;
+        self
+    }
+
+    pub fn learned_ani(mut self, learned_ani: bool) -> AniEngine {
+        self.learned_ani = learned_ani;
+        self
+    }
+
+    pub fn sketch_params(&self) -> &SketchParams {
+        &self.sketch_params
+    }
+
+    pub fn map_params(&self) -> &MapParams {
+        &self.map_params
+    }
+
+    pub fn sketch_files(&self, files: &Vec<String>) -> Result<Vec<Sketch>> {
+        let sketches = file_io::fastx_to_sketches(files, &self.sketch_params, true);
+        if sketches.is_empty() {
+            return Err(SkaniError::EmptyInput(
+                "no valid fasta/fastq sequences found".to_string(),
+            ));
+        }
+        Ok(sketches)
+    }
+
+    //Sketch in-memory (id, sequence) records as one genome.
+    pub fn sketch_records<I, S, T>(&self, name: &str, records: I) -> Result<Sketch>
+    where
+        I: IntoIterator<Item = (S, T)>,
+        S: AsRef<[u8]>,
+        T: AsRef<[u8]>,
+    {
+        file_io::records_to_sketch(name, records, &self.sketch_params, true).ok_or_else(|| {
+            SkaniError::EmptyInput(format!(
+                "{} has no sequences of length >= {}",
+                name, MIN_LENGTH_CONTIG
+            ))
+        })
+    }
+
+    //Load .sketch files generated by `skani sketch`. The parameters
+    //must match the engine's.
+    pub fn load_sketches(&self, files: &[String]) -> Result<Vec<Sketch>> {
+        let mut sketches = vec![];
+        for file in files.iter() {
+            let (sketch_params, sketch) = file_io::read_sketch_file(file)?;
+            self.check_params(&sketch_params, file)?;
+            sketches.push(sketch);
+        }
+        Ok(sketches)
+    }
+
+    pub fn compare(&self, ref_sketch: &Sketch, query_sketch: &Sketch) -> Result<AniEstResult> {
+        self.check_sketch(ref_sketch)?;
+        self.check_sketch(query_sketch)?;
+        let model = regression::get_model(self.sketch_params.c, self.learned_ani);
+        Ok(self.compare_unchecked(ref_sketch, query_sketch, &model))
+    }
+
+    //Like compare, but also returns the chains (aligned regions) and fragment
+    //identities used for the estimate.
+    pub fn compare_detailed(
+        &self,
+        ref_sketch: &Sketch,
+        query_sketch: &Sketch,
+    ) -> Result<(AniEstResult, AlignmentDetails)> {
+        self.check_sketch(ref_sketch)?;
+        self.check_sketch(query_sketch)?;
+        let model = regression::get_model(self.sketch_params.c, self.learned_ani);
+        let (mut ani_res, details) =
+            chain::chain_seeds_detailed(ref_sketch, query_sketch, self.map_params.clone());
+        if let Some(model) = model {
+            regression::predict_from_ani_res(&mut ani_res, &model);
+        }
+        Ok((ani_res, details))
+    }
+
+    //Every query against every reference. Only pairs passing the screen and
+    //aligned fraction cutoffs are returned, ordered by query then reference.
+    pub fn dist(&self, queries: &[Sketch], refs: &[Sketch]) -> Result<Vec<AniEstResult>> {
+        if queries.is_empty() || refs.is_empty() {
+            return Err(SkaniError::EmptyInput(
+                "no reference or query sketches given".to_string(),
+            ));
+        }
+        for sketch in queries.iter().chain(refs.iter()) {
+            self.check_sketch(sketch)?;
+        }
+        let model = regression::get_model(self.sketch_params.c, self.learned_ani);
+        let anis: Mutex<Vec<(usize, usize, AniEstResult)>> = Mutex::new(vec![]);
+        (0..queries.len()).into_par_iter().for_each(|j| {
+            (0..refs.len()).into_par_iter().for_each(|i| {
+                if chain::check_markers_quickly(&queries[j], &refs[i], self.screen_val) {
+                    let ani_res = self.compare_unchecked(&refs[i], &queries[j], &model);
+                    if ani_res.ani > 0.1 {
+                        anis.lock().unwrap().push((j, i, ani_res));
+                    }
+                }
+            });
+        });
+        let mut anis = anis.into_inner().unwrap();
+        anis.sort_by_key(|x| (x.0, x.1));
+        Ok(anis.into_iter().map(|x| x.2).collect())
+    }
+
+    //All pairs within one set of sketches, like `skani triangle`. The key
+    //of the outer map is always the smaller index.
+    pub fn triangle(&self, sketches: &[Sketch]) -> Result<AniMatrix> {
+        if sketches.is_empty() {
+            return Err(SkaniError::EmptyInput("no sketches given".to_string()));
+        }
+        for sketch in sketches.iter() {
+            self.check_sketch(sketch)?;
+        }
+        let model = regression::get_model(self.sketch_params.c, self.learned_ani);
+        let kmer_to_sketch = screen::kmer_to_sketch_from_refs(sketches);
+        let anis: Mutex<AniMatrix> = Mutex::new(FxHashMap::default());
+        (0..sketches.len()).into_par_iter().for_each(|i| {
+            let screened_refs = screen::screen_refs(
+                self.screen_val,
+                &kmer_to_sketch,
+                &sketches[i],
+                &self.sketch_params,
+                sketches,
+            );
+            screened_refs.into_par_iter().for_each(|j| {
+                if j > i {
+                    let ani_res = self.compare_unchecked(&sketches[i], &sketches[j], &model);
+                    if ani_res.ani > 0.1 {
+                        let mut locked = anis.lock().unwrap();
+                        locked.entry(i).or_default().insert(j, ani_res);
+                    }
+                }
+            });
+        });
+        Ok(anis.into_inner().unwrap())
+    }
+
+    fn compare_unchecked(
+        &self,
+        ref_sketch: &Sketch,
+        query_sketch: &Sketch,
+        model: &Option<gbdt::gradient_boost::GBDT>,
+    ) -> AniEstResult {
+        let mut ani_res = chain::chain_seeds(ref_sketch, query_sketch, self.map_params.clone());
+        if let Some(model) = model {
+            regression::predict_from_ani_res(&mut ani_res, model);
+        }
+        ani_res
+    }
+
+    fn check_params(&self, sketch_params: &SketchParams, file: &str) -> Result<()> {
+        if *sketch_params != self.sketch_params {
+            return Err(SkaniError::ParameterMismatch(format!(
+                "{} was sketched with c = {}, k = {}, marker c = {} but the engine uses c = {}, k = {}, marker c = {}",
+                file,
+                sketch_params.c,
+                sketch_params.k,
+                sketch_params.marker_c,
+                self.sketch_params.c,
+                self.sketch_params.k,
+                self.sketch_params.marker_c
+            )));
+        }
+        Ok(())
+    }
+
+    fn check_sketch(&self, sketch: &Sketch) -> Result<()> {
+        if sketch.c != self.sketch_params.c
+            || sketch.k != self.sketch_params.k
+            || sketch.amino_acid != self.sketch_params.use_aa
+        {
+            return Err(SkaniError::ParameterMismatch(format!(
+                "{} has c = {}, k = {} but the engine uses c = {}, k = {}",
+                sketch.file_name, sketch.c, sketch.k, self.sketch_params.c, self.sketch_params.k
+            )));
+        }
+        if sketch.kmer_seeds_k.is_none() || sketch.contig_lengths.is_empty() {
+            return Err(SkaniError::BadSketch {
+                file: sketch.file_name.clone(),
+                reason: "sketch has no seeds (marker-only sketches can not be used for ANI)"
+                    .to_string(),
+            });
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,31 @@
+use thiserror::Error;
+
+//Errors returned by the library API (see engine.rs). The command line
+//still logs and exits on most errors; these are for users that embed skani.
+#[derive(Error, Debug)]
+pub enum SkaniError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("{file} is not a valid sketch: {reason}")]
+    BadSketch { file: String, reason: String },
+
+    #[error("{file} uses sketch format version {found}, but this version of skani ({skani_version}) only reads version {expected}. Re-sketch the genomes with `skani sketch`.")]
+    IncompatibleVersion {
+        file: String,
+        found: u32,
+        expected: u32,
+        skani_version: String,
+    },
+
+    #[error("{file} is not a valid skani sparse matrix: {reason}")]
+    BadMatrix { file: String, reason: String },
+
+    #[error("Sketch parameter mismatch: {0}")]
+    ParameterMismatch(String),
+
+    #[error("Empty input: {0}")]
+    EmptyInput(String),
+}
+
+pub type Result<T> = std::result::Result<T, SkaniError>;
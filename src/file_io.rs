@@ -1,16 +1,23 @@
+use crate::error::*;
+use crate::grouping::GenomeGrouping;
 use crate::params::*;
 use std::fs::OpenOptions;
 use crate::seeding;
 use crate::types::*;
 use fxhash::FxHashMap;
 use log::*;
-use needletail::parse_fastx_file;
+use needletail::errors::ParseError;
+use needletail::{parse_fastx_reader, FastxReader};
 use rand::seq::SliceRandom;
 use rand::thread_rng;
 use rayon::prelude::*;
 use std::fs::File;
-use std::io::{self, BufReader, BufWriter, Write};
-use std::sync::Mutex;
+use bincode::Options;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::io::{self, BufReader, BufWriter, Cursor, Read, Write};
+use std::path::Path;
+use std::sync::{Mutex, Once};
 
 fn write_header(writer: &mut impl Write, id_str: &str, ci: bool, verbose: bool) {
     if !ci && !verbose {
@@ -80,6 +87,183 @@ fn write_ani_res(writer: &mut impl Write, ani_res: &AniEstResult, ci: bool, verb
     }
 }
 
+//TSV (with the header unless appending), one JSON array, or one JSON object
+//per line. Values in JSON are not scaled to percentages.
+fn write_ani_results<'a>(
+    writer: &mut impl Write,
+    anis: impl IntoIterator<Item = &'a AniEstResult>,
+    output_format: OutputFormat,
+    id_str: &str,
+    ci: bool,
+    verbose: bool,
+    append: bool,
+) {
+    match output_format {
+        OutputFormat::Tsv => {
+            if !append {
+                write_header(writer, id_str, ci, verbose);
+            }
+            for ani_res in anis {
+                write_ani_res(writer, ani_res, ci, verbose);
+            }
+        }
+        OutputFormat::Json => {
+            let anis = anis.into_iter().collect::<Vec<_>>();
+            serde_json::to_writer_pretty(&mut *writer, &anis).unwrap();
+            writeln!(writer).unwrap();
+        }
+        OutputFormat::Jsonl => {
+            for ani_res in anis {
+                serde_json::to_writer(&mut *writer, ani_res).unwrap();
+                writeln!(writer).unwrap();
+            }
+        }
+    }
+}
+
+//Adds one record to the sketch, seeding it with the AA, AVX2 or default
+//seeding method. Records shorter than MIN_LENGTH_CONTIG are skipped; returns
+//whether the record was added.
+fn add_record_to_sketch(
+    new_sketch: &mut Sketch,
+    contig: &[u8],
+    seq: &[u8],
+    contig_index: ContigIndex,
+    sketch_params: &SketchParams,
+    seed: bool,
+) -> bool {
+    if seq.len() < MIN_LENGTH_CONTIG {
+        return false;
+    }
+    new_sketch
+        .contigs
+        .push(String::from_utf8_lossy(contig).to_string());
+    new_sketch.contig_lengths.push(seq.len() as GnPosition);
+    new_sketch.total_sequence_length += seq.len();
+    if sketch_params.use_aa {
+        let orfs = seeding::get_orfs(seq, sketch_params);
+        seeding::fmh_seeds_aa_with_orf(seq, sketch_params, contig_index, new_sketch, orfs, seed)
+    } else {
+        #[cfg(any(target_arch = "x86_64"))]
+        {
+            if is_x86_feature_detected!("avx2") {
+                use crate::avx2_seeding;
+                unsafe {
+                    avx2_seeding::avx2_fmh_seeds(seq, sketch_params, contig_index, new_sketch, seed);
+                }
+            } else {
+                seeding::fmh_seeds(seq, sketch_params, contig_index, new_sketch, seed);
+            }
+        }
+        #[cfg(not(target_arch = "x86_64"))]
+        {
+            seeding::fmh_seeds(seq, sketch_params, contig_index, new_sketch, seed);
+        }
+    }
+    true
+}
+
+//Sketch in-memory (id, sequence) records as one genome called `name`.
+//Returns None if no record is long enough to be sketched.
+pub fn records_to_sketch<I, S, T>(
+    name: &str,
+    records: I,
+    sketch_params: &SketchParams,
+    seed: bool,
+) -> Option<Sketch>
+where
+    I: IntoIterator<Item = (S, T)>,
+    S: AsRef<[u8]>,
+    T: AsRef<[u8]>,
+{
+    let mut new_sketch = Sketch::new(
+        sketch_params.marker_c,
+        sketch_params.c,
+        sketch_params.k,
+        name.to_string(),
+        sketch_params.use_aa,
+    );
+    let mut j = 0;
+    for (contig, seq) in records {
+        if add_record_to_sketch(
+            &mut new_sketch,
+            contig.as_ref(),
+            seq.as_ref(),
+            j,
+            sketch_params,
+            seed,
+        ) {
+            j += 1;
+        }
+    }
+    if j == 0 {
+        None
+    } else {
+        Some(new_sketch)
+    }
+}
+
+//Same as records_to_sketch, but each record becomes its own sketch (like -i/--qi/--ri).
+pub fn records_to_multiple_sketches<I, S, T>(
+    name: &str,
+    records: I,
+    sketch_params: &SketchParams,
+    seed: bool,
+) -> Vec<Sketch>
+where
+    I: IntoIterator<Item = (S, T)>,
+    S: AsRef<[u8]>,
+    T: AsRef<[u8]>,
+{
+    let mut ret = vec![];
+    for (contig, seq) in records {
+        let mut new_sketch = Sketch::new(
+            sketch_params.marker_c,
+            sketch_params.c,
+            sketch_params.k,
+            name.to_string(),
+            sketch_params.use_aa,
+        );
+        if add_record_to_sketch(
+            &mut new_sketch,
+            contig.as_ref(),
+            seq.as_ref(),
+            0,
+            sketch_params,
+            seed,
+        ) {
+            new_sketch.contig_order = ret.len();
+            ret.push(new_sketch);
+        }
+    }
+    ret
+}
+
+//Fasta/fastq reader for a file, or for stdin if the file is `-`. Gzip, bzip2
+//and xz compression are detected by needletail from the first bytes; zstd is
+//detected here and decompressed before needletail sees the data.
+pub fn open_fastx(file: &str) -> std::result::Result<Box<dyn FastxReader>, ParseError> {
+    if file == STDIN_FILE {
+        fastx_reader(io::stdin())
+    } else {
+        fastx_reader(File::open(file)?)
+    }
+}
+
+fn fastx_reader<R: Read + Send + 'static>(
+    mut reader: R,
+) -> std::result::Result<Box<dyn FastxReader>, ParseError> {
+    let mut start = vec![];
+    (&mut reader).take(ZSTD_MAGIC.len() as u64).read_to_end(&mut start)?;
+    let is_zstd = start == ZSTD_MAGIC;
+    let reader = Cursor::new(start).chain(reader);
+    if is_zstd {
+        parse_fastx_reader(zstd::Decoder::new(reader)?)
+    } else {
+        parse_fastx_reader(reader)
+    }
+}
+
 pub fn fastx_to_sketches(
     ref_files: &Vec<String>,
     sketch_params: &SketchParams,
@@ -97,13 +281,13 @@ pub fn fastx_to_sketches(
             ref_file.to_string(),
             sketch_params.use_aa,
         );
-        let reader = parse_fastx_file(ref_file);
-        if reader.is_err() {
+        let reader = open_fastx(ref_file);
+        if let Err(e) = &reader {
             if ref_file.contains(".sketch"){
                 warn!("{} is not a valid fasta/fastq file but has the .sketch extension. Not all inputs have .sketch extension, so fasta/fastq is assumed.", ref_file);
             }
             else{
-                warn!("{} is not a valid fasta/fastq file; skipping.", ref_file);
+                warn!("{} is not a valid fasta/fastq file ({}); skipping.", ref_file, e);
             }
         } else {
             let mut j = 0;
@@ -115,59 +299,7 @@ pub fn fastx_to_sketches(
                     let record = record.unwrap_or_else(|_| panic!("Invalid record for file {}", ref_file));
                     let contig = record.id();
                     let seq = record.seq();
-                    if seq.len() >= MIN_LENGTH_CONTIG {
-                        new_sketch
-                            .contigs
-                            .push(String::from_utf8(contig.to_vec()).unwrap());
-                        new_sketch.contig_lengths.push(seq.len() as GnPosition);
-
-                        new_sketch.total_sequence_length += seq.len();
-                        if sketch_params.use_aa {
-                            let orfs = seeding::get_orfs(&seq, sketch_params);
-                            seeding::fmh_seeds_aa_with_orf(
-                                &seq,
-                                sketch_params,
-                                j as u32,
-                                &mut new_sketch,
-                                orfs,
-                                seed,
-                            )
-                        } else {
-                            #[cfg(any(target_arch = "x86_64"))]
-                            {
-                                if is_x86_feature_detected!("avx2"){
-                                    use crate::avx2_seeding;
-                                    unsafe {
-                                        avx2_seeding::avx2_fmh_seeds(
-                                            &seq,
-                                            sketch_params,
-                                            j as u32,
-                                            &mut new_sketch,
-                                            seed,
-                                        );
-                                    }
-                                } else {
-                                    seeding::fmh_seeds(
-                                        &seq,
-                                        sketch_params,
-                                        j as u32,
-                                        &mut new_sketch,
-                                        seed,
-                                    );
-                                }
-                            }
-                            #[cfg(not(target_arch = "x86_64"))]
-                            {
-                            seeding::fmh_seeds(
-                                        &seq,
-                                        sketch_params,
-                                        j as u32,
-                                        &mut new_sketch,
-                                        seed,
-                                    );
-                            }
-
-                        }
+                    if add_record_to_sketch(&mut new_sketch, contig, &seq, j, sketch_params, seed) {
                         //new_sketch.contig_order = 0;
                         j += 1;
                         is_valid = true;
@@ -206,9 +338,9 @@ pub fn fastx_to_multiple_sketch_rewrite(
     index_vec.shuffle(&mut thread_rng());
     index_vec.into_par_iter().for_each(|i| {
         let ref_file = &ref_files[i];
-        let reader = parse_fastx_file(ref_file);
-        if reader.is_err() {
-            warn!("{} is not a valid fasta/fastq file; skipping.", ref_file);
+        let reader = open_fastx(ref_file);
+        if let Err(e) = &reader {
+            warn!("{} is not a valid fasta/fastq file ({}); skipping.", ref_file, e);
         } else {
             let mut j = 0;
             let mut reader = reader.unwrap();
@@ -219,65 +351,14 @@ pub fn fastx_to_multiple_sketch_rewrite(
                         record.unwrap_or_else(|_| panic!("Invalid record for file {}", ref_file));
                     let contig = record.id();
                     let seq = record.seq();
-                    if seq.len() >= MIN_LENGTH_CONTIG {
-                        let mut new_sketch = Sketch::new(
-                            sketch_params.marker_c,
-                            sketch_params.c,
-                            sketch_params.k,
-                            ref_file.to_string(),
-                            sketch_params.use_aa,
-                        );
-                        new_sketch
-                            .contigs
-                            .push(String::from_utf8(contig.to_vec()).unwrap());
-                        new_sketch.contig_lengths.push(seq.len() as GnPosition);
-
-                        new_sketch.total_sequence_length += seq.len();
-                        if sketch_params.use_aa {
-                            let orfs = seeding::get_orfs(&seq, sketch_params);
-                            seeding::fmh_seeds_aa_with_orf(
-                                &seq,
-                                sketch_params,
-                                0_u32,
-                                &mut new_sketch,
-                                orfs,
-                                seed,
-                            )
-                        } else {
-                            #[cfg(any(target_arch = "x86_64"))]
-                            {
-                                if is_x86_feature_detected!("avx2") {
-                                    use crate::avx2_seeding;
-                                    unsafe {
-                                        avx2_seeding::avx2_fmh_seeds(
-                                            &seq,
-                                            sketch_params,
-                                            0_u32,
-                                            &mut new_sketch,
-                                            seed,
-                                        );
-                                    }
-                                } else {
-                                    seeding::fmh_seeds(
-                                        &seq,
-                                        sketch_params,
-                                        0_u32,
-                                        &mut new_sketch,
-                                        seed,
-                                    );
-                                }
-                            }
-                            #[cfg(not(target_arch = "x86_64"))]
-                            {
-                            seeding::fmh_seeds(
-                                        &seq,
-                                        sketch_params,
-                                        j as u32,
-                                        &mut new_sketch,
-                                        seed,
-                                    );
-                            }
-                        }
+                    let mut new_sketch = Sketch::new(
+                        sketch_params.marker_c,
+                        sketch_params.c,
+                        sketch_params.k,
+                        ref_file.to_string(),
+                        sketch_params.use_aa,
+                    );
+                    if add_record_to_sketch(&mut new_sketch, contig, &seq, 0, sketch_params, seed) {
                         new_sketch.contig_order = j;
 
 //                        if new_sketch.total_sequence_length > REPET_KMER_THRESHOLD {
@@ -301,6 +382,96 @@ pub fn fastx_to_multiple_sketch_rewrite(
     ref_sketches
 }
 
+//One sketch per genome of `grouping`, named by the genome id. Records that
+//belong to no genome are skipped, except that a file without any grouped
+//record is sketched as one genome, so single-genome files can be mixed with
+//multi-genome ones.
+pub fn fastx_to_grouped_sketches(
+    ref_files: &Vec<String>,
+    grouping: &GenomeGrouping,
+    sketch_params: &SketchParams,
+    seed: bool,
+) -> Vec<Sketch> {
+    let ref_sketches: Mutex<Vec<_>> = Mutex::new(vec![]);
+    //A genome's sketch is named by its id, so its records have to be in one file.
+    let genome_files: Mutex<FxHashMap<String, String>> = Mutex::new(FxHashMap::default());
+    ref_files.par_iter().for_each(|ref_file| {
+        let mut reader = match open_fastx(ref_file) {
+            Ok(reader) => reader,
+            Err(e) => {
+                warn!("{} is not a valid fasta/fastq file ({}); skipping.", ref_file, e);
+                return;
+            }
+        };
+        let new_sketch = |name: &str| {
+            Sketch::new(
+                sketch_params.marker_c,
+                sketch_params.c,
+                sketch_params.k,
+                name.to_string(),
+                sketch_params.use_aa,
+            )
+        };
+        let mut genomes: Vec<Sketch> = vec![];
+        let mut genome_index: FxHashMap<String, usize> = FxHashMap::default();
+        let mut ungrouped = new_sketch(ref_file);
+        let mut num_ungrouped = 0;
+        while let Some(record) = reader.next() {
+            let record = match record {
+                Ok(record) => record,
+                Err(_) => {
+                    warn!("File {} is not a valid fasta/fastq file", ref_file);
+                    return;
+                }
+            };
+            let header = String::from_utf8_lossy(record.id());
+            let sketch = match grouping.genome(&header) {
+                Some(genome) => {
+                    let index = *genome_index.entry(genome).or_insert_with_key(|genome| {
+                        genomes.push(new_sketch(genome));
+                        genomes.len() - 1
+                    });
+                    &mut genomes[index]
+                }
+                None => {
+                    num_ungrouped += 1;
+                    &mut ungrouped
+                }
+            };
+            let contig_index = sketch.contigs.len() as ContigIndex;
+            add_record_to_sketch(sketch, record.id(), &record.seq(), contig_index, sketch_params, seed);
+        }
+        genomes.retain(|x| !x.contigs.is_empty());
+        if genome_index.is_empty() {
+            if !ungrouped.contigs.is_empty() {
+                genomes.push(ungrouped);
+            }
+        } else {
+            debug!("{} has {} genomes.", ref_file, genomes.len());
+            let mut genome_files = genome_files.lock().unwrap();
+            for genome in genomes.iter() {
+                if let Some(other_file) = genome_files.insert(genome.file_name.clone(), ref_file.clone()) {
+                    error!(
+                        "Genome {} has records in both {} and {}; each genome's records must be in one file. Exiting.",
+                        genome.file_name, other_file, ref_file
+                    );
+                    std::process::exit(1)
+                }
+            }
+            if num_ungrouped > 0 {
+                warn!(
+                    "{} records of {} are not in any genome of the genome map; skipping them.",
+                    num_ungrouped, ref_file
+                );
+            }
+        }
+        ref_sketches.lock().unwrap().extend(genomes);
+    });
+    let mut ref_sketches = ref_sketches.into_inner().unwrap();
+    ref_sketches.sort();
+    ref_sketches
+}
+
 pub fn write_phyllip_matrix(
     anis: &FxHashMap<usize, FxHashMap<usize, AniEstResult>>,
     sketches: &Vec<Sketch>,
@@ -350,52 +521,9 @@ pub fn write_phyllip_matrix(
             writeln!(&mut handle).unwrap();
         }
 
-        let af_mat_file = "skani_matrix.af".to_string();
-        let mut af_file = BufWriter::new(File::create(af_mat_file).unwrap());
-        writeln!(&mut af_file, "{}", sketches.len()).unwrap();
-        for i in 0..sketches.len() {
-            let name;
-            if use_contig_names {
-                name = &sketches[i].contigs[0];
-            } else {
-                name = &sketches[i].file_name;
-            }
-            write!(&mut af_file, "{}", name).unwrap();
-            //We always output full matrix for AF.
-            let end = sketches.len();
-            for j in 0..end {
-                if i == j {
-                    write!(&mut af_file, "\t{:.2}", 100.).unwrap();
-                    continue;
-                }
-                let x = usize::min(i, j);
-                let y = usize::max(i, j);
-                if !anis.contains_key(&x) || !anis[&x].contains_key(&y) {
-                    write!(&mut af_file, "\t{:.2}", 0.).unwrap();
-                } else if anis[&x][&y].ani == -1. || anis[&x][&y].ani.is_nan() {
-                    write!(&mut af_file, "\t{:.2}", 0.).unwrap();
-                } else {
-                    if j > i {
-                        write!(
-                            &mut af_file,
-                            "\t{:.2}",
-                            anis[&x][&y].align_fraction_ref * 100.
-                        )
-                        .unwrap();
-                    } else {
-                        write!(
-                            &mut af_file,
-                            "\t{:.2}",
-                            anis[&x][&y].align_fraction_query * 100.
-                        )
-                        .unwrap();
-                    }
-                }
-            }
-            writeln!(&mut af_file).unwrap();
-        }
-
-        info!("Aligned fraction matrix written to skani_matrix.af");
+        //The align fraction matrix used to go to skani_matrix.af in the working
+        //directory; it is now only written next to an output file.
+        info!("The align fraction matrix is not written to stdout. Use -o FILE to also write it to FILE.af, or --binary to write ANI and align fractions to one file.");
     } else {
         let ani_mat_file = file_name.to_string();
         let af_mat_file = format!("{}.af", file_name);
@@ -476,23 +604,27 @@ pub fn write_sparse_matrix(
     aai: bool,
     est_ci: bool,
     detailed_out: bool,
+    output_format: OutputFormat,
     append: bool
 ) {
     let id_str = if aai { "AAI" } else { "ANI" };
+    //Rows and columns are written in index order so the output does not depend
+    //on hashing or thread scheduling.
+    let mut rows = anis.keys().collect::<Vec<&usize>>();
+    rows.sort();
+    let results = rows
+        .into_iter()
+        .flat_map(|i| {
+            let mut row = anis[i].iter().collect::<Vec<(&usize, &AniEstResult)>>();
+            row.sort_by_key(|x| x.0);
+            row.into_iter().map(|x| x.1)
+        })
+        .filter(|x| !(x.ani == -1. || x.ani.is_nan()));
     if file_name.is_empty() {
         let stdout = io::stdout();
         let mut handle = stdout.lock();
-        if !append{
-            write_header(&mut handle, id_str, est_ci, detailed_out);
-        }
         //        write!(&mut handle,"Ref_file\tQuery_file\t{}\tAlign_fraction_ref\tAlign_fraction_query\t{}_95_percentile\t{}_5_percentile\tRef_name\tQuery_name\n", id_str, id_str, id_str).unwrap();
-        for i in anis.keys() {
-            for (j, ani_res) in anis[i].iter() {
-                if !(anis[i][j].ani == -1. || anis[i][j].ani.is_nan()) {
-                    write_ani_res(&mut handle, ani_res, est_ci, detailed_out);
-                }
-            }
-        }
+        write_ani_results(&mut handle, results, output_format, id_str, est_ci, detailed_out, append);
     } else {
         let ani_mat_file = file_name.to_string();
         let mut ani_file;
@@ -506,17 +638,133 @@ pub fn write_sparse_matrix(
         else{
             ani_file = BufWriter::new(File::create(ani_mat_file).expect(file_name));
         }
-        if !append{
-            write_header(&mut ani_file, id_str, est_ci, detailed_out);
+        write_ani_results(&mut ani_file, results, output_format, id_str, est_ci, detailed_out, append);
+    }
+}
+
+//Details are written after every chunk of queries in `skani dist`; later
+//chunks are appended.
+fn details_writer(file_name: &str, append: bool) -> BufWriter<File> {
+    let file = if append {
+        OpenOptions::new().append(true).create(true).open(file_name)
+    } else {
+        File::create(file_name)
+    };
+    BufWriter::new(file.unwrap_or_else(|e| {
+        error!("Could not create {}: {}. Exiting.", file_name, e);
+        std::process::exit(1)
+    }))
+}
+
+//One PAF line per chain. Column 10 (matches) is estimated from the identity of
+//the chain's fragment and mapping quality is always 255 (missing). Tags:
+//cm = number of anchors, s1 = chaining score, fi = fragment identity
+//(omitted if the fragment was not used), qf/rf = query/reference file.
+pub fn write_chains_paf(chains: &mut [ChainRecord], file_name: &str, append: bool) {
+    let mut handle = details_writer(file_name, append);
+    chains.sort_by(|x, y| {
+        (&x.query_file, &x.ref_file, &x.query_contig, x.query_start)
+            .cmp(&(&y.query_file, &y.ref_file, &y.query_contig, y.query_start))
+    });
+    let first_word = |x: &str| x.split_whitespace().next().unwrap_or("").to_string();
+    for chain in chains.iter() {
+        let block_len = GnPosition::max(
+            chain.query_end - chain.query_start,
+            chain.ref_end - chain.ref_start,
+        );
+        let matches = chain
+            .fragment_identity
+            .map_or(0, |x| (x as f64 * block_len as f64).round() as GnPosition);
+        write!(
+            handle,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t255\tcm:i:{}\ts1:i:{}",
+            first_word(&chain.query_contig),
+            chain.query_contig_len,
+            chain.query_start,
+            chain.query_end,
+            if chain.reverse { '-' } else { '+' },
+            first_word(&chain.ref_contig),
+            chain.ref_contig_len,
+            chain.ref_start,
+            chain.ref_end,
+            matches,
+            block_len,
+            chain.num_anchors,
+            chain.score.round() as i64,
+        )
+        .unwrap();
+        if let Some(identity) = chain.fragment_identity {
+            write!(handle, "\tfi:f:{:.4}", identity).unwrap();
         }
-        for i in anis.keys() {
-            for (j, ani_res) in anis[i].iter() {
-                if !(anis[i][j].ani == -1. || anis[i][j].ani.is_nan()) {
-                    write_ani_res(&mut ani_file, ani_res, est_ci, detailed_out);
-                }
+        writeln!(handle, "\tqf:Z:{}\trf:Z:{}", chain.query_file, chain.ref_file).unwrap();
+    }
+}
+
+//BED-like: the first three columns are the query range. Identities are
+//percentages like the ANI column of `skani dist`.
+pub fn write_fragment_anis(fragments: &mut [FragmentRecord], file_name: &str, append: bool) {
+    let mut handle = details_writer(file_name, append);
+    fragments.sort_by(|x, y| {
+        (&x.query_file, &x.ref_file, &x.query_contig, x.query_start, &x.ref_contig, x.ref_start)
+            .cmp(&(&y.query_file, &y.ref_file, &y.query_contig, y.query_start, &y.ref_contig, y.ref_start))
+    });
+    let first_word = |x: &str| x.split_whitespace().next().unwrap_or("").to_string();
+    if !append {
+        writeln!(handle, "#Query_contig\tQuery_start\tQuery_end\tRef_contig\tRef_start\tRef_end\tIdentity\tNum_seeds\tNum_anchors\tFragmented\tQuery_file\tRef_file").unwrap();
+    }
+    for fragment in fragments.iter() {
+        writeln!(
+            handle,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{:.2}\t{}\t{}\t{}\t{}\t{}",
+            first_word(&fragment.query_contig),
+            fragment.query_start,
+            fragment.query_end,
+            first_word(&fragment.ref_contig),
+            fragment.ref_start,
+            fragment.ref_end,
+            fragment.identity * 100.,
+            fragment.num_seeds,
+            fragment.num_anchors,
+            if fragment.fragmented_query { "query" } else { "ref" },
+            fragment.query_file,
+            fragment.ref_file,
+        )
+        .unwrap();
+    }
+}
+
+//Writes {prefix}.query_aligned.bed, .query_unaligned.bed, .ref_aligned.bed and
+//.ref_unaligned.bed. The name column is the file of the other genome in the pair.
+pub fn write_aligned_beds(details: &mut [AlignmentDetails], prefix: &str, append: bool) {
+    //Pairs of single contigs (--qi, --ri) share file names, so the regions
+    //break ties.
+    details.sort_by(|x, y| {
+        (&x.query_file, &x.ref_file, &x.query_aligned, &x.query_unaligned, &x.ref_aligned, &x.ref_unaligned)
+            .cmp(&(&y.query_file, &y.ref_file, &y.query_aligned, &y.query_unaligned, &y.ref_aligned, &y.ref_unaligned))
+    });
+    let first_word = |x: &str| x.split_whitespace().next().unwrap_or("").to_string();
+    let write_bed = |suffix: &str, regions: &dyn Fn(&AlignmentDetails) -> (&Vec<GenomeRegion>, &String)| {
+        let file_name = format!("{}.{}.bed", prefix, suffix);
+        let mut handle = details_writer(&file_name, append);
+        for pair_details in details.iter() {
+            let (regions, other_file) = regions(pair_details);
+            for region in regions.iter() {
+                writeln!(
+                    handle,
+                    "{}\t{}\t{}\t{}",
+                    first_word(&region.contig),
+                    region.start,
+                    region.end,
+                    other_file
+                )
+                .unwrap();
             }
         }
-    }
+    };
+    write_bed("query_aligned", &|x| (&x.query_aligned, &x.ref_file));
+    write_bed("query_unaligned", &|x| (&x.query_unaligned, &x.ref_file));
+    write_bed("ref_aligned", &|x| (&x.ref_aligned, &x.query_file));
+    write_bed("ref_unaligned", &|x| (&x.ref_unaligned, &x.query_file));
 }
 
 pub fn write_query_ref_list(
@@ -526,43 +774,14 @@ pub fn write_query_ref_list(
     aai: bool,
     est_ci: bool,
     detailed_out: bool,
+    output_format: OutputFormat,
     append: bool,
 ) {
-    let id_str = if aai { "AAI" } else { "ANI" };
-    let mut query_file_result_map = FxHashMap::default();
     let out_file = file_name.to_string();
-
-    for i in 0..anis.len() {
-        if anis[i].ani < 0. || anis[i].ani.is_nan() {
-            continue;
-        }
-        let _ani = if anis[i].ani < 0. {
-            "NA".to_string()
-        } else {
-            format!("{}", anis[i].ani)
-        };
-        let results = query_file_result_map
-            .entry(&anis[i].query_contig)
-            .or_insert(vec![]);
-        results.push(&anis[i]);
-    }
-    let mut sorted_keys = query_file_result_map.keys().collect::<Vec<&&String>>();
-    sorted_keys.sort();
-
     if out_file.is_empty() {
         let stdout = io::stdout();
         let mut handle = stdout.lock();
-        if !append{
-            write_header(&mut handle, id_str, est_ci, detailed_out);
-        }
-        for key in sorted_keys {
-            let mut anis = query_file_result_map[key].clone();
-
-            anis.sort_by(|y, x| x.ani.partial_cmp(&y.ani).unwrap());
-            for i in 0..usize::min(n, anis.len()) {
-                write_ani_res(&mut handle, anis[i], est_ci, detailed_out);
-            }
-        }
+        write_query_ref_list_to(&mut handle, anis, n, aai, est_ci, detailed_out, output_format, append);
     } else {
         let mut handle;
         if append{
@@ -575,21 +794,370 @@ pub fn write_query_ref_list(
         else{
             handle = BufWriter::new(File::create(out_file).expect(file_name));
         }
+        write_query_ref_list_to(&mut handle, anis, n, aai, est_ci, detailed_out, output_format, append);
+    }
+}
+
+//Same as write_query_ref_list, but to any writer (e.g. a socket for `skani serve`).
+pub fn write_query_ref_list_to(
+    handle: &mut impl Write,
+    anis: &[AniEstResult],
+    n: usize,
+    aai: bool,
+    est_ci: bool,
+    detailed_out: bool,
+    output_format: OutputFormat,
+    append: bool,
+) {
+    let id_str = if aai { "AAI" } else { "ANI" };
+    let mut query_file_result_map = FxHashMap::default();
+    //Queries are written in the order they first appear in `anis`. They are
+    //keyed by file and contig, so files with the same first header are kept
+    //apart.
+    let mut query_order = vec![];
 
-        if !append{
-            write_header(&mut handle, id_str, est_ci, detailed_out);
+    for i in 0..anis.len() {
+        if anis[i].ani < 0. || anis[i].ani.is_nan() {
+            continue;
         }
-        for key in sorted_keys {
-            let mut anis = query_file_result_map[key].clone();
+        let key = (&anis[i].query_file, &anis[i].query_contig);
+        let results = query_file_result_map
+            .entry(key)
+            .or_insert_with(|| {
+                query_order.push(key);
+                vec![]
+            });
+        results.push(&anis[i]);
+    }
 
-            anis.sort_by(|y, x| x.ani.partial_cmp(&y.ani).unwrap());
-            for i in 0..usize::min(n, anis.len()) {
-                write_ani_res(&mut handle, anis[i], est_ci, detailed_out);
-            }
+    let mut results = vec![];
+    for key in query_order {
+        let mut anis = query_file_result_map[&key].clone();
+
+        //Ties are broken by reference so that equal ANIs are not written in
+        //the order the threads finished.
+        anis.sort_by(|y, x| {
+            x.ani
+                .partial_cmp(&y.ani)
+                .unwrap()
+                .then_with(|| y.ref_file.cmp(&x.ref_file))
+                .then_with(|| y.ref_contig.cmp(&x.ref_contig))
+        });
+        anis.truncate(n);
+        results.extend(anis);
+    }
+    write_ani_results(handle, results, output_format, id_str, est_ci, detailed_out, append);
+}
+
+//On-disk layout of .sketch and markers.bin files:
+//  SKETCH_MAGIC | format version (u32, little endian) | SketchFileHeader | payload
+//The payload is the bincode encoding of (SketchParams, Sketch) for .sketch files
+//and (SketchParams, (Vec<Sketch>, Vec<String>)) for markers.bin, where the
+//strings are the .sketch file of each marker sketch relative to the folder.
+//markers.bin files written before the sketch files were stored have the
+//Markers kind and (SketchParams, Vec<Sketch>) as the payload. Files written
+//before the header existed are just the payload; these are still read.
+//Triangle shards use the same layout with (SketchParams, TriangleShard) as
+//the payload.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum SketchFileKind {
+    Sketch,
+    Markers,
+    TriangleShard,
+    MarkersWithSketchFiles,
+}
+
+//Results of one `skani triangle --shard I/N` run: the pairs (i, j), i < j,
+//for rows i with i mod N = I. Names are the file name and first contig name
+//of every genome in triangle order, so shards can be checked against each
+//other and merged without the inputs.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TriangleShard {
+    pub shard: usize,
+    pub num_shards: usize,
+    pub use_contig_names: bool,
+    pub names: Vec<(String, String)>,
+    pub anis: Vec<(usize, usize, AniEstResult)>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SketchFileHeader {
+    pub skani_version: String,
+    pub kind: SketchFileKind,
+    pub k: usize,
+    pub c: usize,
+    pub marker_c: usize,
+    pub amino_acid: bool,
+    pub payload_length: u64,
+    pub checksum: u32,
+}
+
+pub fn base_name(file: &str) -> &str {
+    Path::new(file)
+        .file_name()
+        .and_then(|x| x.to_str())
+        .unwrap_or(file)
+}
+
+static LEGACY_WARNING: Once = Once::new();
+
+fn bad_sketch(file: &str, reason: impl ToString) -> SkaniError {
+    SkaniError::BadSketch {
+        file: file.to_string(),
+        reason: reason.to_string(),
+    }
+}
+
+fn write_with_header<T: Serialize>(
+    file_name: &str,
+    kind: SketchFileKind,
+    sketch_params: &SketchParams,
+    payload: &T,
+) -> Result<()> {
+    let payload = bincode::serialize(&(sketch_params, payload)).map_err(|e| bad_sketch(file_name, e))?;
+    let header = SketchFileHeader {
+        skani_version: env!("CARGO_PKG_VERSION").to_string(),
+        kind,
+        k: sketch_params.k,
+        c: sketch_params.c,
+        marker_c: sketch_params.marker_c,
+        amino_acid: sketch_params.use_aa,
+        payload_length: payload.len() as u64,
+        checksum: crc32fast::hash(&payload),
+    };
+    let mut writer = BufWriter::new(File::create(file_name)?);
+    writer.write_all(SKETCH_MAGIC)?;
+    writer.write_all(&SKETCH_FORMAT_VERSION.to_le_bytes())?;
+    bincode::serialize_into(&mut writer, &header).map_err(|e| bad_sketch(file_name, e))?;
+    writer.write_all(&payload)?;
+    writer.flush()?;
+    Ok(())
+}
+
+pub fn write_sketch_file(file_name: &str, sketch_params: &SketchParams, sketch: &Sketch) -> Result<()> {
+    write_with_header(file_name, SketchFileKind::Sketch, sketch_params, sketch)
+}
+
+//sketch_files[i] is the .sketch file of markers[i], relative to the folder of
+//markers.bin. With -i, several marker sketches come from the same fasta file
+//but each has its own .sketch file.
+pub fn write_marker_file(
+    file_name: &str,
+    sketch_params: &SketchParams,
+    markers: &[Sketch],
+    sketch_files: &[String],
+) -> Result<()> {
+    assert!(markers.len() == sketch_files.len());
+    write_with_header(
+        file_name,
+        SketchFileKind::MarkersWithSketchFiles,
+        sketch_params,
+        &(markers, sketch_files),
+    )
+}
+
+//Returns None for legacy files without a header.
+fn read_header(file_name: &str, reader: &mut impl Read) -> Result<Option<SketchFileHeader>> {
+    let mut magic = [0; 8];
+    if reader.read_exact(&mut magic).is_err() || &magic != SKETCH_MAGIC {
+        return Ok(None);
+    }
+    let mut version = [0; 4];
+    reader
+        .read_exact(&mut version)
+        .map_err(|_| bad_sketch(file_name, "file is truncated"))?;
+    let version = u32::from_le_bytes(version);
+    if version != SKETCH_FORMAT_VERSION {
+        return Err(SkaniError::IncompatibleVersion {
+            file: file_name.to_string(),
+            found: version,
+            expected: SKETCH_FORMAT_VERSION,
+            skani_version: env!("CARGO_PKG_VERSION").to_string(),
+        });
+    }
+    let header: SketchFileHeader = bincode::deserialize_from(reader)
+        .map_err(|e| bad_sketch(file_name, format!("header is corrupted ({})", e)))?;
+    Ok(Some(header))
+}
+
+pub fn read_sketch_header(file_name: &str) -> Result<Option<SketchFileHeader>> {
+    let mut reader = BufReader::new(File::open(file_name)?);
+    read_header(file_name, &mut reader)
+}
+
+fn read_with_header<T: DeserializeOwned>(
+    file_name: &str,
+    kind: SketchFileKind,
+) -> Result<(SketchParams, T)> {
+    let mut reader = BufReader::new(File::open(file_name)?);
+    match decode_with_header(file_name, kind, &mut reader)? {
+        Some(res) => Ok(res),
+        None => {
+            let mut bytes = vec![];
+            BufReader::new(File::open(file_name)?).read_to_end(&mut bytes)?;
+            let res: (SketchParams, T) = bincode::DefaultOptions::new()
+                .with_fixint_encoding()
+                .reject_trailing_bytes()
+                .deserialize(&bytes)
+                .map_err(|_| {
+                    bad_sketch(
+                        file_name,
+                        "no skani header found and the file could not be read as an old style sketch",
+                    )
+                })?;
+            LEGACY_WARNING.call_once(|| {
+                warn!("{} was sketched by an older version of skani without a versioned header. Consider re-sketching; support for these files may be removed.", file_name);
+            });
+            Ok(res)
         }
     }
 }
 
+//Returns None if there is no header.
+fn decode_with_header<T: DeserializeOwned>(
+    file_name: &str,
+    kind: SketchFileKind,
+    reader: &mut impl Read,
+) -> Result<Option<(SketchParams, T)>> {
+    let header = match read_header(file_name, reader)? {
+        Some(header) => header,
+        None => return Ok(None),
+    };
+    if header.kind != kind {
+        return Err(bad_sketch(
+            file_name,
+            format!("expected a {:?} file but found a {:?} file", kind, header.kind),
+        ));
+    }
+    let mut payload = vec![];
+    reader.read_to_end(&mut payload)?;
+    if payload.len() as u64 != header.payload_length {
+        return Err(bad_sketch(
+            file_name,
+            format!(
+                "file is truncated or has trailing data (expected {} bytes of sketch data, found {})",
+                header.payload_length,
+                payload.len()
+            ),
+        ));
+    }
+    if crc32fast::hash(&payload) != header.checksum {
+        return Err(bad_sketch(file_name, "checksum mismatch; file is corrupted"));
+    }
+    let (sketch_params, res): (SketchParams, T) = bincode::deserialize(&payload)
+        .map_err(|e| bad_sketch(file_name, format!("sketch data is corrupted ({})", e)))?;
+    if sketch_params.k != header.k
+        || sketch_params.c != header.c
+        || sketch_params.marker_c != header.marker_c
+        || sketch_params.use_aa != header.amino_acid
+    {
+        return Err(bad_sketch(file_name, "header parameters do not match the sketch data"));
+    }
+    Ok(Some((sketch_params, res)))
+}
+
+//Decode a .sketch file that is already in memory. Only files with a header
+//are accepted.
+pub fn sketch_from_bytes(name: &str, mut bytes: &[u8]) -> Result<(SketchParams, Sketch)> {
+    decode_with_header(name, SketchFileKind::Sketch, &mut bytes)?
+        .ok_or_else(|| bad_sketch(name, "no skani header found"))
+}
+
+pub fn write_db_info(db_info: &DbInfo, file_name: &str, output_format: OutputFormat) {
+    let mut handle: Box<dyn Write> = if file_name.is_empty() {
+        Box::new(BufWriter::new(io::stdout()))
+    } else {
+        Box::new(BufWriter::new(File::create(file_name).unwrap_or_else(|e| {
+            error!("Could not create {}: {}. Exiting.", file_name, e);
+            std::process::exit(1)
+        })))
+    };
+    if output_format == OutputFormat::Json {
+        serde_json::to_writer_pretty(&mut handle, db_info).unwrap();
+        writeln!(handle).unwrap();
+        return;
+    }
+    writeln!(handle, "#skani_version\t{}", db_info.skani_version).unwrap();
+    writeln!(handle, "#k\t{}", db_info.k).unwrap();
+    writeln!(handle, "#c\t{}", db_info.c).unwrap();
+    writeln!(handle, "#marker_c\t{}", db_info.marker_c).unwrap();
+    writeln!(
+        handle,
+        "#alphabet\t{}",
+        if db_info.amino_acid { "amino_acid" } else { "DNA" }
+    )
+    .unwrap();
+    writeln!(handle, "#num_genomes\t{}", db_info.num_genomes).unwrap();
+    writeln!(handle, "#total_sequence_length\t{}", db_info.total_sequence_length).unwrap();
+    writeln!(
+        handle,
+        "#sketch_files_without_markers\t{}",
+        db_info.sketch_files_without_markers.len()
+    )
+    .unwrap();
+    writeln!(
+        handle,
+        "#markers_without_sketch_files\t{}",
+        db_info.markers_without_sketch_files.len()
+    )
+    .unwrap();
+    writeln!(handle, "File\tContig_order\tSketch_file\tIn_markers\tNum_contigs\tTotal_length\tNum_markers\tNum_seeds").unwrap();
+    for genome in db_info.genomes.iter() {
+        writeln!(
+            handle,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            genome.file_name,
+            genome.contig_order,
+            genome.sketch_file.as_deref().unwrap_or("NA"),
+            genome.in_markers,
+            genome.num_contigs,
+            genome.total_sequence_length,
+            genome.num_markers,
+            genome
+                .num_seeds
+                .map(|x| x.to_string())
+                .unwrap_or_else(|| "NA".to_string()),
+        )
+        .unwrap();
+    }
+}
+
+pub fn write_triangle_shard(file_name: &str, sketch_params: &SketchParams, shard: &TriangleShard) -> Result<()> {
+    write_with_header(file_name, SketchFileKind::TriangleShard, sketch_params, shard)
+}
+
+pub fn read_triangle_shard(file_name: &str) -> Result<(SketchParams, TriangleShard)> {
+    let mut reader = BufReader::new(File::open(file_name)?);
+    decode_with_header(file_name, SketchFileKind::TriangleShard, &mut reader)?
+        .ok_or_else(|| bad_sketch(file_name, "not a skani triangle shard"))
+}
+
+pub fn read_sketch_file(sketch_file: &str) -> Result<(SketchParams, Sketch)> {
+    read_with_header(sketch_file, SketchFileKind::Sketch)
+}
+
+pub fn read_marker_file(marker_file: &str) -> Result<(SketchParams, Vec<Sketch>)> {
+    read_marker_file_with_sketch_files(marker_file).map(|x| (x.0, x.1))
+}
+
+//The sketch files are None for markers.bin files written without them.
+pub fn read_marker_file_with_sketch_files(
+    marker_file: &str,
+) -> Result<(SketchParams, Vec<Sketch>, Option<Vec<String>>)> {
+    let kind = read_sketch_header(marker_file)?.map(|x| x.kind);
+    if kind == Some(SketchFileKind::MarkersWithSketchFiles) {
+        let (sketch_params, (markers, sketch_files)): (SketchParams, (Vec<Sketch>, Vec<String>)) =
+            read_with_header(marker_file, SketchFileKind::MarkersWithSketchFiles)?;
+        if markers.len() != sketch_files.len() {
+            return Err(bad_sketch(marker_file, "number of sketch files does not match the number of genomes"));
+        }
+        Ok((sketch_params, markers, Some(sketch_files)))
+    } else {
+        let (sketch_params, markers) = read_with_header(marker_file, SketchFileKind::Markers)?;
+        Ok((sketch_params, markers, None))
+    }
+}
+
 pub fn sketches_from_sketch(ref_files: &Vec<String>) -> (SketchParams, Vec<Sketch>) {
     let ret_sketch_params: Mutex<SketchParams> = Mutex::new(SketchParams::default());
     let ret_ref_sketches: Mutex<Vec<Sketch>> = Mutex::new(vec![]);
@@ -600,19 +1168,16 @@ pub fn sketches_from_sketch(ref_files: &Vec<String>) -> (SketchParams, Vec<Sketc
         .for_each(|i| {
             let sketch_file = &ref_files[i];
             if !sketch_file.contains("markers.bin") {
-                let reader = BufReader::new(File::open(sketch_file).expect(sketch_file));
-                let res: Result<(SketchParams, Sketch), _> = bincode::deserialize_from(reader);
-                if res.is_ok() {
-                    let (temp_sketch_param, temp_ref_sketch) = res.unwrap();
+                let res = read_sketch_file(sketch_file);
+                if let Ok((temp_sketch_param, temp_ref_sketch)) = res {
                     let mut locked = ret_sketch_params.lock().unwrap();
                     *locked = temp_sketch_param;
                     let mut locked = ret_ref_sketches.lock().unwrap();
                     locked.push(temp_ref_sketch);
-                } else if sketch_file != "markers.bin" {
-                    error!(
-                        "{} is not a valid .sketch file or is corrupted.",
-                        sketch_file
-                    );
+                } else if let Err(SkaniError::Io(e)) = res {
+                    error!("Could not read {}: {}", sketch_file, e);
+                } else if let Err(e) = res {
+                    error!("{}", e);
                 }
             }
         });
@@ -620,17 +1185,181 @@ pub fn sketches_from_sketch(ref_files: &Vec<String>) -> (SketchParams, Vec<Sketc
     let ret_sketch_params = ret_sketch_params.into_inner().unwrap();
     let mut ret_ref_sketches = ret_ref_sketches.into_inner().unwrap();
 
-    ret_ref_sketches.sort_by(|x, y| x.file_name.cmp(&y.file_name));
+    ret_ref_sketches.sort();
     (ret_sketch_params, ret_ref_sketches)
 }
 
-pub fn marker_sketches_from_marker_file(marker_file: &str) -> (SketchParams, Vec<Sketch>) {
-    let reader = BufReader::new(File::open(marker_file).unwrap());
-    let res: Result<(SketchParams, Vec<Sketch>), _> = bincode::deserialize_from(reader);
-    if res.is_ok() {
-        res.unwrap()
-    } else {
-        error!("Problem reading {}. Exiting. ", marker_file);
-        std::process::exit(1)
+pub fn marker_sketches_from_marker_file(
+    marker_file: &str,
+) -> (SketchParams, Vec<Sketch>, Option<Vec<String>>) {
+    match read_marker_file_with_sketch_files(marker_file) {
+        Ok(res) => res,
+        Err(SkaniError::Io(e)) => panic!("{}: {}", marker_file, e),
+        Err(e) => {
+            error!("{}. Exiting.", e);
+            std::process::exit(1)
+        }
     }
 }
+
+//Binary sparse matrix written by `skani triangle --binary` and `--stream`:
+//  SPARSE_COO_MAGIC | version (u32) | num_genomes (u64) | name table | records until EOF
+//The name table has the length in bytes (u32) and UTF-8 bytes of each genome
+//name, in genome index order. Each record is i (u32), j (u32), ANI (f32),
+//align fraction of i (f32) and align fraction of j (f32), 20 bytes, with
+//i < j. Genome i is the reference and j the query, as in `triangle -E`.
+//Records are sorted by (i, j). All numbers are little endian.
+const SPARSE_COO_HEADER_SIZE: usize = 20;
+const SPARSE_COO_RECORD_SIZE: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SparseEntry {
+    pub i: u32,
+    pub j: u32,
+    pub ani: f32,
+    pub af_i: f32,
+    pub af_j: f32,
+}
+
+pub struct SparseCoo {
+    pub names: Vec<String>,
+    pub entries: Vec<SparseEntry>,
+}
+
+pub struct SparseCooWriter {
+    writer: BufWriter<File>,
+}
+
+impl SparseCooWriter {
+    pub fn create(file_name: &str, names: &[String]) -> Result<SparseCooWriter> {
+        let mut writer = BufWriter::new(File::create(file_name)?);
+        writer.write_all(SPARSE_COO_MAGIC)?;
+        writer.write_all(&SPARSE_COO_VERSION.to_le_bytes())?;
+        writer.write_all(&(names.len() as u64).to_le_bytes())?;
+        for name in names.iter() {
+            writer.write_all(&(name.len() as u32).to_le_bytes())?;
+            writer.write_all(name.as_bytes())?;
+        }
+        writer.flush()?;
+        Ok(SparseCooWriter { writer })
+    }
+
+    //Flushes, so everything written so far is on disk.
+    pub fn write_entries(&mut self, entries: &[SparseEntry]) -> Result<()> {
+        for entry in entries.iter() {
+            self.writer.write_all(&entry.i.to_le_bytes())?;
+            self.writer.write_all(&entry.j.to_le_bytes())?;
+            self.writer.write_all(&entry.ani.to_le_bytes())?;
+            self.writer.write_all(&entry.af_i.to_le_bytes())?;
+            self.writer.write_all(&entry.af_j.to_le_bytes())?;
+        }
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+//True if the file starts like a binary sparse matrix; see read_sparse_coo.
+pub fn is_sparse_coo(file_name: &str) -> bool {
+    let mut start = [0; 8];
+    File::open(file_name)
+        .and_then(|mut x| x.read_exact(&mut start))
+        .is_ok()
+        && &start == SPARSE_COO_MAGIC
+}
+
+pub fn read_sparse_coo(file_name: &str) -> Result<SparseCoo> {
+    let bad = |reason: String| SkaniError::BadMatrix {
+        file: file_name.to_string(),
+        reason,
+    };
+    let mut bytes = vec![];
+    BufReader::new(File::open(file_name)?).read_to_end(&mut bytes)?;
+    if bytes.len() < SPARSE_COO_HEADER_SIZE || &bytes[0..8] != SPARSE_COO_MAGIC {
+        return Err(bad("no sparse matrix header found".to_string()));
+    }
+    let version = u32::from_le_bytes(bytes[8..12].try_into().unwrap());
+    if version != SPARSE_COO_VERSION {
+        return Err(bad(format!(
+            "format version {} is not supported; expected {}",
+            version, SPARSE_COO_VERSION
+        )));
+    }
+    let n = u64::from_le_bytes(bytes[12..20].try_into().unwrap()) as usize;
+    let read_f32 = |x: &[u8]| f32::from_le_bytes(x.try_into().unwrap());
+    let read_u32 = |x: &[u8]| u32::from_le_bytes(x.try_into().unwrap());
+    let mut pos = SPARSE_COO_HEADER_SIZE;
+    let mut names = vec![];
+    for _ in 0..n {
+        if bytes.len() < pos + 4 {
+            return Err(bad("name table is truncated".to_string()));
+        }
+        let len = read_u32(&bytes[pos..pos + 4]) as usize;
+        pos += 4;
+        if bytes.len() < pos + len {
+            return Err(bad("name table is truncated".to_string()));
+        }
+        match std::str::from_utf8(&bytes[pos..pos + len]) {
+            Ok(name) => names.push(name.to_string()),
+            Err(_) => return Err(bad(format!("name {} is not valid UTF-8", names.len()))),
+        }
+        pos += len;
+    }
+
+    let records = &bytes[pos..];
+    if records.len() % SPARSE_COO_RECORD_SIZE != 0 {
+        return Err(bad("file is truncated".to_string()));
+    }
+    let mut entries = Vec::with_capacity(records.len() / SPARSE_COO_RECORD_SIZE);
+    for record in records.chunks_exact(SPARSE_COO_RECORD_SIZE) {
+        let entry = SparseEntry {
+            i: read_u32(&record[0..4]),
+            j: read_u32(&record[4..8]),
+            ani: read_f32(&record[8..12]),
+            af_i: read_f32(&record[12..16]),
+            af_j: read_f32(&record[16..20]),
+        };
+        if entry.i as usize >= n || entry.j as usize >= n {
+            return Err(bad(format!(
+                "entry ({}, {}) is out of range for {} genomes",
+                entry.i, entry.j, n
+            )));
+        }
+        entries.push(entry);
+    }
+    Ok(SparseCoo { names, entries })
+}
+
+//Triangle results as a binary sparse matrix; see SparseCooWriter.
+pub fn write_sparse_coo(
+    anis: &FxHashMap<usize, FxHashMap<usize, AniEstResult>>,
+    sketches: &[Sketch],
+    file_name: &str,
+    use_contig_names: bool,
+) -> Result<()> {
+    let names = sketches
+        .iter()
+        .map(|x| {
+            if use_contig_names {
+                x.contigs[0].clone()
+            } else {
+                x.file_name.clone()
+            }
+        })
+        .collect::<Vec<String>>();
+    let mut entries = anis
+        .iter()
+        .flat_map(|(i, row)| {
+            row.iter().map(move |(j, ani_res)| SparseEntry {
+                i: *i as u32,
+                j: *j as u32,
+                ani: ani_res.ani,
+                af_i: ani_res.align_fraction_ref,
+                af_j: ani_res.align_fraction_query,
+            })
+        })
+        .filter(|x| !(x.ani == -1. || x.ani.is_nan()))
+        .collect::<Vec<SparseEntry>>();
+    entries.sort_by_key(|x| (x.i, x.j));
+    let mut writer = SparseCooWriter::create(file_name, &names)?;
+    writer.write_entries(&entries)
+}
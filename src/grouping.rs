@@ -0,0 +1,93 @@
+use crate::params::*;
+use fxhash::FxHashMap;
+use log::*;
+use regex::Regex;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+//Assigns the records of multi-genome fasta files (e.g. all MAGs of a sample
+//in one file) to genomes, either with a contig id -> genome id table or with
+//a regex on the record header.
+pub enum GenomeGrouping {
+    Map(FxHashMap<String, String>),
+    Regex(Regex),
+}
+
+//Two whitespace-separated columns, contig id and genome id, e.g. a DAS Tool
+//contig2bin table. Empty lines and lines starting with # are skipped.
+pub fn read_genome_map(file_name: &str) -> Result<FxHashMap<String, String>, String> {
+    let file = File::open(file_name).map_err(|e| format!("could not open {}: {}", file_name, e))?;
+    let mut map = FxHashMap::default();
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|e| format!("could not read {}: {}", file_name, e))?;
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields = line.split_whitespace().collect::<Vec<&str>>();
+        if fields.len() != 2 {
+            return Err(format!(
+                "line {} of {} does not have two columns (contig id and genome id)",
+                i + 1,
+                file_name
+            ));
+        }
+        if let Some(genome) = map.insert(fields[0].to_string(), fields[1].to_string()) {
+            if genome != fields[1] {
+                return Err(format!(
+                    "contig {} is in both genome {} and genome {} in {}",
+                    fields[0], genome, fields[1], file_name
+                ));
+            }
+        }
+    }
+    Ok(map)
+}
+
+impl GenomeGrouping {
+    //None if neither --genome-map nor --genome-regex was given.
+    pub fn from_params(command_params: &CommandParams) -> Option<GenomeGrouping> {
+        if !command_params.genome_map.is_empty() {
+            let map = read_genome_map(&command_params.genome_map).unwrap_or_else(|e| {
+                error!("{}. Exiting.", e);
+                std::process::exit(1)
+            });
+            info!("{} contigs in {} genomes read from {}.", map.len(), num_genomes(&map), command_params.genome_map);
+            Some(GenomeGrouping::Map(map))
+        } else if !command_params.genome_regex.is_empty() {
+            let regex = Regex::new(&command_params.genome_regex).unwrap_or_else(|e| {
+                error!("Invalid genome regex {}: {}. Exiting.", command_params.genome_regex, e);
+                std::process::exit(1)
+            });
+            Some(GenomeGrouping::Regex(regex))
+        } else {
+            None
+        }
+    }
+
+    //The map is keyed by contig id, the header up to the first whitespace. The
+    //regex is matched against the whole header; the genome id is the first
+    //capture group, or the whole match if the regex has no groups.
+    pub fn genome(&self, header: &str) -> Option<String> {
+        match self {
+            GenomeGrouping::Map(map) => {
+                let contig = header.split_whitespace().next().unwrap_or("");
+                map.get(contig).cloned()
+            }
+            GenomeGrouping::Regex(regex) => {
+                let captures = regex.captures(header)?;
+                captures
+                    .get(1)
+                    .or_else(|| captures.get(0))
+                    .map(|x| x.as_str().to_string())
+                    .filter(|x| !x.is_empty())
+            }
+        }
+    }
+}
+
+fn num_genomes(map: &FxHashMap<String, String>) -> usize {
+    let mut genomes = map.values().collect::<Vec<&String>>();
+    genomes.sort();
+    genomes.dedup();
+    genomes.len()
+}
@@ -0,0 +1,183 @@
+use crate::file_io;
+use crate::params::*;
+use crate::types::*;
+use fxhash::FxHashMap;
+use log::*;
+use rayon::prelude::*;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+
+//Summarize sketch folders, markers.bin files and .sketch files. Sketch files
+//without a markers.bin entry (and the reverse) are reported.
+pub fn info(command_params: CommandParams) {
+    let mut marker_files = vec![];
+    let mut sketch_files = vec![];
+    for input in command_params.ref_files.iter() {
+        if Path::new(input).is_dir() {
+            let paths = fs::read_dir(input).unwrap_or_else(|e| {
+                error!("Could not read folder {}: {}. Exiting.", input, e);
+                std::process::exit(1)
+            });
+            for path in paths {
+                let path = path.unwrap().path().to_str().unwrap().to_string();
+                add_input(path, &mut marker_files, &mut sketch_files);
+            }
+        } else {
+            add_input(input.clone(), &mut marker_files, &mut sketch_files);
+        }
+    }
+    if marker_files.len() > 1 {
+        error!("More than one markers.bin found; run `skani info` on one sketch folder at a time. Exiting.");
+        std::process::exit(1);
+    }
+    if marker_files.is_empty() && sketch_files.is_empty() {
+        error!("No markers.bin or .sketch files found. Exiting.");
+        std::process::exit(1);
+    }
+    sketch_files.sort();
+
+    //Orphans can only be found if the .sketch files were looked for.
+    let check_orphans = !sketch_files.is_empty()
+        || command_params.ref_files.iter().any(|x| Path::new(x).is_dir());
+    let db_info = get_db_info(marker_files.first(), &sketch_files, check_orphans);
+    if !db_info.sketch_files_without_markers.is_empty() {
+        warn!(
+            "{} .sketch files have no entry in markers.bin and will not be used by `skani search`.",
+            db_info.sketch_files_without_markers.len()
+        );
+    }
+    if !db_info.markers_without_sketch_files.is_empty() {
+        warn!(
+            "{} entries in markers.bin have no .sketch file. Use `skani sketch --remove` to remove them.",
+            db_info.markers_without_sketch_files.len()
+        );
+    }
+    file_io::write_db_info(&db_info, &command_params.out_file_name, command_params.output_format);
+}
+
+fn add_input(file: String, marker_files: &mut Vec<String>, sketch_files: &mut Vec<String>) {
+    if file.ends_with("markers.bin") {
+        marker_files.push(file);
+    } else if file.ends_with(".sketch") {
+        sketch_files.push(file);
+    } else {
+        debug!("{} is not a markers.bin or .sketch file; skipping.", file);
+    }
+}
+
+fn exit_on_error<T>(res: crate::error::Result<T>) -> T {
+    res.unwrap_or_else(|e| {
+        error!("{}. Exiting.", e);
+        std::process::exit(1)
+    })
+}
+
+pub fn get_db_info(
+    marker_file: Option<&String>,
+    sketch_files: &[String],
+    check_orphans: bool,
+) -> DbInfo {
+    let mut db_info = DbInfo::default();
+    let mut sketch_params = None;
+    let mut genomes: FxHashMap<(String, usize), GenomeInfo> = FxHashMap::default();
+
+    if let Some(marker_file) = marker_file {
+        let header = exit_on_error(file_io::read_sketch_header(marker_file));
+        db_info.skani_version = header
+            .map(|x| x.skani_version)
+            .unwrap_or_else(|| "unknown (no header)".to_string());
+        let (params, markers) = exit_on_error(file_io::read_marker_file(marker_file));
+        for marker in markers {
+            let genome_info = GenomeInfo {
+                file_name: marker.file_name.clone(),
+                contig_order: marker.contig_order,
+                sketch_file: None,
+                in_markers: true,
+                num_contigs: marker.contigs.len(),
+                total_sequence_length: marker.total_sequence_length,
+                num_markers: marker.marker_seeds.len(),
+                num_seeds: None,
+            };
+            genomes.insert((marker.file_name, marker.contig_order), genome_info);
+        }
+        sketch_params = Some(params);
+    }
+
+    let sketch_infos: Mutex<Vec<(SketchParams, GenomeInfo, String)>> = Mutex::new(vec![]);
+    sketch_files.par_iter().for_each(|sketch_file| {
+        let (params, sketch) = match file_io::read_sketch_file(sketch_file) {
+            Ok(res) => res,
+            Err(e) => {
+                error!("{}; skipping.", e);
+                return;
+            }
+        };
+        let header = file_io::read_sketch_header(sketch_file).ok().flatten();
+        let genome_info = GenomeInfo {
+            file_name: sketch.file_name.clone(),
+            contig_order: sketch.contig_order,
+            sketch_file: Some(sketch_file.clone()),
+            in_markers: false,
+            num_contigs: sketch.contigs.len(),
+            total_sequence_length: sketch.total_sequence_length,
+            num_markers: sketch.marker_seeds.len(),
+            num_seeds: sketch.kmer_seeds_k.as_ref().map(|x| x.len()),
+        };
+        let version = header
+            .map(|x| x.skani_version)
+            .unwrap_or_else(|| "unknown (no header)".to_string());
+        sketch_infos.lock().unwrap().push((params, genome_info, version));
+    });
+
+    for (params, genome_info, version) in sketch_infos.into_inner().unwrap() {
+        if let Some(sketch_params) = sketch_params.as_ref() {
+            if *sketch_params != params {
+                warn!(
+                    "{} has different sketch parameters (c = {}, m = {}, k = {}) than the rest of the database.",
+                    genome_info.sketch_file.as_ref().unwrap(),
+                    params.c,
+                    params.marker_c,
+                    params.k
+                );
+            }
+        } else {
+            sketch_params = Some(params);
+        }
+        if db_info.skani_version.is_empty() {
+            db_info.skani_version = version;
+        }
+        let key = (genome_info.file_name.clone(), genome_info.contig_order);
+        if let Some(existing) = genomes.get_mut(&key) {
+            existing.sketch_file = genome_info.sketch_file;
+            existing.num_seeds = genome_info.num_seeds;
+        } else {
+            genomes.insert(key, genome_info);
+        }
+    }
+
+    let sketch_params = sketch_params.unwrap_or_default();
+    db_info.k = sketch_params.k;
+    db_info.c = sketch_params.c;
+    db_info.marker_c = sketch_params.marker_c;
+    db_info.amino_acid = sketch_params.use_aa;
+
+    let mut genomes = genomes.into_values().collect::<Vec<GenomeInfo>>();
+    genomes.sort_by(|x, y| (&x.file_name, x.contig_order).cmp(&(&y.file_name, y.contig_order)));
+    for genome in genomes.iter() {
+        if marker_file.is_some() && !genome.in_markers {
+            db_info
+                .sketch_files_without_markers
+                .push(genome.sketch_file.clone().unwrap());
+        }
+        if check_orphans && genome.sketch_file.is_none() {
+            db_info
+                .markers_without_sketch_files
+                .push(genome.file_name.clone());
+        }
+    }
+    db_info.num_genomes = genomes.len();
+    db_info.total_sequence_length = genomes.iter().map(|x| x.total_sequence_length).sum();
+    db_info.genomes = genomes;
+    db_info
+}
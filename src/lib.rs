@@ -5,12 +5,24 @@ pub mod file_io;
 pub mod seeding;
 pub mod screen;
 pub mod search;
+pub mod serve;
 pub mod sketch;
 pub mod dist;
 pub mod triangle;
+pub mod core_genome;
+pub mod cluster;
+pub mod derep;
+pub mod tree;
+pub mod merge;
+pub mod checkpoint;
+pub mod grouping;
 pub mod cmd_line;
 pub mod model;
 pub mod regression;
+pub mod error;
+pub mod engine;
+pub mod info;
+pub mod db;
 
 #[cfg(target_arch = "x86_64")]
 pub mod avx2_seeding;
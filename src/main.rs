@@ -1,10 +1,16 @@
 use clap::{AppSettings, Arg, ArgGroup, Command, SubCommand};
 use std::env;
+use skani::cluster;
+use skani::core_genome;
+use skani::derep;
 use skani::dist;
+use skani::info;
+use skani::merge;
 use skani::cmd_line::*;
 use skani::params;
 use skani::parse;
 use skani::search;
+use skani::serve;
 use skani::sketch;
 use skani::triangle;
 
@@ -53,9 +59,48 @@ fn main() {
                 .arg(
                     Arg::new("individual contig")
                         .short('i')
-                        .help("Use individual sequences instead the entire file for multi-fastas. CURRENTLY DOES NOT WORK WITH `skani search`.")
+                        .help("Use individual sequences instead the entire file for multi-fastas.")
+                )
+                .arg(
+                    Arg::new(GENOME_MAP)
+                        .long(CMD_GENOME_MAP)
+                        .help(H_GENOME_MAP)
+                        .takes_value(true)
+                        .conflicts_with_all(&[GENOME_REGEX, "individual contig"])
+                )
+                .arg(
+                    Arg::new(GENOME_REGEX)
+                        .long(CMD_GENOME_REGEX)
+                        .help(H_GENOME_REGEX)
+                        .takes_value(true)
+                        .conflicts_with_all(&["individual contig"])
                 )
                 .arg(Arg::new("output sketch folder").short('o').help("Output folder where sketch files are placed. Creates a folder if it does not exist, and overwrites the contents in folder if it does.").takes_value(true).required(true).display_order(1))
+                .arg(
+                    Arg::new(APPEND)
+                        .long(CMD_APPEND)
+                        .help(H_APPEND)
+                        .display_order(2),
+                )
+                .arg(
+                    Arg::new(REMOVE)
+                        .long(CMD_REMOVE)
+                        .help(H_REMOVE)
+                        .conflicts_with(APPEND)
+                        .display_order(3),
+                )
+                .arg(
+                    Arg::new(PACKED)
+                        .long(CMD_PACKED)
+                        .help(H_PACKED)
+                        .display_order(4),
+                )
+                .arg(
+                    Arg::new(SAVE_MARKER_INDEX)
+                        .long(CMD_SAVE_MARKER_INDEX)
+                        .help(H_SAVE_MARKER_INDEX)
+                        .display_order(5),
+                )
                 .help_heading("PRESETS")
                 .arg(
                     Arg::new(MODE_SLOW)
@@ -137,7 +182,7 @@ fn main() {
                 .arg(
                     Arg::new("query")
                         .index(1)
-                        .help("Query fasta or sketch.")
+                        .help("Query fasta or sketch. Use - for fasta/fastq from stdin.")
                         .takes_value(true)
                 )
                 .arg(
@@ -150,7 +195,7 @@ fn main() {
                 .arg(
                     Arg::new("queries")
                         .short('q')
-                        .help("Query fasta(s) or sketch(es)")
+                        .help("Query fasta(s) or sketch(es). Use - for fasta/fastq from stdin.")
                         .takes_value(true)
                         .multiple(true)
                 )
@@ -183,6 +228,20 @@ fn main() {
                         .long(CMD_IND_CTG_REF)
                         .help(H_IND_CTG_REF)
                 )
+                .arg(
+                    Arg::new(GENOME_MAP)
+                        .long(CMD_GENOME_MAP)
+                        .help(H_GENOME_MAP)
+                        .takes_value(true)
+                        .conflicts_with_all(&[GENOME_REGEX, IND_CTG_QRY, IND_CTG_REF])
+                )
+                .arg(
+                    Arg::new(GENOME_REGEX)
+                        .long(CMD_GENOME_REGEX)
+                        .help(H_GENOME_REGEX)
+                        .takes_value(true)
+                        .conflicts_with_all(&[IND_CTG_QRY, IND_CTG_REF])
+                )
                 .help_heading("OUTPUT")
                 .arg(
                     Arg::new("output")
@@ -216,6 +275,38 @@ fn main() {
                         .help(H_DETAIL_OUT)
                         .takes_value(false)
                 )
+                .arg(
+                    Arg::new(OUTPUT_FORMAT)
+                        .long(CMD_OUTPUT_FORMAT)
+                        .help(H_OUTPUT_FORMAT)
+                        .takes_value(true)
+                        .possible_values(["tsv", "json", "jsonl"])
+                )
+                .arg(
+                    Arg::new(CHAINS)
+                        .long(CMD_CHAINS)
+                        .help(H_CHAINS)
+                        .takes_value(true)
+                )
+                .arg(
+                    Arg::new(FRAGMENT_ANI)
+                        .long(CMD_FRAGMENT_ANI)
+                        .help(H_FRAGMENT_ANI)
+                        .takes_value(true)
+                )
+                .arg(
+                    Arg::new(ALIGNED_BED)
+                        .long(CMD_ALIGNED_BED)
+                        .help(H_ALIGNED_BED)
+                        .takes_value(true)
+                )
+                .arg(
+                    Arg::new(RESUME)
+                        .long(CMD_RESUME)
+                        .help(H_RESUME)
+                        .requires("output")
+                        .conflicts_with_all(&[CHAINS, FRAGMENT_ANI, ALIGNED_BED])
+                )
                 .help_heading("PRESETS")
                 .arg(
                     Arg::new(MODE_SLOW)
@@ -338,6 +429,20 @@ fn main() {
                         .short('i')
                         .help("Use individual sequences instead the entire file for multi-fastas.")
                 )
+                .arg(
+                    Arg::new(GENOME_MAP)
+                        .long(CMD_GENOME_MAP)
+                        .help(H_GENOME_MAP)
+                        .takes_value(true)
+                        .conflicts_with_all(&[GENOME_REGEX, "individual contig", STREAM])
+                )
+                .arg(
+                    Arg::new(GENOME_REGEX)
+                        .long(CMD_GENOME_REGEX)
+                        .help(H_GENOME_REGEX)
+                        .takes_value(true)
+                        .conflicts_with_all(&["individual contig", STREAM])
+                )
                 .help_heading("OUTPUT")
                 .arg(
                     Arg::new("output")
@@ -369,12 +474,76 @@ fn main() {
                         .help(H_DETAIL_OUT)
                         .takes_value(false)
                 )
+                .arg(
+                    Arg::new(OUTPUT_FORMAT)
+                        .long(CMD_OUTPUT_FORMAT)
+                        .help(H_OUTPUT_FORMAT)
+                        .takes_value(true)
+                        .possible_values(["tsv", "json", "jsonl"])
+                )
                 .arg(
                     Arg::new(DISTANCE_OUT)
                         .long(CMD_DISTANCE_OUT)
                         .help(H_DISTANCE_OUT)
                         .takes_value(false)
                 )
+                .arg(
+                    Arg::new(TREE)
+                        .long(CMD_TREE)
+                        .help(H_TREE)
+                        .takes_value(true)
+                )
+                .arg(
+                    Arg::new(TREE_METHOD)
+                        .long(CMD_TREE_METHOD)
+                        .help(H_TREE_METHOD)
+                        .takes_value(true)
+                        .possible_values(["upgma", "nj"])
+                        .requires(TREE)
+                )
+                .arg(
+                    Arg::new(TREE_FILL)
+                        .long(CMD_TREE_FILL)
+                        .help(H_TREE_FILL)
+                        .takes_value(true)
+                        .requires(TREE)
+                )
+                .arg(
+                    Arg::new(STREAM)
+                        .long(CMD_STREAM)
+                        .help(H_STREAM)
+                        .requires("output")
+                        .conflicts_with_all(&[TREE, "sparse", FULL_MAT, DISTANCE_OUT, OUTPUT_FORMAT, CONF_INTERVAL, DETAIL_OUT, "individual contig"])
+                )
+                .arg(
+                    Arg::new(BLOCK_SIZE)
+                        .long(CMD_BLOCK_SIZE)
+                        .help(H_BLOCK_SIZE)
+                        .takes_value(true)
+                        .requires(STREAM)
+                )
+                .arg(
+                    Arg::new(BINARY)
+                        .long(CMD_BINARY)
+                        .help(H_BINARY)
+                        .requires("output")
+                        .conflicts_with_all(&[STREAM, "sparse", FULL_MAT, DISTANCE_OUT, OUTPUT_FORMAT, CONF_INTERVAL, DETAIL_OUT])
+                )
+                .arg(
+                    Arg::new(SHARD)
+                        .long(CMD_SHARD)
+                        .help(H_SHARD)
+                        .takes_value(true)
+                        .requires("output")
+                        .conflicts_with_all(&[STREAM, BINARY, TREE, "sparse", FULL_MAT, DISTANCE_OUT, OUTPUT_FORMAT, CONF_INTERVAL, DETAIL_OUT])
+                )
+                .arg(
+                    Arg::new(RESUME)
+                        .long(CMD_RESUME)
+                        .help(H_RESUME_TRI)
+                        .requires_all(&["output", "sparse"])
+                        .conflicts_with_all(&[STREAM, SHARD, TREE])
+                )
                 .arg(
                     Arg::new("sparse")
                         .long("sparse")
@@ -455,8 +624,8 @@ fn main() {
                 .arg(Arg::new("trace").long("trace").help("Trace level verbosity."))
         )
         .subcommand(
-            SubCommand::with_name(params::SEARCH_STRING)
-            .about("Search queries against a large pre-sketched database of reference genomes in a memory efficient manner.\nUsage: skani search -d sketch_folder query1.fa query2.fa ... ")
+            SubCommand::with_name(params::CORE_STRING)
+            .about("Chain genomes against a reference and output a window presence/absence matrix and per-genome core fractions.\nUsage: skani core genome1.fa genome2.fa genome3.fa ... -o prefix")
                 .arg(
                     Arg::new("t")
                         .short('t')
@@ -466,56 +635,51 @@ fn main() {
                 )
                 .help_heading("INPUTS")
                 .arg(
-                    Arg::new("sketched database folder")
-                        .short('d')
-                        .help("Output folder from `skani sketch`.")
-                        .takes_value(true)
-                        .required(true)
+                    Arg::new("fasta_list")
+                        .short('l')
+                        .help("File with each line containing one fasta/sketch file.")
+                        .takes_value(true),
                 )
                 .arg(
-                    Arg::new("query")
-                        .index(1)
-                        .help("Query fasta(s) or sketch(es).")
-                        .multiple(true)
-                        .takes_value(true),
+                    Arg::new("aai")
+                        .short('a')
+                        .long("aai")
+                        .hidden(true)
+                        .help("Use amino acid to calculate AAI instead.\t[default: ANI]"),
                 )
                 .arg(
-                    Arg::new("queries")
-                        .short('q')
-                        .help("Query fasta(s) or sketch(es)")
+                    Arg::new("fasta_files")
+                        .index(1)
+                        .help("Fasta(s) or sketch(es).")
                         .takes_value(true)
                         .multiple(true),
                 )
                 .arg(
-                    Arg::new("query list file")
-                        .long("ql")
-                        .help("File with each line containing one fasta/sketch file.")
+                    Arg::new(CORE_REF)
+                        .short(CMD_CORE_REF)
+                        .help(H_CORE_REF)
                         .takes_value(true),
                 )
-                .arg(
-                    Arg::new(IND_CTG_QRY)
-                        .long(CMD_IND_CTG_QRY)
-                        .help(H_IND_CTG_QRY)
-                )
                 .help_heading("OUTPUT")
                 .arg(
                     Arg::new("output")
                         .short('o')
-                        .help("Output file name; rewrites file by default\t[default: output to stdout].")
+                        .help("Output prefix; writes PREFIX.presence_absence.tsv and PREFIX.core_fraction.tsv.")
                         .takes_value(true)
+                        .required(true)
                         .display_order(1)
                 )
                 .arg(
-                    Arg::new(CONF_INTERVAL)
-                        .long(CMD_CONF_INTERVAL)
-                        .help(H_CONF_INTERVAL)
-                        .takes_value(false)
+                    Arg::new(CORE_WINDOW)
+                        .long(CMD_CORE_WINDOW)
+                        .help(H_CORE_WINDOW)
+                        .takes_value(true)
                 )
                 .arg(
-                    Arg::new(DETAIL_OUT)
-                        .long(CMD_DETAIL_OUT)
-                        .help(H_DETAIL_OUT)
-                        .takes_value(false)
+                    Arg::new(CORE_THRESHOLD)
+                        .long(CMD_CORE_THRESHOLD)
+                        .help(H_CORE_THRESHOLD)
+                        .takes_value(true)
                 )
                 .arg(
                     Arg::new(MIN_ALIGN_FRAC)
@@ -523,18 +687,24 @@ fn main() {
                         .help(H_MIN_ALIGN_FRAC)
                         .takes_value(true)
                 )
+                .help_heading("PRESETS")
                 .arg(
-                    Arg::new("n")
-                        .short('n')
-                        .help("Max number of results to show for each query.\t[default: unlimited]")
-                        .takes_value(true)
+                    Arg::new(MODE_SLOW)
+                        .long(CMD_MODE_SLOW)
+                        .help(H_MODE_SLOW)
+                        .takes_value(false),
                 )
-                .group(
-                    ArgGroup::new("q")
-                        .arg("query")
-                        .arg("queries")
-                        .arg("query list file")
-                        .required(true),
+                .arg(
+                    Arg::new(MODE_MEDIUM)
+                        .long(CMD_MODE_MEDIUM)
+                        .help(H_MODE_MEDIUM)
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::new(MODE_FAST)
+                        .long(CMD_MODE_FAST)
+                        .help(H_MODE_FAST)
+                        .takes_value(false),
                 )
                 .help_heading("ALGORITHM PARAMETERS")
                 .arg(
@@ -550,16 +720,30 @@ fn main() {
                     .takes_value(false)
                 )
                 .arg(
-                    Arg::new(KEEP_REFS)
-                        .long(CMD_KEEP_REFS)
-                        .help(H_KEEP_REFS),
+                    Arg::new(MARKER_C)
+                        .short(CMD_MARKER_C)
+                        .help(H_MARKER_C)
+                        .takes_value(true),
                 )
                 .arg(
-                    Arg::new(NO_FULL_INDEX)
-                        .long(CMD_NO_FULL_INDEX)
-                        .help(H_NO_FULL_INDEX),
+                    Arg::new("k")
+                        .short('k')
+                        .help("k-mer size.\t[default: 15]")
+                        .takes_value(true)
+                        .hidden(true)
+                )
+                .arg(
+                    Arg::new("c")
+                        .short('c')
+                        .help(H_C_FACTOR)
+                        .takes_value(true),
+                )
+                .group(
+                    ArgGroup::new("ref")
+                        .arg("fasta_files")
+                        .arg("fasta_list")
+                        .required(true),
                 )
-                .arg(Arg::new("s").short('s').takes_value(true).help(H_SCREEN))
                 .arg(
                     Arg::new(ROBUST)
                         .long(CMD_ROBUST)
@@ -573,7 +757,637 @@ fn main() {
                 .help_heading("MISC")
                 .arg(Arg::new("v").short('v').help("Debug level verbosity."))
                 .arg(Arg::new("trace").long("trace").help("Trace level verbosity."))
-
+        )
+        .subcommand(
+            SubCommand::with_name(params::CLUSTER_STRING)
+            .about("Cluster genomes at ANI and aligned fraction thresholds and output cluster membership and representatives.\nUsage: skani cluster genome1.fa genome2.fa genome3.fa ... > clusters.tsv\nor:    skani cluster matrix.bin > clusters.tsv (from skani triangle --binary or --stream)")
+                .arg(
+                    Arg::new("t")
+                        .short('t')
+                        .default_value("3")
+                        .help("Number of threads.")
+                        .takes_value(true),
+                )
+                .help_heading("INPUTS")
+                .arg(
+                    Arg::new("fasta_list")
+                        .short('l')
+                        .help("File with each line containing one fasta/sketch file.")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("aai")
+                        .short('a')
+                        .long("aai")
+                        .hidden(true)
+                        .help("Use amino acid to calculate AAI instead.\t[default: ANI]"),
+                )
+                .arg(
+                    Arg::new("fasta_files")
+                        .index(1)
+                        .help("Fasta(s) or sketch(es), or one binary matrix from skani triangle --binary or --stream.")
+                        .takes_value(true)
+                        .multiple(true),
+                )
+                .help_heading("OUTPUT")
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .help("Output file name; rewrites file by default\t[default: output to stdout]")
+                        .takes_value(true)
+                        .display_order(1)
+                )
+                .help_heading("CLUSTERING")
+                .arg(
+                    Arg::new(CLUSTER_METHOD)
+                        .long(CMD_CLUSTER_METHOD)
+                        .help(H_CLUSTER_METHOD)
+                        .takes_value(true)
+                        .possible_values(["single", "complete", "greedy"])
+                )
+                .arg(
+                    Arg::new(CLUSTER_ANI)
+                        .long(CMD_CLUSTER_ANI)
+                        .help(H_CLUSTER_ANI)
+                        .takes_value(true)
+                )
+                .arg(
+                    Arg::new(MIN_ALIGN_FRAC)
+                        .long(CMD_MIN_ALIGN_FRAC)
+                        .help(H_CLUSTER_AF)
+                        .takes_value(true)
+                )
+                .help_heading("PRESETS")
+                .arg(
+                    Arg::new(MODE_SLOW)
+                        .long(CMD_MODE_SLOW)
+                        .help(H_MODE_SLOW)
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::new(MODE_MEDIUM)
+                        .long(CMD_MODE_MEDIUM)
+                        .help(H_MODE_MEDIUM)
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::new(MODE_FAST)
+                        .long(CMD_MODE_FAST)
+                        .help(H_MODE_FAST)
+                        .takes_value(false),
+                )
+                .help_heading("ALGORITHM PARAMETERS")
+                .arg(
+                    Arg::new(LEARNED_ANI)
+                    .long(CMD_LEARNED_ANI)
+                    .help(H_LEARNED_ANI)
+                    .takes_value(false)
+                )
+                .arg(
+                    Arg::new(NO_LEARNED_ANI)
+                    .long(CMD_NO_LEARNED_ANI)
+                    .help(H_NO_LEARNED_ANI)
+                    .takes_value(false)
+                )
+                .arg(
+                    Arg::new(MARKER_C)
+                        .short(CMD_MARKER_C)
+                        .help(H_MARKER_C)
+                        .takes_value(true),
+                )
+                .arg(Arg::new("s").short('s').takes_value(true).help(H_CLUSTER_SCREEN))
+                .arg(
+                    Arg::new("k")
+                        .short('k')
+                        .help("k-mer size.\t[default: 15]")
+                        .takes_value(true)
+                        .hidden(true)
+                )
+                .arg(
+                    Arg::new("c")
+                        .short('c')
+                        .help(H_C_FACTOR)
+                        .takes_value(true),
+                )
+                .group(
+                    ArgGroup::new("ref")
+                        .arg("fasta_files")
+                        .arg("fasta_list")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new(ROBUST)
+                        .long(CMD_ROBUST)
+                        .help(H_ROBUST),
+                )
+                .arg(
+                    Arg::new("median")
+                        .long("median")
+                        .help("Estimate median identity instead of average (mean) identity."),
+                )
+                .help_heading("MISC")
+                .arg(Arg::new("v").short('v').help("Debug level verbosity."))
+                .arg(Arg::new("trace").long("trace").help("Trace level verbosity."))
+        )
+        .subcommand(
+            SubCommand::with_name(params::DEREP_STRING)
+            .about("Pick representative genomes so that every genome is within the ANI/AF thresholds of one, preferring higher quality genomes.\nUsage: skani derep genome1.fa genome2.fa ... --quality checkm.tsv -o prefix")
+                .arg(
+                    Arg::new("t")
+                        .short('t')
+                        .default_value("3")
+                        .help("Number of threads.")
+                        .takes_value(true),
+                )
+                .help_heading("INPUTS")
+                .arg(
+                    Arg::new("fasta_list")
+                        .short('l')
+                        .help("File with each line containing one fasta/sketch file.")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("aai")
+                        .short('a')
+                        .long("aai")
+                        .hidden(true)
+                        .help("Use amino acid to calculate AAI instead.\t[default: ANI]"),
+                )
+                .arg(
+                    Arg::new("fasta_files")
+                        .index(1)
+                        .help("Fasta(s) or sketch(es).")
+                        .takes_value(true)
+                        .multiple(true),
+                )
+                .arg(
+                    Arg::new(QUALITY_FILE)
+                        .long(CMD_QUALITY_FILE)
+                        .help(H_QUALITY_FILE)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new(QUALITY_COLUMN)
+                        .long(CMD_QUALITY_COLUMN)
+                        .help(H_QUALITY_COLUMN)
+                        .takes_value(true)
+                        .requires(QUALITY_FILE),
+                )
+                .help_heading("OUTPUT")
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .help("Output prefix; writes PREFIX.representatives.txt and PREFIX.clusters.tsv.")
+                        .takes_value(true)
+                        .required(true)
+                        .display_order(1)
+                )
+                .arg(
+                    Arg::new(DEREP_COPY)
+                        .long(CMD_DEREP_COPY)
+                        .help(H_DEREP_COPY)
+                        .takes_value(true)
+                )
+                .arg(
+                    Arg::new(DEREP_SYMLINK)
+                        .long(CMD_DEREP_SYMLINK)
+                        .help(H_DEREP_SYMLINK)
+                        .takes_value(true)
+                        .conflicts_with(DEREP_COPY)
+                )
+                .help_heading("THRESHOLDS")
+                .arg(
+                    Arg::new(CLUSTER_ANI)
+                        .long(CMD_CLUSTER_ANI)
+                        .help(H_CLUSTER_ANI)
+                        .takes_value(true)
+                )
+                .arg(
+                    Arg::new(MIN_ALIGN_FRAC)
+                        .long(CMD_MIN_ALIGN_FRAC)
+                        .help(H_CLUSTER_AF)
+                        .takes_value(true)
+                )
+                .help_heading("PRESETS")
+                .arg(
+                    Arg::new(MODE_SLOW)
+                        .long(CMD_MODE_SLOW)
+                        .help(H_MODE_SLOW)
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::new(MODE_MEDIUM)
+                        .long(CMD_MODE_MEDIUM)
+                        .help(H_MODE_MEDIUM)
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::new(MODE_FAST)
+                        .long(CMD_MODE_FAST)
+                        .help(H_MODE_FAST)
+                        .takes_value(false),
+                )
+                .help_heading("ALGORITHM PARAMETERS")
+                .arg(
+                    Arg::new(LEARNED_ANI)
+                    .long(CMD_LEARNED_ANI)
+                    .help(H_LEARNED_ANI)
+                    .takes_value(false)
+                )
+                .arg(
+                    Arg::new(NO_LEARNED_ANI)
+                    .long(CMD_NO_LEARNED_ANI)
+                    .help(H_NO_LEARNED_ANI)
+                    .takes_value(false)
+                )
+                .arg(
+                    Arg::new(MARKER_C)
+                        .short(CMD_MARKER_C)
+                        .help(H_MARKER_C)
+                        .takes_value(true),
+                )
+                .arg(Arg::new("s").short('s').takes_value(true).help(H_CLUSTER_SCREEN))
+                .arg(
+                    Arg::new("k")
+                        .short('k')
+                        .help("k-mer size.\t[default: 15]")
+                        .takes_value(true)
+                        .hidden(true)
+                )
+                .arg(
+                    Arg::new("c")
+                        .short('c')
+                        .help(H_C_FACTOR)
+                        .takes_value(true),
+                )
+                .group(
+                    ArgGroup::new("ref")
+                        .arg("fasta_files")
+                        .arg("fasta_list")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new(ROBUST)
+                        .long(CMD_ROBUST)
+                        .help(H_ROBUST),
+                )
+                .arg(
+                    Arg::new("median")
+                        .long("median")
+                        .help("Estimate median identity instead of average (mean) identity."),
+                )
+                .help_heading("MISC")
+                .arg(Arg::new("v").short('v').help("Debug level verbosity."))
+                .arg(Arg::new("trace").long("trace").help("Trace level verbosity."))
+        )
+        .subcommand(
+            SubCommand::with_name(params::SEARCH_STRING)
+            .about("Search queries against a large pre-sketched database of reference genomes in a memory efficient manner.\nUsage: skani search -d sketch_folder query1.fa query2.fa ... ")
+                .arg(
+                    Arg::new("t")
+                        .short('t')
+                        .default_value("3")
+                        .help("Number of threads.")
+                        .takes_value(true),
+                )
+                .help_heading("INPUTS")
+                .arg(
+                    Arg::new("sketched database folder")
+                        .short('d')
+                        .help("Output folder from `skani sketch`.")
+                        .takes_value(true)
+                        .required(true)
+                )
+                .arg(
+                    Arg::new("query")
+                        .index(1)
+                        .help("Query fasta(s) or sketch(es). Use - for fasta/fastq from stdin.")
+                        .multiple(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("queries")
+                        .short('q')
+                        .help("Query fasta(s) or sketch(es). Use - for fasta/fastq from stdin.")
+                        .takes_value(true)
+                        .multiple(true),
+                )
+                .arg(
+                    Arg::new("query list file")
+                        .long("ql")
+                        .help("File with each line containing one fasta/sketch file.")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new(IND_CTG_QRY)
+                        .long(CMD_IND_CTG_QRY)
+                        .help(H_IND_CTG_QRY)
+                )
+                .arg(
+                    Arg::new(GENOME_MAP)
+                        .long(CMD_GENOME_MAP)
+                        .help(H_GENOME_MAP)
+                        .takes_value(true)
+                        .conflicts_with_all(&[GENOME_REGEX, IND_CTG_QRY])
+                )
+                .arg(
+                    Arg::new(GENOME_REGEX)
+                        .long(CMD_GENOME_REGEX)
+                        .help(H_GENOME_REGEX)
+                        .takes_value(true)
+                        .conflicts_with_all(&[IND_CTG_QRY])
+                )
+                .help_heading("OUTPUT")
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .help("Output file name; rewrites file by default\t[default: output to stdout].")
+                        .takes_value(true)
+                        .display_order(1)
+                )
+                .arg(
+                    Arg::new(CONF_INTERVAL)
+                        .long(CMD_CONF_INTERVAL)
+                        .help(H_CONF_INTERVAL)
+                        .takes_value(false)
+                )
+                .arg(
+                    Arg::new(DETAIL_OUT)
+                        .long(CMD_DETAIL_OUT)
+                        .help(H_DETAIL_OUT)
+                        .takes_value(false)
+                )
+                .arg(
+                    Arg::new(OUTPUT_FORMAT)
+                        .long(CMD_OUTPUT_FORMAT)
+                        .help(H_OUTPUT_FORMAT)
+                        .takes_value(true)
+                        .possible_values(["tsv", "json", "jsonl"])
+                )
+                .arg(
+                    Arg::new(RESUME)
+                        .long(CMD_RESUME)
+                        .help(H_RESUME)
+                        .requires("output")
+                )
+                .arg(
+                    Arg::new(MIN_ALIGN_FRAC)
+                        .long(CMD_MIN_ALIGN_FRAC)
+                        .help(H_MIN_ALIGN_FRAC)
+                        .takes_value(true)
+                )
+                .arg(
+                    Arg::new("n")
+                        .short('n')
+                        .help("Max number of results to show for each query.\t[default: unlimited]")
+                        .takes_value(true)
+                )
+                .group(
+                    ArgGroup::new("q")
+                        .arg("query")
+                        .arg("queries")
+                        .arg("query list file")
+                        .required(true),
+                )
+                .help_heading("ALGORITHM PARAMETERS")
+                .arg(
+                    Arg::new(LEARNED_ANI)
+                    .long(CMD_LEARNED_ANI)
+                    .help(H_LEARNED_ANI)
+                    .takes_value(false)
+                )
+                .arg(
+                    Arg::new(NO_LEARNED_ANI)
+                    .long(CMD_NO_LEARNED_ANI)
+                    .help(H_NO_LEARNED_ANI)
+                    .takes_value(false)
+                )
+                .arg(
+                    Arg::new(KEEP_REFS)
+                        .long(CMD_KEEP_REFS)
+                        .help(H_KEEP_REFS),
+                )
+                .arg(
+                    Arg::new(NO_FULL_INDEX)
+                        .long(CMD_NO_FULL_INDEX)
+                        .help(H_NO_FULL_INDEX),
+                )
+                .arg(Arg::new("s").short('s').takes_value(true).help(H_SCREEN))
+                .arg(
+                    Arg::new(ROBUST)
+                        .long(CMD_ROBUST)
+                        .help(H_ROBUST),
+                )
+                .arg(
+                    Arg::new("median")
+                        .long("median")
+                        .help("Estimate median identity instead of average (mean) identity."),
+                )
+                .help_heading("MISC")
+                .arg(Arg::new("v").short('v').help("Debug level verbosity."))
+                .arg(Arg::new("trace").long("trace").help("Trace level verbosity."))
+
+        )
+        .subcommand(
+            SubCommand::with_name(params::INFO_STRING)
+            .about("Summarize a sketch folder or sketch files: sketch parameters, genomes, and .sketch files missing from markers.bin (or the reverse).\nUsage: skani info sketch_folder")
+                .arg(
+                    Arg::new("t")
+                        .short('t')
+                        .default_value("3")
+                        .help("Number of threads.")
+                        .takes_value(true),
+                )
+                .help_heading("INPUT/OUTPUT")
+                .arg(
+                    Arg::new("inputs")
+                        .index(1)
+                        .help("Sketch folder(s) generated by `skani sketch`, markers.bin, or .sketch files.")
+                        .takes_value(true)
+                        .multiple(true)
+                        .required(true)
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .help("Output file name; rewrites file by default\t[default: output to stdout]")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new(JSON_OUT)
+                        .long(CMD_JSON_OUT)
+                        .help(H_JSON_OUT),
+                )
+                .help_heading("MISC")
+                .arg(Arg::new("v").short('v').help("Debug level verbosity."))
+                .arg(Arg::new("trace").long("trace").help("Trace level verbosity."))
+        )
+        .subcommand(
+            SubCommand::with_name(params::MERGE_STRING)
+            .about("Combine the shard files of `skani triangle --shard` into a lower triangular ANI/AF matrix.\nUsage: skani merge shard_0 shard_1 ... -o skani_ani_matrix.txt")
+                .arg(
+                    Arg::new("t")
+                        .short('t')
+                        .default_value("3")
+                        .help("Number of threads.")
+                        .takes_value(true),
+                )
+                .help_heading("INPUTS")
+                .arg(
+                    Arg::new("inputs")
+                        .index(1)
+                        .help("Shard files written by `skani triangle --shard I/N -o`, one for each I.")
+                        .takes_value(true)
+                        .multiple(true)
+                        .required(true)
+                )
+                .help_heading("OUTPUT")
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .help("Output file name; rewrites file by default\t[default: output to stdout]")
+                        .takes_value(true)
+                        .display_order(1)
+                )
+                .arg(
+                    Arg::new(FULL_MAT)
+                        .long(CMD_FULL_MAT)
+                        .help(H_FULL_MAT)
+                )
+                .arg(
+                    Arg::new(CONF_INTERVAL)
+                        .long(CMD_CONF_INTERVAL)
+                        .help(H_CONF_INTERVAL_TRI)
+                        .takes_value(false)
+                )
+                .arg(
+                    Arg::new(DETAIL_OUT)
+                        .long(CMD_DETAIL_OUT)
+                        .help(H_DETAIL_OUT)
+                        .takes_value(false)
+                )
+                .arg(
+                    Arg::new(OUTPUT_FORMAT)
+                        .long(CMD_OUTPUT_FORMAT)
+                        .help(H_OUTPUT_FORMAT)
+                        .takes_value(true)
+                        .possible_values(["tsv", "json", "jsonl"])
+                )
+                .arg(
+                    Arg::new(DISTANCE_OUT)
+                        .long(CMD_DISTANCE_OUT)
+                        .help(H_DISTANCE_OUT)
+                        .takes_value(false)
+                )
+                .arg(
+                    Arg::new(BINARY)
+                        .long(CMD_BINARY)
+                        .help(H_BINARY)
+                        .requires("output")
+                        .conflicts_with_all(&["sparse", FULL_MAT, DISTANCE_OUT, OUTPUT_FORMAT, CONF_INTERVAL, DETAIL_OUT])
+                )
+                .arg(
+                    Arg::new("sparse")
+                        .long("sparse")
+                        .short('E')
+                        .help("Output comparisons in a row-by-row form (i.e. sparse matrix) in the same form as `skani dist`."),
+                )
+                .help_heading("MISC")
+                .arg(Arg::new("v").short('v').help("Debug level verbosity."))
+                .arg(Arg::new("trace").long("trace").help("Trace level verbosity."))
+        )
+        .subcommand(
+            SubCommand::with_name(params::SERVE_STRING)
+            .about("Load a sketched database once and answer search queries over a Unix domain socket.\nUsage: skani serve -d sketch_folder --socket skani.sock")
+                .arg(
+                    Arg::new("t")
+                        .short('t')
+                        .default_value("3")
+                        .help("Number of threads.")
+                        .takes_value(true),
+                )
+                .help_heading("INPUTS")
+                .arg(
+                    Arg::new("sketched database folder")
+                        .short('d')
+                        .help("Output folder from `skani sketch`.")
+                        .takes_value(true)
+                        .required(true)
+                )
+                .arg(
+                    Arg::new(SOCKET)
+                        .long(CMD_SOCKET)
+                        .help(H_SOCKET)
+                        .takes_value(true)
+                        .required(true)
+                )
+                .arg(
+                    Arg::new(IND_CTG_QRY)
+                        .long(CMD_IND_CTG_QRY)
+                        .help(H_IND_CTG_QRY)
+                )
+                .help_heading("OUTPUT")
+                .arg(
+                    Arg::new(CONF_INTERVAL)
+                        .long(CMD_CONF_INTERVAL)
+                        .help(H_CONF_INTERVAL)
+                        .takes_value(false)
+                )
+                .arg(
+                    Arg::new(DETAIL_OUT)
+                        .long(CMD_DETAIL_OUT)
+                        .help(H_DETAIL_OUT)
+                        .takes_value(false)
+                )
+                .arg(
+                    Arg::new(MIN_ALIGN_FRAC)
+                        .long(CMD_MIN_ALIGN_FRAC)
+                        .help(H_MIN_ALIGN_FRAC)
+                        .takes_value(true)
+                )
+                .arg(
+                    Arg::new("n")
+                        .short('n')
+                        .help("Max number of results to show for each query.\t[default: unlimited]")
+                        .takes_value(true)
+                )
+                .help_heading("ALGORITHM PARAMETERS")
+                .arg(
+                    Arg::new(LEARNED_ANI)
+                    .long(CMD_LEARNED_ANI)
+                    .help(H_LEARNED_ANI)
+                    .takes_value(false)
+                )
+                .arg(
+                    Arg::new(NO_LEARNED_ANI)
+                    .long(CMD_NO_LEARNED_ANI)
+                    .help(H_NO_LEARNED_ANI)
+                    .takes_value(false)
+                )
+                .arg(
+                    Arg::new(NO_FULL_INDEX)
+                        .long(CMD_NO_FULL_INDEX)
+                        .help("Do not use hash-table inverted index for faster ANI filtering."),
+                )
+                .arg(Arg::new("s").short('s').takes_value(true).help(H_SCREEN))
+                .arg(
+                    Arg::new(ROBUST)
+                        .long(CMD_ROBUST)
+                        .help(H_ROBUST),
+                )
+                .arg(
+                    Arg::new("median")
+                        .long("median")
+                        .help("Estimate median identity instead of average (mean) identity."),
+                )
+                .arg(
+                    Arg::new(KEEP_REFS)
+                        .long(CMD_KEEP_REFS)
+                        .help(H_KEEP_REFS_SERVE),
+                )
+                .help_heading("MISC")
+                .arg(Arg::new("v").short('v').help("Debug level verbosity."))
+                .arg(Arg::new("trace").long("trace").help("Trace level verbosity."))
         )
         .get_matches();
 
@@ -582,13 +1396,31 @@ fn main() {
     let cmd_txt = env::args().into_iter().collect::<Vec<String>>().join(" ");
     log::info!("{}", cmd_txt);
 
-    if command_params.mode == params::Mode::Sketch {
+    if command_params.mode == params::Mode::Sketch && command_params.remove {
+        sketch::remove(command_params);
+    } else if command_params.mode == params::Mode::Sketch && command_params.append {
+        sketch::append(command_params, sketch_params);
+    } else if command_params.mode == params::Mode::Sketch {
         sketch::sketch(command_params, sketch_params);
     } else if command_params.mode == params::Mode::Search {
         search::search(command_params);
     } else if command_params.mode == params::Mode::Dist {
         dist::dist(command_params, sketch_params);
+    } else if command_params.mode == params::Mode::Triangle && command_params.stream {
+        triangle::triangle_stream(command_params, sketch_params);
     } else if command_params.mode == params::Mode::Triangle {
         triangle::triangle(command_params, sketch_params);
+    } else if command_params.mode == params::Mode::Core {
+        core_genome::core(command_params, sketch_params);
+    } else if command_params.mode == params::Mode::Cluster {
+        cluster::cluster(command_params, sketch_params);
+    } else if command_params.mode == params::Mode::Derep {
+        derep::derep(command_params, sketch_params);
+    } else if command_params.mode == params::Mode::Merge {
+        merge::merge(command_params);
+    } else if command_params.mode == params::Mode::Info {
+        info::info(command_params);
+    } else if command_params.mode == params::Mode::Serve {
+        serve::serve(command_params);
     }
 }
@@ -0,0 +1,161 @@
+use crate::error::SkaniError;
+use crate::file_io;
+use crate::file_io::TriangleShard;
+use crate::params::*;
+use crate::types::*;
+use fxhash::FxHashMap;
+use log::*;
+use std::time::Instant;
+
+//Checks that the shards come from the same triangle run and that every shard
+//0..N is given exactly once, then puts their pairs together as one shard 0/1.
+pub fn combine_shards(
+    shards: Vec<(String, SketchParams, TriangleShard)>,
+) -> Result<(SketchParams, TriangleShard), String> {
+    if shards.is_empty() {
+        return Err("no shards given".to_string());
+    }
+    let (first_file, first_params, first) = &shards[0];
+    let num_shards = first.num_shards;
+    if num_shards == 0 {
+        return Err(format!("{} has 0 shards", first_file));
+    }
+    let mut seen: Vec<Option<&String>> = vec![None; num_shards];
+    for (file, params, shard) in shards.iter() {
+        if shard.num_shards != num_shards {
+            return Err(format!(
+                "{} is shard {}/{} but {} is shard {}/{}",
+                first_file, first.shard, num_shards, file, shard.shard, shard.num_shards
+            ));
+        }
+        if shard.names != first.names
+            || shard.use_contig_names != first.use_contig_names
+            || params.k != first_params.k
+            || params.c != first_params.c
+            || params.use_aa != first_params.use_aa
+        {
+            return Err(format!(
+                "{} and {} were computed from different genomes or parameters",
+                first_file, file
+            ));
+        }
+        if shard.shard >= num_shards {
+            return Err(format!("{} is shard {}/{}", file, shard.shard, num_shards));
+        }
+        if let Some((i, j, _)) = shard.anis.iter().find(|(i, j, _)| i >= j || *j >= shard.names.len()) {
+            return Err(format!(
+                "{} has the pair ({}, {}), which is not in a triangle of {} genomes",
+                file,
+                i,
+                j,
+                shard.names.len()
+            ));
+        }
+        if let Some(other) = seen[shard.shard] {
+            return Err(format!(
+                "{} and {} are both shard {}/{}",
+                other, file, shard.shard, num_shards
+            ));
+        }
+        seen[shard.shard] = Some(file);
+    }
+    let missing = (0..num_shards)
+        .filter(|x| seen[*x].is_none())
+        .map(|x| format!("{}/{}", x, num_shards))
+        .collect::<Vec<String>>();
+    if !missing.is_empty() {
+        return Err(format!("missing shard(s) {}", missing.join(", ")));
+    }
+
+    let sketch_params = first_params.clone();
+    let mut shards = shards.into_iter().map(|x| x.2).collect::<Vec<TriangleShard>>();
+    let mut merged = TriangleShard {
+        shard: 0,
+        num_shards: 1,
+        use_contig_names: shards[0].use_contig_names,
+        names: std::mem::take(&mut shards[0].names),
+        anis: vec![],
+    };
+    for shard in shards {
+        merged.anis.extend(shard.anis);
+    }
+    merged.anis.sort_by_key(|x| (x.0, x.1));
+    Ok((sketch_params, merged))
+}
+
+pub fn merge(command_params: CommandParams) {
+    let now = Instant::now();
+    let mut shards = vec![];
+    for file in command_params.ref_files.iter() {
+        match file_io::read_triangle_shard(file) {
+            Ok((params, shard)) => shards.push((file.clone(), params, shard)),
+            Err(SkaniError::Io(e)) => {
+                error!("Could not read {}: {}. Exiting.", file, e);
+                std::process::exit(1)
+            }
+            Err(e) => {
+                error!("{}. Exiting.", e);
+                std::process::exit(1)
+            }
+        }
+    }
+    let (sketch_params, merged) = combine_shards(shards).unwrap_or_else(|e| {
+        error!("Could not merge shards: {}. Exiting.", e);
+        std::process::exit(1)
+    });
+    info!(
+        "{} pairs for {} genomes read from {} shards.",
+        merged.anis.len(),
+        merged.names.len(),
+        command_params.ref_files.len()
+    );
+
+    //The matrix writers only need the names of each genome.
+    let sketches = merged
+        .names
+        .into_iter()
+        .map(|(file_name, contig)| Sketch {
+            file_name,
+            contigs: vec![contig],
+            ..Default::default()
+        })
+        .collect::<Vec<Sketch>>();
+    let mut anis: FxHashMap<usize, FxHashMap<usize, AniEstResult>> = FxHashMap::default();
+    for (i, j, ani_res) in merged.anis {
+        anis.entry(i).or_default().insert(j, ani_res);
+    }
+
+    if command_params.binary {
+        if let Err(e) = file_io::write_sparse_coo(
+            &anis,
+            &sketches,
+            &command_params.out_file_name,
+            merged.use_contig_names,
+        ) {
+            error!("Could not write {}: {}. Exiting.", command_params.out_file_name, e);
+            std::process::exit(1)
+        }
+    } else if command_params.sparse {
+        file_io::write_sparse_matrix(
+            &anis,
+            &sketches,
+            &command_params.out_file_name,
+            sketch_params.use_aa,
+            command_params.est_ci,
+            command_params.detailed_out,
+            command_params.output_format,
+            false,
+        );
+    } else {
+        file_io::write_phyllip_matrix(
+            &anis,
+            &sketches,
+            &command_params.out_file_name,
+            merged.use_contig_names,
+            command_params.full_matrix,
+            sketch_params.use_aa,
+            command_params.distance,
+        );
+    }
+    info!("Merge time: {}", now.elapsed().as_secs_f32());
+}
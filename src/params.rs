@@ -32,6 +32,12 @@ pub const SEARCH_STRING: &str = "search";
 pub const DIST_STRING: &str = "dist";
 pub const SKETCH_STRING: &str = "sketch";
 pub const TRIANGLE_STRING: &str = "triangle";
+pub const INFO_STRING: &str = "info";
+pub const SERVE_STRING: &str = "serve";
+pub const CORE_STRING: &str = "core";
+pub const CLUSTER_STRING: &str = "cluster";
+pub const DEREP_STRING: &str = "derep";
+pub const MERGE_STRING: &str = "merge";
 pub const CHUNK_SIZE_DNA: usize = 20000;
 pub const CHUNK_SIZE_AA: usize = 20000;
 pub const MIN_LENGTH_CONTIG: usize = 500;
@@ -45,6 +51,28 @@ pub const FULL_INDEX_THRESH: usize = 50;
 pub const REPET_KMER_THRESHOLD: usize = 8_000_000;
 pub const OVERLAP_ORTHOLOGOUS_FRACTION: f32  = 0.50;
 pub const TOTAL_BASES_REGRESS_CUTOFF: usize = 150000;
+pub const SKETCH_MAGIC: &[u8; 8] = b"SKANISKT";
+pub const SKETCH_FORMAT_VERSION: u32 = 1;
+pub const PACKED_DB_MAGIC: &[u8; 8] = b"SKANIPKD";
+pub const PACKED_DB_VERSION: u32 = 1;
+pub const PACKED_DB_PREFIX: &str = "packed_db";
+pub const MARKER_INDEX_MAGIC: &[u8; 8] = b"SKANIMKI";
+pub const MARKER_INDEX_VERSION: u32 = 1;
+//Seconds `skani serve` waits for a client to send or read data before
+//dropping the connection.
+pub const SERVE_TIMEOUT_SECS: u64 = 60;
+pub const SERVE_MAX_QUERY_BYTES: usize = 256 << 20;
+pub const MARKER_INDEX_FILE: &str = "marker_index.bin";
+pub const CORE_WINDOW_DEFAULT: &str = "1000";
+pub const CORE_THRESHOLD_DEFAULT: &str = "95";
+pub const CORE_WINDOW_MIN_COVER: f64 = 0.5;
+pub const CLUSTER_ANI_DEFAULT: &str = "95";
+pub const TREE_FILL_DEFAULT: &str = "100";
+pub const STREAM_BLOCK_SIZE_DEFAULT: &str = "5000";
+pub const SPARSE_COO_MAGIC: &[u8; 8] = b"SKANICOO";
+pub const SPARSE_COO_VERSION: u32 = 2;
+pub const STDIN_FILE: &str = "-";
+pub const ZSTD_MAGIC: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
 pub const LEARNED_INFO_HELP: &str = "Learned ANI mode detected. ANI may be adjusted according to a regression model trained on MAGs.";
 
 pub const FAST_C: usize = 200;
@@ -58,9 +86,35 @@ pub enum Mode {
     Dist,
     Triangle,
     Search,
+    Info,
+    Serve,
+    Core,
+    Cluster,
+    Derep,
+    Merge,
 }
 
-#[derive(Default, PartialEq)]
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum OutputFormat {
+    Tsv,
+    Json,
+    Jsonl,
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum ClusterMethod {
+    Single,
+    Complete,
+    Greedy,
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum TreeMethod {
+    Upgma,
+    Nj,
+}
+
+#[derive(Default, PartialEq, Clone, Debug)]
 pub struct MapParams {
     pub fragment_length: usize,
     pub max_gap_length: f64,
@@ -103,6 +157,35 @@ pub struct CommandParams{
     pub learned_ani_cmd: bool,
     pub detailed_out: bool,
     pub distance: bool,
+    pub append: bool,
+    pub remove: bool,
+    pub output_format: OutputFormat,
+    pub packed: bool,
+    pub marker_index: bool,
+    pub socket_path: String,
+    pub chains_file: String,
+    pub fragment_ani_file: String,
+    pub aligned_bed_prefix: String,
+    pub core_ref: String,
+    pub core_window: usize,
+    pub core_threshold: f64,
+    pub cluster_method: ClusterMethod,
+    pub cluster_ani: f64,
+    pub quality_file: String,
+    pub quality_column: String,
+    pub derep_out_dir: String,
+    pub derep_symlink: bool,
+    pub tree_file: String,
+    pub tree_method: TreeMethod,
+    pub tree_fill: f64,
+    pub stream: bool,
+    pub block_size: usize,
+    pub shard: usize,
+    pub num_shards: usize,
+    pub resume: bool,
+    pub binary: bool,
+    pub genome_map: String,
+    pub genome_regex: String,
 }
 
 pub fn fragment_length_formula(_n: usize, aa: bool) -> usize {
@@ -116,7 +199,7 @@ pub fn fragment_length_formula(_n: usize, aa: bool) -> usize {
 //    return (n as f64).sqrt() as usize * 3;
 }
 
-#[derive(Default,  PartialEq, Serialize, Deserialize, Debug)]
+#[derive(Default, PartialEq, Clone, Serialize, Deserialize, Debug)]
 pub struct SketchParams {
     pub c: usize,
     pub k: usize,
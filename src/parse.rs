@@ -29,6 +29,30 @@ pub fn parse_params(matches: &ArgMatches) -> (SketchParams, CommandParams) {
             matches_subc = matches.subcommand_matches(SEARCH_STRING).unwrap();
             //            return parse_params_search(matches_subc);
         }
+        Some(SERVE_STRING) => {
+            mode = Mode::Serve;
+            matches_subc = matches.subcommand_matches(SERVE_STRING).unwrap();
+        }
+        Some(CORE_STRING) => {
+            mode = Mode::Core;
+            matches_subc = matches.subcommand_matches(CORE_STRING).unwrap();
+        }
+        Some(CLUSTER_STRING) => {
+            mode = Mode::Cluster;
+            matches_subc = matches.subcommand_matches(CLUSTER_STRING).unwrap();
+        }
+        Some(DEREP_STRING) => {
+            mode = Mode::Derep;
+            matches_subc = matches.subcommand_matches(DEREP_STRING).unwrap();
+        }
+        Some(MERGE_STRING) => {
+            mode = Mode::Merge;
+            matches_subc = matches.subcommand_matches(MERGE_STRING).unwrap();
+        }
+        Some(INFO_STRING) => {
+            mode = Mode::Info;
+            matches_subc = matches.subcommand_matches(INFO_STRING).unwrap();
+        }
         _ => {
             panic!()
         } // Either no subcommand or one not tested for...
@@ -50,8 +74,14 @@ pub fn parse_params(matches: &ArgMatches) -> (SketchParams, CommandParams) {
         simple_logging::log_to_stderr(LevelFilter::Trace);
     }
 
-    if mode == Mode::Search {
-        return parse_params_search(matches_subc);
+    if mode == Mode::Search || mode == Mode::Serve {
+        return parse_params_search(matches_subc, mode);
+    }
+    if mode == Mode::Info {
+        return parse_params_info(matches_subc);
+    }
+    if mode == Mode::Merge {
+        return parse_params_merge(matches_subc);
     }
 
     let amino_acid;
@@ -68,7 +98,7 @@ pub fn parse_params(matches: &ArgMatches) -> (SketchParams, CommandParams) {
     if mode == Mode::Triangle {
         sparse = matches_subc.is_present("sparse");
     }
-    if mode == Mode::Triangle || mode == Mode::Sketch {
+    if mode == Mode::Triangle || mode == Mode::Sketch || mode == Mode::Core || mode == Mode::Cluster || mode == Mode::Derep {
         if let Some(values) = matches_subc.values_of("fasta_files") {
             ref_files = values.map(|x| x.to_string()).collect();
         } else if let Some(values) = matches_subc.value_of("fasta_list") {
@@ -188,8 +218,8 @@ pub fn parse_params(matches: &ArgMatches) -> (SketchParams, CommandParams) {
             .parse::<f64>()
             .unwrap()
             / 100.;
-        est_ci = matches_subc.is_present(CONF_INTERVAL);
-        detailed_out = matches_subc.is_present(DETAIL_OUT);
+        est_ci = (mode == Mode::Triangle || mode == Mode::Dist) && matches_subc.is_present(CONF_INTERVAL);
+        detailed_out = (mode == Mode::Triangle || mode == Mode::Dist) && matches_subc.is_present(DETAIL_OUT);
     } else {
         min_aligned_frac = 0.;
         est_ci = false;
@@ -209,7 +239,7 @@ pub fn parse_params(matches: &ArgMatches) -> (SketchParams, CommandParams) {
             .value_of("output sketch folder")
             .unwrap_or("")
             .to_string();
-    } else if mode == Mode::Dist {
+    } else if mode == Mode::Dist || mode == Mode::Core || mode == Mode::Cluster || mode == Mode::Derep {
         out_file_name = matches_subc.value_of("output").unwrap_or("").to_string();
     } else {
         panic!("Mode doesn't exist");
@@ -218,7 +248,7 @@ pub fn parse_params(matches: &ArgMatches) -> (SketchParams, CommandParams) {
     let mut screen_val = 0.;
     let mut robust = false;
     let mut median = false;
-    if mode == Mode::Triangle || mode == Mode::Dist {
+    if mode == Mode::Triangle || mode == Mode::Dist || mode == Mode::Cluster || mode == Mode::Derep {
         screen_val = matches_subc
             .value_of("s")
             .unwrap_or("0.00")
@@ -226,12 +256,156 @@ pub fn parse_params(matches: &ArgMatches) -> (SketchParams, CommandParams) {
             .unwrap()
             / 100.;
     }
-    if mode == Mode::Triangle || mode == Mode::Search || mode == Mode::Dist {
+    if mode == Mode::Triangle
+        || mode == Mode::Search
+        || mode == Mode::Dist
+        || mode == Mode::Core
+        || mode == Mode::Cluster
+        || mode == Mode::Derep
+    {
         robust = matches_subc.is_present("robust");
         median = matches_subc.is_present("median");
     }
 
-    let sketch_params = SketchParams::new(marker_c, c, k, use_syncs, amino_acid);
+    let append = mode == Mode::Sketch && matches_subc.is_present(APPEND);
+    let remove = mode == Mode::Sketch && matches_subc.is_present(REMOVE);
+    let packed = mode == Mode::Sketch && matches_subc.is_present(PACKED);
+    let marker_index = mode == Mode::Sketch && matches_subc.is_present(SAVE_MARKER_INDEX);
+    let mut chains_file = String::new();
+    let mut fragment_ani_file = String::new();
+    let mut aligned_bed_prefix = String::new();
+    if mode == Mode::Dist {
+        chains_file = matches_subc.value_of(CHAINS).unwrap_or("").to_string();
+        fragment_ani_file = matches_subc.value_of(FRAGMENT_ANI).unwrap_or("").to_string();
+        aligned_bed_prefix = matches_subc.value_of(ALIGNED_BED).unwrap_or("").to_string();
+    }
+    let mut core_ref = String::new();
+    let mut core_window = 0;
+    let mut core_threshold = 0.;
+    if mode == Mode::Core {
+        core_ref = matches_subc.value_of(CORE_REF).unwrap_or("").to_string();
+        core_window = matches_subc
+            .value_of(CORE_WINDOW)
+            .unwrap_or(CORE_WINDOW_DEFAULT)
+            .parse::<usize>()
+            .unwrap();
+        core_threshold = matches_subc
+            .value_of(CORE_THRESHOLD)
+            .unwrap_or(CORE_THRESHOLD_DEFAULT)
+            .parse::<f64>()
+            .unwrap()
+            / 100.;
+        if core_window == 0 {
+            error!("--{} must be > 0. Exiting.", CMD_CORE_WINDOW);
+            std::process::exit(1);
+        }
+    }
+    let mut cluster_method = ClusterMethod::Single;
+    let mut cluster_ani = 0.;
+    let mut quality_file = String::new();
+    let mut quality_column = String::new();
+    let mut derep_out_dir = String::new();
+    let mut derep_symlink = false;
+    if mode == Mode::Derep {
+        cluster_method = ClusterMethod::Greedy;
+        quality_file = matches_subc.value_of(QUALITY_FILE).unwrap_or("").to_string();
+        quality_column = matches_subc.value_of(QUALITY_COLUMN).unwrap_or("").to_string();
+        if let Some(dir) = matches_subc.value_of(DEREP_SYMLINK) {
+            derep_out_dir = dir.to_string();
+            derep_symlink = true;
+        } else if let Some(dir) = matches_subc.value_of(DEREP_COPY) {
+            derep_out_dir = dir.to_string();
+        }
+    }
+    if mode == Mode::Cluster {
+        cluster_method = match matches_subc.value_of(CLUSTER_METHOD) {
+            Some("complete") => ClusterMethod::Complete,
+            Some("greedy") => ClusterMethod::Greedy,
+            _ => ClusterMethod::Single,
+        };
+    }
+    if mode == Mode::Cluster || mode == Mode::Derep {
+        cluster_ani = matches_subc
+            .value_of(CLUSTER_ANI)
+            .unwrap_or(CLUSTER_ANI_DEFAULT)
+            .parse::<f64>()
+            .unwrap()
+            / 100.;
+    }
+    let mut tree_file = String::new();
+    let mut tree_method = TreeMethod::Upgma;
+    let mut tree_fill = 0.;
+    if mode == Mode::Triangle {
+        tree_file = matches_subc.value_of(TREE).unwrap_or("").to_string();
+        if matches_subc.value_of(TREE_METHOD) == Some("nj") {
+            tree_method = TreeMethod::Nj;
+        }
+        tree_fill = matches_subc
+            .value_of(TREE_FILL)
+            .unwrap_or(TREE_FILL_DEFAULT)
+            .parse::<f64>()
+            .unwrap();
+    }
+    let mut stream = false;
+    let mut block_size = 0;
+    if mode == Mode::Triangle {
+        stream = matches_subc.is_present(STREAM);
+        block_size = matches_subc
+            .value_of(BLOCK_SIZE)
+            .unwrap_or(STREAM_BLOCK_SIZE_DEFAULT)
+            .parse::<usize>()
+            .unwrap();
+        if block_size == 0 {
+            error!("--{} must be > 0. Exiting.", CMD_BLOCK_SIZE);
+            std::process::exit(1);
+        }
+    }
+    let mut shard = 0;
+    let mut num_shards = 0;
+    if mode == Mode::Triangle {
+        if let Some(value) = matches_subc.value_of(SHARD) {
+            match parse_shard(value) {
+                Some((x, y)) => {
+                    shard = x;
+                    num_shards = y;
+                }
+                None => {
+                    error!("--{} must be I/N with 0 <= I < N, e.g. 0/4. Exiting.", CMD_SHARD);
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
+    let mut output_format = OutputFormat::Tsv;
+    if mode == Mode::Triangle || mode == Mode::Dist {
+        output_format = parse_output_format(matches_subc);
+    }
+    if output_format != OutputFormat::Tsv && mode == Mode::Triangle && !sparse {
+        error!("--{} json/jsonl requires --sparse (-E). Exiting.", CMD_OUTPUT_FORMAT);
+        std::process::exit(1);
+    }
+    let resume = (mode == Mode::Triangle || mode == Mode::Dist) && matches_subc.is_present(RESUME);
+    let binary = mode == Mode::Triangle && matches_subc.is_present(BINARY);
+    let mut genome_map = String::new();
+    let mut genome_regex = String::new();
+    if mode == Mode::Sketch || mode == Mode::Dist || mode == Mode::Triangle {
+        genome_map = matches_subc.value_of(GENOME_MAP).unwrap_or("").to_string();
+        genome_regex = matches_subc.value_of(GENOME_REGEX).unwrap_or("").to_string();
+    }
+    check_resume(resume, output_format);
+    let explicit_sketch_params = ["c", "k", "marker_c", "aai", MODE_FAST, MODE_SLOW, MODE_MEDIUM]
+        .iter()
+        .any(|x| matches_subc.is_present(x));
+
+    //When appending without explicit parameters, the parameters stored
+    //in the sketch folder are used; see sketch::append.
+    let sketch_params = if append && !explicit_sketch_params {
+        SketchParams::default()
+    } else {
+        SketchParams::new(marker_c, c, k, use_syncs, amino_acid)
+    };
+
+    check_stdin(&ref_files, &query_files, mode == Mode::Dist);
 
     let mut refs_are_sketch = !ref_files.is_empty();
     for ref_file in ref_files.iter() {
@@ -298,7 +472,7 @@ pub fn parse_params(matches: &ArgMatches) -> (SketchParams, CommandParams) {
 
     let learned_ani;
     let learned_ani_cmd;
-    if mode == Mode::Triangle || mode == Mode::Dist {
+    if mode == Mode::Triangle || mode == Mode::Dist || mode == Mode::Core || mode == Mode::Cluster || mode == Mode::Derep {
         if matches_subc.is_present(LEARNED_ANI) && matches_subc.is_present(NO_LEARNED_ANI) {
             panic!("Only one of --learned-ani and --no-learned-ani is allowed");
         } else if matches_subc.is_present(LEARNED_ANI) {
@@ -346,14 +520,52 @@ pub fn parse_params(matches: &ArgMatches) -> (SketchParams, CommandParams) {
         learned_ani_cmd,
         detailed_out,
         distance,
+        append,
+        remove,
+        output_format,
+        packed,
+        marker_index,
+        socket_path: String::new(),
+        chains_file,
+        fragment_ani_file,
+        aligned_bed_prefix,
+        core_ref,
+        core_window,
+        core_threshold,
+        cluster_method,
+        cluster_ani,
+        quality_file,
+        quality_column,
+        derep_out_dir,
+        derep_symlink,
+        tree_file,
+        tree_method,
+        tree_fill,
+        stream,
+        block_size,
+        shard,
+        num_shards,
+        resume,
+        binary,
+        genome_map,
+        genome_regex,
     };
 
     (sketch_params, command_params)
 }
 
-pub fn parse_params_search(matches_subc: &ArgMatches) -> (SketchParams, CommandParams) {
-    let mode = Mode::Search;
-    let out_file_name = matches_subc.value_of("output").unwrap_or("").to_string();
+//Also used for `skani serve`, which has no query or output arguments.
+pub fn parse_params_search(matches_subc: &ArgMatches, mode: Mode) -> (SketchParams, CommandParams) {
+    let serve = mode == Mode::Serve;
+    let out_file_name;
+    let socket_path;
+    if serve {
+        out_file_name = String::new();
+        socket_path = matches_subc.value_of(SOCKET).unwrap().to_string();
+    } else {
+        out_file_name = matches_subc.value_of("output").unwrap_or("").to_string();
+        socket_path = String::new();
+    }
 
     let mut query_files = vec![];
     let mut query_file_list = None;
@@ -362,7 +574,8 @@ pub fn parse_params_search(matches_subc: &ArgMatches) -> (SketchParams, CommandP
         .unwrap_or("1000000000")
         .parse::<usize>()
         .unwrap();
-    if let Some(values) = matches_subc.values_of("query") {
+    if serve {
+    } else if let Some(values) = matches_subc.values_of("query") {
         query_files = values.map(|x| x.to_string()).collect();
     } else if let Some(values) = matches_subc.values_of("queries") {
         query_files = values.map(|x| x.to_string()).collect();
@@ -388,6 +601,7 @@ pub fn parse_params_search(matches_subc: &ArgMatches) -> (SketchParams, CommandP
         .map(|x| x.unwrap().path().to_str().unwrap().to_string())
         .collect();
     let refs_are_sketch = true;
+    check_stdin(&[], &query_files, true);
 
     let mut queries_are_sketch = !query_files.is_empty();
     for query_file in query_files.iter() {
@@ -409,7 +623,7 @@ pub fn parse_params_search(matches_subc: &ArgMatches) -> (SketchParams, CommandP
         / 100.;
     let screen;
     let individual_contig_q = matches_subc.is_present("individual contig query");
-    if (query_files.len() > FULL_INDEX_THRESH || individual_contig_q) && !matches_subc.is_present(NO_FULL_INDEX) {
+    if (query_files.len() > FULL_INDEX_THRESH || individual_contig_q || serve) && !matches_subc.is_present(NO_FULL_INDEX) {
         screen = true;
     } else {
         screen = false;
@@ -422,6 +636,13 @@ pub fn parse_params_search(matches_subc: &ArgMatches) -> (SketchParams, CommandP
         .unwrap()
         / 100.;
     let keep_refs = matches_subc.is_present(KEEP_REFS);
+    let output_format = if serve {
+        OutputFormat::Tsv
+    } else {
+        parse_output_format(matches_subc)
+    };
+    let resume = !serve && matches_subc.is_present(RESUME);
+    check_resume(resume, output_format);
     let est_ci = matches_subc.is_present(CONF_INTERVAL);
     let detailed_out = matches_subc.is_present(DETAIL_OUT);
     let learned_ani;
@@ -464,6 +685,35 @@ pub fn parse_params_search(matches_subc: &ArgMatches) -> (SketchParams, CommandP
         learned_ani_cmd,
         detailed_out,
         distance: false,
+        append: false,
+        remove: false,
+        output_format,
+        packed: false,
+        marker_index: false,
+        socket_path,
+        chains_file: String::new(),
+        fragment_ani_file: String::new(),
+        aligned_bed_prefix: String::new(),
+        core_ref: String::new(),
+        core_window: 0,
+        core_threshold: 0.,
+        cluster_method: ClusterMethod::Single,
+        cluster_ani: 0.,
+        quality_file: String::new(),
+        quality_column: String::new(),
+        derep_out_dir: String::new(),
+        derep_symlink: false,
+        tree_file: String::new(),
+        tree_method: TreeMethod::Upgma,
+        tree_fill: 0.,
+        stream: false,
+        block_size: 0,
+        shard: 0,
+        num_shards: 0,
+        resume,
+        binary: false,
+        genome_map: if serve { String::new() } else { matches_subc.value_of(GENOME_MAP).unwrap_or("").to_string() },
+        genome_regex: if serve { String::new() } else { matches_subc.value_of(GENOME_REGEX).unwrap_or("").to_string() },
     };
 
     if command_params.ref_files.is_empty() {
@@ -473,5 +723,186 @@ pub fn parse_params_search(matches_subc: &ArgMatches) -> (SketchParams, CommandP
 
 
 
+    (SketchParams::default(), command_params)
+}
+
+fn parse_output_format(matches_subc: &ArgMatches) -> OutputFormat {
+    match matches_subc.value_of(OUTPUT_FORMAT) {
+        Some("json") => OutputFormat::Json,
+        Some("jsonl") => OutputFormat::Jsonl,
+        _ => OutputFormat::Tsv,
+    }
+}
+
+//Stdin can only be read once, and only queries are sketched from it.
+fn check_stdin(ref_files: &[String], query_files: &[String], queries_allowed: bool) {
+    let num_stdin = |files: &[String]| files.iter().filter(|x| *x == STDIN_FILE).count();
+    if num_stdin(ref_files) > 0 || (!queries_allowed && num_stdin(query_files) > 0) {
+        error!("{} (stdin) can only be used for queries of `skani dist` and `skani search`. Exiting.", STDIN_FILE);
+        std::process::exit(1);
+    }
+    if num_stdin(query_files) > 1 {
+        error!("{} (stdin) can only be given once. Exiting.", STDIN_FILE);
+        std::process::exit(1);
+    }
+}
+
+//JSON output is written once at the end, so there is nothing to resume.
+fn check_resume(resume: bool, output_format: OutputFormat) {
+    if resume && output_format == OutputFormat::Json {
+        error!("--{} does not work with --{} json; use jsonl instead. Exiting.", CMD_RESUME, CMD_OUTPUT_FORMAT);
+        std::process::exit(1);
+    }
+}
+
+fn parse_shard(value: &str) -> Option<(usize, usize)> {
+    let (shard, num_shards) = value.split_once('/')?;
+    let shard = shard.trim().parse::<usize>().ok()?;
+    let num_shards = num_shards.trim().parse::<usize>().ok()?;
+    if shard < num_shards {
+        Some((shard, num_shards))
+    } else {
+        None
+    }
+}
+
+pub fn parse_params_merge(matches_subc: &ArgMatches) -> (SketchParams, CommandParams) {
+    let ref_files = matches_subc
+        .values_of("inputs")
+        .unwrap()
+        .map(|x| x.to_string())
+        .collect();
+    let out_file_name = matches_subc.value_of("output").unwrap_or("").to_string();
+    let sparse = matches_subc.is_present("sparse");
+    let output_format = parse_output_format(matches_subc);
+    if output_format != OutputFormat::Tsv && !sparse {
+        error!("--{} json/jsonl requires --sparse (-E). Exiting.", CMD_OUTPUT_FORMAT);
+        std::process::exit(1);
+    }
+
+    let command_params = CommandParams {
+        screen: false,
+        screen_val: 0.,
+        mode: Mode::Merge,
+        out_file_name,
+        ref_files,
+        query_files: vec![],
+        refs_are_sketch: false,
+        queries_are_sketch: false,
+        robust: false,
+        median: false,
+        sparse,
+        full_matrix: matches_subc.is_present(FULL_MAT),
+        max_results: usize::MAX,
+        individual_contig_q: false,
+        individual_contig_r: false,
+        min_aligned_frac: 0.,
+        keep_refs: false,
+        est_ci: matches_subc.is_present(CONF_INTERVAL),
+        learned_ani: false,
+        learned_ani_cmd: false,
+        detailed_out: matches_subc.is_present(DETAIL_OUT),
+        distance: matches_subc.is_present(DISTANCE_OUT),
+        append: false,
+        remove: false,
+        output_format,
+        packed: false,
+        marker_index: false,
+        socket_path: String::new(),
+        chains_file: String::new(),
+        fragment_ani_file: String::new(),
+        aligned_bed_prefix: String::new(),
+        core_ref: String::new(),
+        core_window: 0,
+        core_threshold: 0.,
+        cluster_method: ClusterMethod::Single,
+        cluster_ani: 0.,
+        quality_file: String::new(),
+        quality_column: String::new(),
+        derep_out_dir: String::new(),
+        derep_symlink: false,
+        tree_file: String::new(),
+        tree_method: TreeMethod::Upgma,
+        tree_fill: 0.,
+        stream: false,
+        block_size: 0,
+        shard: 0,
+        num_shards: 0,
+        resume: false,
+        binary: matches_subc.is_present(BINARY),
+        genome_map: String::new(),
+        genome_regex: String::new(),
+    };
+
+    (SketchParams::default(), command_params)
+}
+
+pub fn parse_params_info(matches_subc: &ArgMatches) -> (SketchParams, CommandParams) {
+    let ref_files = matches_subc
+        .values_of("inputs")
+        .unwrap()
+        .map(|x| x.to_string())
+        .collect();
+    let out_file_name = matches_subc.value_of("output").unwrap_or("").to_string();
+    let output_format = if matches_subc.is_present(JSON_OUT) {
+        OutputFormat::Json
+    } else {
+        OutputFormat::Tsv
+    };
+
+    let command_params = CommandParams {
+        screen: false,
+        screen_val: 0.,
+        mode: Mode::Info,
+        out_file_name,
+        ref_files,
+        query_files: vec![],
+        refs_are_sketch: true,
+        queries_are_sketch: false,
+        robust: false,
+        median: false,
+        sparse: false,
+        full_matrix: false,
+        max_results: usize::MAX,
+        individual_contig_q: false,
+        individual_contig_r: false,
+        min_aligned_frac: 0.,
+        keep_refs: false,
+        est_ci: false,
+        learned_ani: false,
+        learned_ani_cmd: false,
+        detailed_out: false,
+        distance: false,
+        append: false,
+        remove: false,
+        output_format,
+        packed: false,
+        marker_index: false,
+        socket_path: String::new(),
+        chains_file: String::new(),
+        fragment_ani_file: String::new(),
+        aligned_bed_prefix: String::new(),
+        core_ref: String::new(),
+        core_window: 0,
+        core_threshold: 0.,
+        cluster_method: ClusterMethod::Single,
+        cluster_ani: 0.,
+        quality_file: String::new(),
+        quality_column: String::new(),
+        derep_out_dir: String::new(),
+        derep_symlink: false,
+        tree_file: String::new(),
+        tree_method: TreeMethod::Upgma,
+        tree_fill: 0.,
+        stream: false,
+        block_size: 0,
+        shard: 0,
+        num_shards: 0,
+        resume: false,
+        binary: false,
+        genome_map: String::new(),
+        genome_regex: String::new(),
+    };
+
     (SketchParams::default(), command_params)
 }
@@ -7,22 +7,36 @@ use fxhash::FxHashMap;
 use fxhash::FxHashSet;
 use log::*;
 
+//Marker -> reference sketch indices. Implemented by the in-memory
+//KmerToSketch and by the persisted index in db.rs.
+pub trait MarkerIndex {
+    fn for_each_sketch_with(&self, marker: MarkerBits, f: &mut dyn FnMut(u32));
+}
+
+impl MarkerIndex for KmerToSketch {
+    fn for_each_sketch_with(&self, marker: MarkerBits, f: &mut dyn FnMut(u32)) {
+        if let Some(sketch_ids) = self.get(&marker) {
+            for sketch_id in sketch_ids.iter() {
+                f(*sketch_id);
+            }
+        }
+    }
+}
+
 //Used in search, but not in dist,triangle
-pub fn screen_refs_filenames<'a>(
+pub fn screen_refs_filenames<'a, I: MarkerIndex + ?Sized>(
     identity: f64,
-    kmer_to_sketch: &KmerToSketch,
+    kmer_to_sketch: &I,
     query_sketch: &Sketch,
     sketch_params: &SketchParams,
-    ref_sketches: &'a Vec<Sketch>
+    ref_sketches: &'a [Sketch]
 ) -> Vec<&'a String>{
     let mut count_hash_map = FxHashMap::default();
     for marker in query_sketch.marker_seeds.iter() {
-        if kmer_to_sketch.contains_key(marker) {
-            for sketch_id in kmer_to_sketch[marker].iter() {
-                let count = count_hash_map.entry(sketch_id).or_insert(0);
-                *count += 1;
-            }
-        }
+        kmer_to_sketch.for_each_sketch_with(*marker, &mut |sketch_id| {
+            let count = count_hash_map.entry(sketch_id).or_insert(0);
+            *count += 1;
+        });
     }
     //Use fixed K value for AA markers, but flexible ones for DNA because saturation less of an
     //issue.
@@ -38,24 +52,59 @@ pub fn screen_refs_filenames<'a>(
         .filter(|x| {
             *x.1 > usize::max((cutoff 
                 * usize::min(
-                    ref_sketches[**x.0 as usize].marker_seeds.len(),
+                    ref_sketches[*x.0 as usize].marker_seeds.len(),
                     query_sketch.marker_seeds.len(),
                 ) as f64) as usize,1)
         })
-        .map(|x| &ref_sketches[**x.0 as usize].file_name)
+        .map(|x| &ref_sketches[*x.0 as usize].file_name)
         .collect();
     ret
 
 }
 
+//Same as screen_refs_filenames but returns indices, and only needs the
+//number of markers of each reference.
+pub fn screen_refs_with<I: MarkerIndex + ?Sized, F: Fn(usize) -> usize>(
+    identity: f64,
+    kmer_to_sketch: &I,
+    query_sketch: &Sketch,
+    sketch_params: &SketchParams,
+    ref_num_markers: F,
+) -> Vec<usize> {
+    let mut count_hash_map = FxHashMap::default();
+    for marker in query_sketch.marker_seeds.iter() {
+        kmer_to_sketch.for_each_sketch_with(*marker, &mut |sketch_id| {
+            let count = count_hash_map.entry(sketch_id as usize).or_insert(0);
+            *count += 1;
+        });
+    }
+    let k = if sketch_params.use_aa {
+        K_MARKER_AA
+    } else {
+        K_MARKER_DNA
+    };
+    let cutoff = identity.powi(k as i32);
+    count_hash_map
+        .into_iter()
+        .filter(|x| {
+            x.1 > usize::max(
+                (cutoff * usize::min(ref_num_markers(x.0), query_sketch.marker_seeds.len()) as f64)
+                    as usize,
+                1,
+            )
+        })
+        .map(|x| x.0)
+        .collect()
+}
+
 //used in triangle, dist, but not search. Note the different behavior when the input sketch
 //is small. 
-pub fn screen_refs(
+pub fn screen_refs<I: MarkerIndex + ?Sized>(
     identity: f64,
-    kmer_to_sketch: &KmerToSketch,
+    kmer_to_sketch: &I,
     query_sketch: &Sketch,
     sketch_params: &SketchParams,
-    ref_sketches: &Vec<Sketch>,
+    ref_sketches: &[Sketch],
 ) -> FxHashSet<usize> {
     let mut count_hash_map = FxHashMap::default();
     //Don't screen when the input sketch is too small.
@@ -63,12 +112,10 @@ pub fn screen_refs(
         return (0..ref_sketches.len()).collect();
     }
     for marker in query_sketch.marker_seeds.iter() {
-        if kmer_to_sketch.contains_key(marker) {
-            for sketch_id in kmer_to_sketch[marker].iter() {
-                let count = count_hash_map.entry(sketch_id).or_insert(0);
-                *count += 1;
-            }
-        }
+        kmer_to_sketch.for_each_sketch_with(*marker, &mut |sketch_id| {
+            let count = count_hash_map.entry(sketch_id).or_insert(0);
+            *count += 1;
+        });
     }
     //Use fixed K value for AA markers, but flexible ones for DNA because saturation less of an
     //issue.
@@ -83,15 +130,15 @@ pub fn screen_refs(
         .filter(|x| {
             *x.1 > usize::max((cutoff 
                 * usize::min(
-                    ref_sketches[**x.0 as usize].marker_seeds.len(),
+                    ref_sketches[*x.0 as usize].marker_seeds.len(),
                     query_sketch.marker_seeds.len(),
                 ) as f64) as usize,1)
         })
-        .map(|x| **x.0 as usize)
+        .map(|x| *x.0 as usize)
         .collect();
     ret
 }
-pub fn kmer_to_sketch_from_refs(ref_sketches: &Vec<Sketch>) -> KmerToSketch {
+pub fn kmer_to_sketch_from_refs(ref_sketches: &[Sketch]) -> KmerToSketch {
 //    let max_size: usize = ref_sketches.iter().map(|x| x.marker_seeds.len()).sum::<usize>();
     let mut ret = KmerToSketch::default();
     //ret.reserve(max_size);
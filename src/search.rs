@@ -1,261 +1,125 @@
 use crate::chain;
+use crate::checkpoint::{self, Checkpoint};
+use crate::db;
 use crate::regression;
 use crate::file_io;
+use crate::grouping::GenomeGrouping;
 use crate::params::*;
 use crate::screen;
+use crate::screen::MarkerIndex;
+use crate::sketch;
 use crate::types::*;
 use fxhash::FxHashMap;
 use log::*;
 use rayon::prelude::*;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Mutex;
 use std::sync::RwLock;
 use std::time::Instant;
 
 pub fn search(command_params: CommandParams) {
-    let now = Instant::now();
     info!("Searching...");
-    let mut ref_marker_file = "";
-    for file in command_params.ref_files.iter() {
-        if !file.contains(".sketch") && !file.contains("marker") {
-            warn!(
-                "{} does not have .sketch as an extension; skipping file",
-                file
-            );
-        } else if file.contains("markers.bin") {
-            ref_marker_file = file;
-        }
-    }
-
-    if ref_marker_file.is_empty() {
-        //error!("No sketch files found in the folder. Sketch files must be generated by `skani sketch` and have the .sketch extension.");
-        error!("markers.bin not found in the folder. Ensure that the folder was generated by `skani sketch`.");
-        std::process::exit(1)
-    }
-
-    let ref_sketches;
-    let sketch_params;
-    (sketch_params, ref_sketches) = file_io::marker_sketches_from_marker_file(ref_marker_file);
-    let screen_val;
-    if command_params.screen_val == 0. {
-        if sketch_params.use_aa {
-            screen_val = SEARCH_AAI_CUTOFF_DEFAULT;
-        } else {
-            screen_val = SEARCH_ANI_CUTOFF_DEFAULT;
-        }
-    } else {
-        screen_val = command_params.screen_val;
-    }
-
-    info!("Loading markers time: {}", now.elapsed().as_secs_f32());
-    let kmer_to_sketch;
-    if command_params.screen {
-        let now = Instant::now();
-        info!("Full index option detected; generating marker hash table");
-        kmer_to_sketch = screen::kmer_to_sketch_from_refs(&ref_sketches);
-        info!("Full indexing time: {}", now.elapsed().as_secs_f32());
-    } else {
-        kmer_to_sketch = KmerToSketch::default();
-    }
-    let ref_sketches_used: RwLock<FxHashMap<_, _>> = RwLock::new(FxHashMap::default());
+    let search_db = SearchDb::load(&command_params);
+    let sketch_params = search_db.sketch_params();
 
     let now = Instant::now();
-    //assert!(ref_sketches.len() == ref_marker_files.len());
-    let anis: Mutex<Vec<AniEstResult>> = Mutex::new(vec![]);
     let counter: Mutex<usize> = Mutex::new(0);
-    let first_write: Mutex<bool> = Mutex::new(true);
-    let folder = Path::new(&ref_marker_file).parent().unwrap();
+    let mut checkpoint = Checkpoint::open(
+        &command_params,
+        !command_params.out_file_name.is_empty() && command_params.output_format != OutputFormat::Json,
+    );
+    let mut first_write = !checkpoint.resumed();
+    if search_db.model().is_some(){
+        info!("{}",LEARNED_INFO_HELP);
+    }
+    //Results are collected in input order and written once at least
+    //INTERMEDIATE_WRITE_COUNT queries are finished, so the output does not
+    //depend on the number of threads.
+    let grouping = GenomeGrouping::from_params(&command_params);
+    let mut anis = vec![];
+    let mut finished = vec![];
     for query_file in command_params.query_files.iter() {
+        //Fasta queries are named by their file, so they can be skipped before sketching.
+        if !command_params.queries_are_sketch
+            && !command_params.individual_contig_q
+            && checkpoint.is_done(query_file)
+        {
+            continue;
+        }
         let query_params;
         let query_sketches;
         if command_params.queries_are_sketch {
             (query_params, query_sketches) =
                 file_io::sketches_from_sketch(&vec![query_file.clone()]);
-            if query_params != sketch_params && !query_file.contains("markers.bin") {
+            if query_params != *sketch_params && !query_file.contains("markers.bin") {
                 warn!("Query sketch parameters for {} not equal to reference sketch parameters; no ANI calculated", query_file);
             }
+        } else if let Some(grouping) = &grouping {
+            query_sketches = file_io::fastx_to_grouped_sketches(
+                &vec![query_file.clone()],
+                grouping,
+                sketch_params,
+                true,
+            );
         } else if command_params.individual_contig_q {
             query_sketches = file_io::fastx_to_multiple_sketch_rewrite(
                 &vec![query_file.clone()],
-                &sketch_params,
+                sketch_params,
                 true,
             );
         } else {
             query_sketches =
-                file_io::fastx_to_sketches(&vec![query_file.clone()], &sketch_params, true);
+                file_io::fastx_to_sketches(&vec![query_file.clone()], sketch_params, true);
         }
+        let query_sketches = query_sketches
+            .into_iter()
+            .map(|x| (checkpoint::query_name(&x, command_params.individual_contig_q), x))
+            .filter(|x| !checkpoint.is_done(&x.0))
+            .collect::<Vec<(String, Sketch)>>();
 
-        if !query_sketches.is_empty() {
-            let is = 0..query_sketches.len();
-            is.into_par_iter().for_each(|i| {
-                let query_sketch = &query_sketches[i];
-                let refs_to_try;
-                if !command_params.screen {
-                    let refs_to_try_mutex: Mutex<Vec<&String>> = Mutex::new(vec![]);
-                    let js = 0..ref_sketches.len();
-                    js.into_par_iter().for_each(|j| {
-                        let ref_sketch = &ref_sketches[j];
-                        if chain::check_markers_quickly(query_sketch, ref_sketch, screen_val) {
-                            let mut lock = refs_to_try_mutex.lock().unwrap();
-                            lock.push(&ref_sketches[j].file_name);
-                        }
-                    });
-                    refs_to_try = refs_to_try_mutex.into_inner().unwrap();
-                } else {
-                    refs_to_try = screen::screen_refs_filenames(
-                        screen_val,
-                        &kmer_to_sketch,
-                        query_sketch,
-                        &sketch_params,
-                        &ref_sketches,
-                    );
-                }
-                debug!("Refs to try {}", refs_to_try.len());
-                let js = 0..refs_to_try.len();
-                js.into_par_iter().for_each(|j| {
-                    let original_file = &refs_to_try[j];
-                    let ref_sketch;
-                    if !command_params.keep_refs {
-                        let sketch_file = folder.join(
-                            Path::new(&format!("{}.sketch", original_file))
-                                .file_name()
-                                .unwrap(),
-                        );
-                        let (_sketch_params_ref, ref_sketch_new) = file_io::sketches_from_sketch(
-                            &vec![sketch_file.to_str().unwrap().to_string()],
-                        );
-                        ref_sketch = ref_sketch_new;
-                        let map_params = chain::map_params_from_sketch(
-                            &ref_sketch[0],
-                            sketch_params.use_aa,
-                            &command_params,
-                        );
-                        let ani_res;
-                        if map_params != MapParams::default() {
-                            ani_res = chain::chain_seeds(&ref_sketch[0], query_sketch, map_params);
-                        } else {
-                            ani_res = AniEstResult::default();
-                        }
-                        if ani_res.ani > 0.5 {
-                            let mut locked = anis.lock().unwrap();
-                            locked.push(ani_res);
-                        }
-                    } else {
-                        let mut contains = false;
-                        {
-                            let read_table = ref_sketches_used.read().unwrap();
-                            if read_table.contains_key(original_file) {
-                                contains = true;
-                            }
-                        }
-                        if contains {
-                            let read_table = ref_sketches_used.read().unwrap();
-                            let ref_sketch: &Vec<_> = &read_table[original_file];
-                            let map_params = chain::map_params_from_sketch(
-                                &ref_sketch[0],
-                                sketch_params.use_aa,
-                                &command_params,
-                            );
-                            let ani_res;
-                            if map_params != MapParams::default() {
-                                ani_res =
-                                    chain::chain_seeds(&ref_sketch[0], query_sketch, map_params);
-                            } else {
-                                ani_res = AniEstResult::default();
-                            }
-                            if ani_res.ani > 0.5 {
-                                let mut locked = anis.lock().unwrap();
-                                locked.push(ani_res);
-                            }
-                        } else {
-                            let sketch_file = folder.join(
-                                Path::new(&format!("{}.sketch", original_file))
-                                    .file_name()
-                                    .unwrap(),
-                            );
-                            let (_sketch_params_ref, ref_sketch) = file_io::sketches_from_sketch(
-                                &vec![sketch_file.to_str().unwrap().to_string()],
-                            );
-
-                            let map_params = chain::map_params_from_sketch(
-                                &ref_sketch[0],
-                                sketch_params.use_aa,
-                                &command_params,
-                            );
-                            let ani_res;
-                            if map_params != MapParams::default() {
-                                ani_res =
-                                    chain::chain_seeds(&ref_sketch[0], query_sketch, map_params);
-                            } else {
-                                ani_res = AniEstResult::default();
-                            }
-
-                            {
-                                let mut write_table = ref_sketches_used.write().unwrap();
-                                write_table.insert(original_file.clone(), ref_sketch);
-                            }
-
-                            if ani_res.ani > 0.5 {
-                                {
-                                    let mut locked = anis.lock().unwrap();
-                                    locked.push(ani_res);
-                                }
-                            }
-                        }
+        for chunk in query_sketches.chunks(INTERMEDIATE_WRITE_COUNT) {
+            let chunk_anis = chunk
+                .par_iter()
+                .map(|(_, query_sketch)| {
+                    let mut query_anis = search_db.search_query(query_sketch, &command_params);
+                    search_db.apply_model(&mut query_anis);
+                    let c;
+                    {
+                        let mut locked = counter.lock().unwrap();
+                        *locked += 1;
+                        c = *locked
                     }
-                });
-
-                let c;
-                {
-                    let mut locked = counter.lock().unwrap();
-                    *locked += 1;
-                    c = *locked
-                }
-                if c % 100 == 0 && c != 0 {
-                    info!("{} query sequences processed.", c);
-                    if c % INTERMEDIATE_WRITE_COUNT == 0 && c != 0{
-                        info!("Writing results for {} query sequences.", INTERMEDIATE_WRITE_COUNT);
-                        let moved_anis: Vec<AniEstResult>;
-                        {
-                        let mut locked = anis.lock().unwrap();
-                        moved_anis = std::mem::take(&mut locked);
-                        }
-                        let mut fw = first_write.lock().unwrap();
-                        file_io::write_query_ref_list(
-                            &moved_anis,
-                            &command_params.out_file_name,
-                            command_params.max_results,
-                            sketch_params.use_aa,
-                            command_params.est_ci,
-                            command_params.detailed_out,
-                            !*fw
-                        );
-                        if *fw == true{
-                            *fw = false;
-                        }
+                    if c % 100 == 0 && c != 0 {
+                        info!("{} query sequences processed.", c);
                     }
-                }
-            });
+                    query_anis
+                })
+                .collect::<Vec<Vec<AniEstResult>>>();
+            for ((query_name, _), query_anis) in chunk.iter().zip(chunk_anis) {
+                anis.extend(query_anis);
+                finished.push(query_name.clone());
+            }
+            if finished.len() >= INTERMEDIATE_WRITE_COUNT && command_params.output_format != OutputFormat::Json{
+                info!("Writing results for {} query sequences.", finished.len());
+                file_io::write_query_ref_list(
+                    &anis,
+                    &command_params.out_file_name,
+                    command_params.max_results,
+                    sketch_params.use_aa,
+                    command_params.est_ci,
+                    command_params.detailed_out,
+                    command_params.output_format,
+                    !first_write
+                );
+                checkpoint.record(&finished);
+                first_write = false;
+                anis.clear();
+                finished.clear();
+            }
         }
     }
     if command_params.keep_refs{
-        info!("{} references kept in memory for --keep-refs", ref_sketches_used.read().unwrap().len());
-    }
-    let mut anis = anis.into_inner().unwrap();
-    let learned_ani;
-    if !command_params.learned_ani_cmd{
-        learned_ani = regression::use_learned_ani(sketch_params.c, command_params.individual_contig_q, command_params.individual_contig_r, command_params.robust, command_params.median);
-    }
-    else{
-        learned_ani = command_params.learned_ani;
-    }
-    let model_opt = regression::get_model(sketch_params.c, learned_ani);
-    if model_opt.is_some(){
-        info!("{}",LEARNED_INFO_HELP);
-        let model = model_opt.as_ref().unwrap();
-        for ani in anis.iter_mut(){
-            regression::predict_from_ani_res(ani, &model);
-        }
+        info!("{} references kept in memory for --keep-refs", search_db.num_kept_refs());
     }
     file_io::write_query_ref_list(
         &anis,
@@ -264,7 +128,289 @@ pub fn search(command_params: CommandParams) {
         sketch_params.use_aa,
         command_params.est_ci,
         command_params.detailed_out,
-        !*first_write.lock().unwrap()
+        command_params.output_format,
+        !first_write
     );
+    checkpoint.record(&finished);
+    checkpoint.finish();
     info!("Searching time: {}", now.elapsed().as_secs_f32());
 }
+
+//The reference side of `skani search`, loaded once. `skani serve` keeps one
+//of these alive between queries.
+pub struct SearchDb {
+    folder: PathBuf,
+    ref_db: RefDatabase,
+    kmer_to_sketch: Box<dyn MarkerIndex + Sync + Send>,
+    screen_val: f64,
+    model: Option<gbdt::gradient_boost::GBDT>,
+    ref_sketches_used: RwLock<FxHashMap<usize, Vec<Sketch>>>,
+}
+
+impl SearchDb {
+    //command_params.ref_files are the files in the sketch folder.
+    pub fn load(command_params: &CommandParams) -> SearchDb {
+        let now = Instant::now();
+        let mut ref_marker_file = "";
+        for file in command_params.ref_files.iter() {
+            if Path::new(file)
+                .file_name()
+                .is_some_and(|x| x.to_str().unwrap_or("").starts_with(PACKED_DB_PREFIX))
+            {
+                continue;
+            }
+            if !file.contains(".sketch") && !file.contains("marker") {
+                warn!(
+                    "{} does not have .sketch as an extension; skipping file",
+                    file
+                );
+            } else if file.contains("markers.bin") {
+                ref_marker_file = file;
+            }
+        }
+
+        if ref_marker_file.is_empty() {
+            //error!("No sketch files found in the folder. Sketch files must be generated by `skani sketch` and have the .sketch extension.");
+            error!("markers.bin not found in the folder. Ensure that the folder was generated by `skani sketch`.");
+            std::process::exit(1)
+        }
+
+        let folder = Path::new(&ref_marker_file).parent().unwrap();
+        let ref_db;
+        if db::packed_db_exists(folder) {
+            info!("Packed database found; memory-mapping it instead of loading markers.bin");
+            ref_db = match db::PackedDb::open(folder) {
+                Ok(packed_db) => RefDatabase::Packed(packed_db),
+                Err(e) => {
+                    error!("{}. Exiting.", e);
+                    std::process::exit(1)
+                }
+            };
+        } else {
+            let (sketch_params, ref_sketches, sketch_files) =
+                file_io::marker_sketches_from_marker_file(ref_marker_file);
+            ref_db = RefDatabase::Sketches(sketch_params, ref_sketches, sketch_files);
+        }
+        let screen_val;
+        if command_params.screen_val == 0. {
+            if ref_db.sketch_params().use_aa {
+                screen_val = SEARCH_AAI_CUTOFF_DEFAULT;
+            } else {
+                screen_val = SEARCH_ANI_CUTOFF_DEFAULT;
+            }
+        } else {
+            screen_val = command_params.screen_val;
+        }
+
+        info!("Loading markers time: {}", now.elapsed().as_secs_f32());
+        let kmer_to_sketch: Box<dyn MarkerIndex + Sync + Send>;
+        if command_params.screen {
+            let now = Instant::now();
+            let index_file = db::marker_index_file(folder);
+            let mut persisted_index = None;
+            if Path::new(&index_file).exists() {
+                let fingerprint =
+                    db::marker_index_fingerprint((0..ref_db.len()).map(|i| ref_db.file_name(i)));
+                match db::MarkerIndexFile::open(&index_file, ref_db.len(), fingerprint) {
+                    Ok(marker_index) => persisted_index = Some(marker_index),
+                    Err(e) => warn!("{}; generating the marker hash table instead.", e),
+                }
+            }
+            if let Some(marker_index) = persisted_index {
+                info!("Full index option detected; using {}", index_file);
+                kmer_to_sketch = Box::new(marker_index);
+            } else {
+                info!("Full index option detected; generating marker hash table");
+                kmer_to_sketch = Box::new(ref_db.kmer_to_sketch());
+            }
+            info!("Full indexing time: {}", now.elapsed().as_secs_f32());
+        } else {
+            kmer_to_sketch = Box::new(KmerToSketch::default());
+        }
+
+        let learned_ani;
+        if !command_params.learned_ani_cmd{
+            learned_ani = regression::use_learned_ani(ref_db.sketch_params().c, command_params.individual_contig_q, command_params.individual_contig_r, command_params.robust, command_params.median);
+        }
+        else{
+            learned_ani = command_params.learned_ani;
+        }
+        let model = regression::get_model(ref_db.sketch_params().c, learned_ani);
+
+        SearchDb {
+            folder: folder.to_path_buf(),
+            ref_db,
+            kmer_to_sketch,
+            screen_val,
+            model,
+            ref_sketches_used: RwLock::new(FxHashMap::default()),
+        }
+    }
+
+    pub fn sketch_params(&self) -> &SketchParams {
+        self.ref_db.sketch_params()
+    }
+
+    pub fn num_kept_refs(&self) -> usize {
+        self.ref_sketches_used.read().unwrap().len()
+    }
+
+    pub fn model(&self) -> Option<&gbdt::gradient_boost::GBDT> {
+        self.model.as_ref()
+    }
+
+    pub fn apply_model(&self, anis: &mut [AniEstResult]) {
+        if let Some(model) = self.model.as_ref() {
+            for ani in anis.iter_mut() {
+                regression::predict_from_ani_res(ani, model);
+            }
+        }
+    }
+
+    //ANI of one query against the references passing the marker screen. The
+    //regression model is not applied here; see apply_model.
+    pub fn search_query(&self, query_sketch: &Sketch, command_params: &CommandParams) -> Vec<AniEstResult> {
+        let ref_db = &self.ref_db;
+        let sketch_params = self.sketch_params();
+        let screen_val = self.screen_val;
+        let anis: Mutex<Vec<AniEstResult>> = Mutex::new(vec![]);
+        let refs_to_try;
+        if !command_params.screen {
+            let refs_to_try_mutex: Mutex<Vec<usize>> = Mutex::new(vec![]);
+            let js = 0..ref_db.len();
+            js.into_par_iter().for_each(|j| {
+                if ref_db.check_markers_quickly(j, query_sketch, screen_val) {
+                    let mut lock = refs_to_try_mutex.lock().unwrap();
+                    lock.push(j);
+                }
+            });
+            refs_to_try = refs_to_try_mutex.into_inner().unwrap();
+        } else {
+            refs_to_try = screen::screen_refs_with(
+                screen_val,
+                &*self.kmer_to_sketch,
+                query_sketch,
+                sketch_params,
+                |j| ref_db.num_markers(j),
+            );
+        }
+        debug!("Refs to try {}", refs_to_try.len());
+
+        let chain_with = |ref_sketch: &Sketch| {
+            let map_params = chain::map_params_from_sketch(
+                ref_sketch,
+                sketch_params.use_aa,
+                command_params,
+            );
+            let ani_res;
+            if map_params != MapParams::default() {
+                ani_res = chain::chain_seeds(ref_sketch, query_sketch, map_params);
+            } else {
+                ani_res = AniEstResult::default();
+            }
+            if ani_res.ani > 0.5 {
+                let mut locked = anis.lock().unwrap();
+                locked.push(ani_res);
+            }
+        };
+
+        let js = 0..refs_to_try.len();
+        js.into_par_iter().for_each(|j| {
+            let ref_index = refs_to_try[j];
+            if !command_params.keep_refs {
+                let ref_sketch = ref_db.load_sketch(ref_index, &self.folder);
+                //The sketch file is missing or corrupted; an error was already logged.
+                if ref_sketch.is_empty() {
+                    return;
+                }
+                chain_with(&ref_sketch[0]);
+            } else {
+                {
+                    let read_table = self.ref_sketches_used.read().unwrap();
+                    if let Some(ref_sketch) = read_table.get(&ref_index) {
+                        chain_with(&ref_sketch[0]);
+                        return;
+                    }
+                }
+                let ref_sketch = ref_db.load_sketch(ref_index, &self.folder);
+                if ref_sketch.is_empty() {
+                    return;
+                }
+                chain_with(&ref_sketch[0]);
+                let mut write_table = self.ref_sketches_used.write().unwrap();
+                write_table.insert(ref_index, ref_sketch);
+            }
+        });
+        anis.into_inner().unwrap()
+    }
+}
+
+//References for `skani search`: the marker sketches from markers.bin (full
+//sketches are read from the .sketch files) or a packed database. The .sketch
+//file names are None for markers.bin files written by older versions.
+enum RefDatabase {
+    Sketches(SketchParams, Vec<Sketch>, Option<Vec<String>>),
+    Packed(db::PackedDb),
+}
+
+impl RefDatabase {
+    fn sketch_params(&self) -> &SketchParams {
+        match self {
+            RefDatabase::Sketches(sketch_params, _, _) => sketch_params,
+            RefDatabase::Packed(packed_db) => &packed_db.sketch_params,
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            RefDatabase::Sketches(_, ref_sketches, _) => ref_sketches.len(),
+            RefDatabase::Packed(packed_db) => packed_db.len(),
+        }
+    }
+
+    fn file_name(&self, i: usize) -> &String {
+        match self {
+            RefDatabase::Sketches(_, ref_sketches, _) => &ref_sketches[i].file_name,
+            RefDatabase::Packed(packed_db) => &packed_db.genomes[i].file_name,
+        }
+    }
+
+    fn num_markers(&self, i: usize) -> usize {
+        match self {
+            RefDatabase::Sketches(_, ref_sketches, _) => ref_sketches[i].marker_seeds.len(),
+            RefDatabase::Packed(packed_db) => packed_db.num_markers(i),
+        }
+    }
+
+    fn check_markers_quickly(&self, i: usize, query_sketch: &Sketch, screen_val: f64) -> bool {
+        match self {
+            RefDatabase::Sketches(_, ref_sketches, _) => {
+                chain::check_markers_quickly(query_sketch, &ref_sketches[i], screen_val)
+            }
+            RefDatabase::Packed(packed_db) => {
+                packed_db.check_markers_quickly(i, query_sketch, screen_val)
+            }
+        }
+    }
+
+    fn kmer_to_sketch(&self) -> KmerToSketch {
+        match self {
+            RefDatabase::Sketches(_, ref_sketches, _) => screen::kmer_to_sketch_from_refs(ref_sketches),
+            RefDatabase::Packed(packed_db) => packed_db.kmer_to_sketch(),
+        }
+    }
+
+    //Empty if the .sketch file could not be read.
+    fn load_sketch(&self, i: usize, folder: &Path) -> Vec<Sketch> {
+        match self {
+            RefDatabase::Sketches(_, ref_sketches, sketch_files) => {
+                let sketch_file = match sketch_files {
+                    Some(sketch_files) => folder.join(&sketch_files[i]).to_str().unwrap().to_string(),
+                    None => sketch::existing_sketch_file_name(folder.to_str().unwrap(), &ref_sketches[i]),
+                };
+                file_io::sketches_from_sketch(&vec![sketch_file]).1
+            }
+            RefDatabase::Packed(packed_db) => vec![packed_db.mapped_sketch(i)],
+        }
+    }
+}
@@ -0,0 +1,176 @@
+use crate::file_io;
+use crate::params::*;
+use crate::search::SearchDb;
+use crate::types::*;
+use log::*;
+use std::fs;
+use std::io::{Cursor, Read, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::{mpsc, Mutex};
+use std::time::{Duration, Instant};
+
+//Name used in the Query_file column for fasta/fastq queries sent to the server.
+pub const SERVE_QUERY_NAME: &str = "query";
+
+pub fn serve(command_params: CommandParams) {
+    let search_db = SearchDb::load(&command_params);
+
+    let socket_path = Path::new(&command_params.socket_path);
+    if let Ok(metadata) = fs::symlink_metadata(socket_path) {
+        if metadata.file_type().is_socket() {
+            if let Err(e) = fs::remove_file(socket_path) {
+                error!("Could not remove old socket {}: {}. Exiting.", command_params.socket_path, e);
+                std::process::exit(1)
+            }
+        } else {
+            error!("{} exists and is not a socket. Exiting.", command_params.socket_path);
+            std::process::exit(1)
+        }
+    }
+    let listener = match UnixListener::bind(socket_path) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Could not listen on {}: {}. Exiting.", command_params.socket_path, e);
+            std::process::exit(1)
+        }
+    };
+    info!("Listening on {}", command_params.socket_path);
+
+    //Connections are handled by one worker per thread (-t), so a slow client
+    //does not hold up the others. New connections wait in the listen queue while
+    //all workers are busy. Clients that send or read nothing for
+    //SERVE_TIMEOUT_SECS are dropped.
+    let timeout = Some(Duration::from_secs(SERVE_TIMEOUT_SECS));
+    let (sender, receiver) = mpsc::sync_channel::<UnixStream>(0);
+    let receiver = Mutex::new(receiver);
+    std::thread::scope(|scope| {
+        for _ in 0..rayon::current_num_threads() {
+            scope.spawn(|| loop {
+                let next = receiver.lock().unwrap().recv();
+                let stream = match next {
+                    Ok(stream) => stream,
+                    Err(_) => return,
+                };
+                let now = Instant::now();
+                if let Err(e) = handle_connection(&search_db, &command_params, stream, SERVE_MAX_QUERY_BYTES) {
+                    warn!("Connection failed: {}", e);
+                }
+                debug!("Query time: {}", now.elapsed().as_secs_f32());
+            });
+        }
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("Connection failed: {}", e);
+                    continue;
+                }
+            };
+            if let Err(e) = stream.set_read_timeout(timeout).and_then(|_| stream.set_write_timeout(timeout)) {
+                warn!("Connection failed: {}", e);
+                continue;
+            }
+            if sender.send(stream).is_err() {
+                break;
+            }
+        }
+        drop(sender);
+    });
+}
+
+//Reads one query until the client closes its write side, then writes back the
+//`skani search` table, or a single "ERROR: ..." line if the query was invalid
+//or longer than `max_query_bytes`.
+pub fn handle_connection<S: Read + Write>(
+    search_db: &SearchDb,
+    command_params: &CommandParams,
+    mut stream: S,
+    max_query_bytes: usize,
+) -> std::io::Result<()> {
+    let mut query = vec![];
+    (&mut stream).take(max_query_bytes as u64 + 1).read_to_end(&mut query)?;
+    let answer = if query.len() > max_query_bytes {
+        Err(format!("query is larger than {} bytes", max_query_bytes))
+    } else {
+        answer_query(search_db, command_params, query)
+    };
+    let mut response = vec![];
+    match answer {
+        Ok(anis) => file_io::write_query_ref_list_to(
+            &mut response,
+            &anis,
+            command_params.max_results,
+            search_db.sketch_params().use_aa,
+            command_params.est_ci,
+            command_params.detailed_out,
+            command_params.output_format,
+            false,
+        ),
+        Err(e) => {
+            debug!("Bad query: {}", e);
+            writeln!(&mut response, "ERROR: {}", e)?;
+        }
+    }
+    stream.write_all(&response)?;
+    stream.flush()
+}
+
+fn answer_query(
+    search_db: &SearchDb,
+    command_params: &CommandParams,
+    query: Vec<u8>,
+) -> Result<Vec<AniEstResult>, String> {
+    let sketch_params = search_db.sketch_params();
+    let query_sketches;
+    if query.starts_with(SKETCH_MAGIC) {
+        let (query_params, query_sketch) =
+            file_io::sketch_from_bytes(SERVE_QUERY_NAME, &query).map_err(|e| e.to_string())?;
+        if query_params != *sketch_params {
+            return Err(
+                "query sketch parameters not equal to reference sketch parameters".to_string(),
+            );
+        }
+        query_sketches = vec![query_sketch];
+    } else {
+        let mut reader = needletail::parse_fastx_reader(Cursor::new(query))
+            .map_err(|e| {
+                format!(
+                    "query is not a fasta/fastq or a .sketch file from skani {} or later ({})",
+                    env!("CARGO_PKG_VERSION"),
+                    e
+                )
+            })?;
+        let mut records = vec![];
+        while let Some(record) = reader.next() {
+            let record = record.map_err(|e| format!("invalid query record ({})", e))?;
+            records.push((record.id().to_vec(), record.seq().to_vec()));
+        }
+        if command_params.individual_contig_q {
+            query_sketches = file_io::records_to_multiple_sketches(
+                SERVE_QUERY_NAME,
+                records,
+                sketch_params,
+                true,
+            );
+        } else {
+            query_sketches = file_io::records_to_sketch(SERVE_QUERY_NAME, records, sketch_params, true)
+                .into_iter()
+                .collect();
+        }
+        if query_sketches.is_empty() {
+            return Err(format!(
+                "query has no sequences of length >= {}",
+                MIN_LENGTH_CONTIG
+            ));
+        }
+    }
+
+    let mut anis = vec![];
+    for query_sketch in query_sketches.iter() {
+        anis.extend(search_db.search_query(query_sketch, command_params));
+    }
+    search_db.apply_model(&mut anis);
+    Ok(anis)
+}
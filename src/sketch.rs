@@ -1,10 +1,11 @@
+use crate::db;
 use crate::file_io;
+use crate::grouping::GenomeGrouping;
 use crate::params::*;
 use crate::types::*;
+use fxhash::FxHashSet;
 use log::*;
 use rayon::prelude::*;
-use std::fs::File;
-use std::io::BufWriter;
 
 use std::path::Path;
 use std::sync::Mutex;
@@ -15,27 +16,222 @@ pub fn sketch(command_params: CommandParams, sketch_params: SketchParams) {
     info!("Sketching files...");
     let p = command_params.out_file_name.to_string();
     if Path::new(&p).exists() {
-        error!("Output directory exists; output directory must not be an existing directory. Exiting.");
+        error!("Output directory exists; output directory must not be an existing directory. Use --append to add to an existing sketch folder. Exiting.");
         std::process::exit(1);
     }
     std::fs::create_dir_all(p).unwrap();
 
-    let num_iters = command_params.ref_files.len();
+    let (markers, sketch_files) = sketch_and_write(&command_params.ref_files, &command_params, &sketch_params);
+    write_markers(&command_params.out_file_name, &sketch_params, &markers, &sketch_files);
+    if command_params.packed {
+        write_packed(&command_params.out_file_name, &sketch_params, &markers, &sketch_files);
+    }
+    if command_params.marker_index {
+        write_marker_index(&command_params.out_file_name, &markers);
+    }
+    info!("Sketching time: {}", now.elapsed().as_secs_f32());
+    
+}
+
+//Add new genomes to a folder made by `skani sketch`. Genomes whose file name
+//is already in markers.bin are skipped.
+pub fn append(command_params: CommandParams, sketch_params: SketchParams) {
+    let now = Instant::now();
+    info!("Appending to sketch folder {}...", command_params.out_file_name);
+    let marker_file = format!("{}/markers.bin", &command_params.out_file_name);
+    if !Path::new(&marker_file).exists() {
+        error!("{} not found. --append requires an output folder generated by `skani sketch`. Exiting.", marker_file);
+        std::process::exit(1);
+    }
+    let (db_params, mut markers, mut sketch_files) = load_markers(&command_params.out_file_name);
+
+    //SketchParams::default() means no sketch parameters were given on the
+    //command line, so use the ones from the database.
+    let sketch_params = if sketch_params == SketchParams::default() {
+        db_params
+    } else if sketch_params != db_params {
+        error!(
+            "Sketch parameters (c = {}, m = {}, k = {}) do not match the parameters of {} (c = {}, m = {}, k = {}). Exiting.",
+            sketch_params.c,
+            sketch_params.marker_c,
+            sketch_params.k,
+            command_params.out_file_name,
+            db_params.c,
+            db_params.marker_c,
+            db_params.k
+        );
+        std::process::exit(1);
+    } else {
+        sketch_params
+    };
+
+    let existing = markers
+        .iter()
+        .map(|x| file_io::base_name(&x.file_name).to_string())
+        .collect::<FxHashSet<String>>();
+    let mut new_files = vec![];
+    for file in command_params.ref_files.iter() {
+        if existing.contains(file_io::base_name(file)) {
+            warn!("{} is already in {}; skipping.", file, command_params.out_file_name);
+        } else {
+            new_files.push(file.clone());
+        }
+    }
+    if new_files.is_empty() {
+        info!("No new files to sketch.");
+        return;
+    }
+
+    let (new_markers, new_sketch_files) = sketch_and_write(&new_files, &command_params, &sketch_params);
+    if new_markers.is_empty() {
+        info!("No new sketches added.");
+        return;
+    }
+    info!("{} new sketches added.", new_markers.len());
+    markers.extend(new_markers);
+    sketch_files.extend(new_sketch_files);
+    write_markers(&command_params.out_file_name, &sketch_params, &markers, &sketch_files);
+    if command_params.packed || db::packed_db_exists(Path::new(&command_params.out_file_name)) {
+        write_packed(&command_params.out_file_name, &sketch_params, &markers, &sketch_files);
+    }
+    if command_params.marker_index || Path::new(&db::marker_index_file(Path::new(&command_params.out_file_name))).exists() {
+        write_marker_index(&command_params.out_file_name, &markers);
+    }
+    info!("Sketching time: {}", now.elapsed().as_secs_f32());
+}
+
+//Remove genomes from a folder made by `skani sketch`. Genomes are given by
+//their fasta file (with or without the path) or their .sketch file name.
+pub fn remove(command_params: CommandParams) {
+    let now = Instant::now();
+    let out_folder = &command_params.out_file_name;
+    info!("Removing sketches from {}...", out_folder);
+    let marker_file = format!("{}/markers.bin", out_folder);
+    if !Path::new(&marker_file).exists() {
+        error!("{} not found. --remove requires an output folder generated by `skani sketch`. Exiting.", marker_file);
+        std::process::exit(1);
+    }
+    let (sketch_params, markers, sketch_files) = load_markers(out_folder);
+
+    let names = command_params
+        .ref_files
+        .iter()
+        .map(|x| {
+            let name = file_io::base_name(x);
+            name.strip_suffix(".sketch").unwrap_or(name).to_string()
+        })
+        .collect::<FxHashSet<String>>();
+    let mut found = FxHashSet::default();
+    let mut kept = vec![];
+    let mut kept_sketch_files = vec![];
+    let mut removed = 0;
+    for (marker, sketch_file) in markers.into_iter().zip(sketch_files) {
+        let name = file_io::base_name(&marker.file_name).to_string();
+        let individual_name = format!("{}_{}", marker.contig_order, name);
+        if !names.contains(&name) && !names.contains(&individual_name) {
+            kept.push(marker);
+            kept_sketch_files.push(sketch_file);
+            continue;
+        }
+        found.insert(name);
+        found.insert(individual_name);
+        let sketch_file = format!("{}/{}", out_folder, sketch_file);
+        if let Err(e) = std::fs::remove_file(&sketch_file) {
+            warn!("Could not delete {}: {}", sketch_file, e);
+        }
+        removed += 1;
+    }
+    for name in names.iter() {
+        if !found.contains(name) {
+            warn!("{} not found in {}.", name, out_folder);
+        }
+    }
+    if removed == 0 {
+        info!("No sketches removed.");
+        return;
+    }
+
+    write_markers(out_folder, &sketch_params, &kept, &kept_sketch_files);
+    if command_params.packed || db::packed_db_exists(Path::new(out_folder)) {
+        write_packed(out_folder, &sketch_params, &kept, &kept_sketch_files);
+    }
+    if command_params.marker_index || Path::new(&db::marker_index_file(Path::new(out_folder))).exists() {
+        write_marker_index(out_folder, &kept);
+    }
+    info!("{} sketches removed; {} left.", removed, kept.len());
+    info!("Removing time: {}", now.elapsed().as_secs_f32());
+}
+
+//Path of the .sketch file for a sketch in the output folder.
+pub fn sketch_file_name(out_folder: &str, sketch: &Sketch, individual_contig: bool) -> String {
+    let filename = file_io::base_name(&sketch.file_name);
+    if individual_contig {
+        format!("{}/{}_{}.sketch", out_folder, sketch.contig_order, filename)
+    } else {
+        format!("{}/{}.sketch", out_folder, filename)
+    }
+}
+
+//Older markers.bin files do not record the .sketch file of each genome, or
+//whether the folder was sketched with -i. Use the -i name only if the plain
+//one does not exist.
+pub fn existing_sketch_file_name(out_folder: &str, sketch: &Sketch) -> String {
+    let sketch_file = sketch_file_name(out_folder, sketch, false);
+    if Path::new(&sketch_file).exists() {
+        sketch_file
+    } else {
+        sketch_file_name(out_folder, sketch, true)
+    }
+}
+
+//Marker sketches of a folder and their .sketch files relative to the folder.
+fn load_markers(out_folder: &str) -> (SketchParams, Vec<Sketch>, Vec<String>) {
+    let marker_file = format!("{}/markers.bin", out_folder);
+    let (sketch_params, markers, sketch_files) = file_io::marker_sketches_from_marker_file(&marker_file);
+    let sketch_files = sketch_files.unwrap_or_else(|| {
+        markers
+            .iter()
+            .map(|x| file_io::base_name(&existing_sketch_file_name(out_folder, x)).to_string())
+            .collect()
+    });
+    (sketch_params, markers, sketch_files)
+}
+
+//Sketches the files, writes their .sketch files to the output folder and
+//returns the marker sketches and the names of their .sketch files.
+fn sketch_and_write(
+    ref_files: &[String],
+    command_params: &CommandParams,
+    sketch_params: &SketchParams,
+) -> (Vec<Sketch>, Vec<String>) {
+    let num_iters = ref_files.len();
     let counter: Mutex<usize> = Mutex::new(0);
-    let marker_sketches: Mutex<Vec<Sketch>> = Mutex::new(vec![]);
+    let marker_sketches: Mutex<Vec<(Sketch, String)>> = Mutex::new(vec![]);
+    let grouping = GenomeGrouping::from_params(command_params);
+    //Genomes from --genome-map/--genome-regex are named by their id, so the same
+    //id in two files would write one .sketch file twice.
+    let sketch_names: Mutex<FxHashSet<String>> = Mutex::new(FxHashSet::default());
     (0..num_iters).into_par_iter().for_each(|i| {
         let ref_sketches;
-        if command_params.individual_contig_r {
+        if let Some(grouping) = &grouping {
+            ref_sketches = file_io::fastx_to_grouped_sketches(
+                &vec![ref_files[i].clone()],
+                grouping,
+                sketch_params,
+                true,
+            );
+        }
+        else if command_params.individual_contig_r {
             ref_sketches = file_io::fastx_to_multiple_sketch_rewrite(
-                &vec![command_params.ref_files[i].clone()],
-                &sketch_params,
+                &vec![ref_files[i].clone()],
+                sketch_params,
                 true,
         );
         }
         else{
             ref_sketches = file_io::fastx_to_sketches(
-                &vec![command_params.ref_files[i].clone()],
-                &sketch_params,
+                &vec![ref_files[i].clone()],
+                sketch_params,
                 true,);
         }
         let marker_ref_sketches = ref_sketches
@@ -45,33 +241,29 @@ pub fn sketch(command_params: CommandParams, sketch_params: SketchParams) {
         (0..ref_sketches.len()).into_par_iter().for_each(|j|{
             let sketch = &ref_sketches[j];
             let marker_sketch = &marker_ref_sketches[j];
-            let path = Path::new(&sketch.file_name);
-            let filename = path.file_name().unwrap().to_str().unwrap();
-            let sketch_name;
-            if command_params.individual_contig_r{
-                sketch_name = format!(
-                    "{}/{}_{}.sketch",
-                    &command_params.out_file_name, j, filename
-                );
-            }
-            else{
-                sketch_name = format!(
-                    "{}/{}.sketch",
-                    &command_params.out_file_name, filename
+            let sketch_name = sketch_file_name(
+                &command_params.out_file_name,
+                sketch,
+                command_params.individual_contig_r,
+            );
+            if !sketch_names.lock().unwrap().insert(sketch_name.clone()) {
+                error!(
+                    "More than one genome would be written to {}; each genome's records must be in one file. Exiting.",
+                    sketch_name
                 );
+                std::process::exit(1);
             }
-            let mut file_bin = BufWriter::new(
-                File::create(sketch_name)
-                .unwrap(),
-            );
 
             trace!("{} compress factor", sketch.total_sequence_length / sketch.kmer_seeds_k.as_ref().unwrap().len());
             trace!("{} marker compress factor", sketch.total_sequence_length / sketch.marker_seeds.len());
 
-            bincode::serialize_into(&mut file_bin, &(&sketch_params, sketch)).unwrap();
+            if let Err(e) = file_io::write_sketch_file(&sketch_name, sketch_params, sketch) {
+                error!("Could not write {}: {}", sketch_name, e);
+                std::process::exit(1);
+            }
 
             let mut locked = marker_sketches.lock().unwrap();
-            locked.push(marker_sketch.clone());
+            locked.push((marker_sketch.clone(), file_io::base_name(&sketch_name).to_string()));
             let mut locked = counter.lock().unwrap();
             *locked += 1;
             if *locked % 100 == 0 && *locked != 0 {
@@ -79,15 +271,46 @@ pub fn sketch(command_params: CommandParams, sketch_params: SketchParams) {
             }
         });
     });
-    let mut file_bin_marker = BufWriter::new(
-        File::create(format!(
-            "{}/markers.bin",
-            &command_params.out_file_name
-        ))
-        .unwrap(),
-    );
-    let markers = marker_sketches.into_inner().unwrap();
-    bincode::serialize_into(&mut file_bin_marker, &(&sketch_params, markers)).unwrap();
-    info!("Sketching time: {}", now.elapsed().as_secs_f32());
-    
+    marker_sketches.into_inner().unwrap().into_iter().unzip()
+}
+
+//Write to a temporary file first so an interrupted run does not leave
+//a broken markers.bin behind. The temporary name must not contain "marker"
+//or ".sketch", otherwise `skani search` would try to load it.
+fn write_markers(out_folder: &str, sketch_params: &SketchParams, markers: &[Sketch], sketch_files: &[String]) {
+    let marker_file = format!("{}/markers.bin", out_folder);
+    let tmp_file = format!("{}/.skani_write.tmp", out_folder);
+    if let Err(e) = file_io::write_marker_file(&tmp_file, sketch_params, markers, sketch_files) {
+        error!("Could not write {}: {}", marker_file, e);
+        std::process::exit(1);
+    }
+    if let Err(e) = std::fs::rename(&tmp_file, &marker_file) {
+        error!("Could not write {}: {}", marker_file, e);
+        std::process::exit(1);
+    }
+}
+
+fn write_packed(out_folder: &str, sketch_params: &SketchParams, markers: &[Sketch], sketch_files: &[String]) {
+    let now = Instant::now();
+    info!("Writing packed database...");
+    let sketch_files = sketch_files
+        .iter()
+        .map(|x| format!("{}/{}", out_folder, x))
+        .collect::<Vec<String>>();
+    if let Err(e) = db::write_packed_db(Path::new(out_folder), sketch_params, markers, &sketch_files) {
+        error!("Could not write packed database: {}. Removing the packed database; `skani search` will use the .sketch files.", e);
+        db::remove_packed_db(Path::new(out_folder)).unwrap();
+        std::process::exit(1);
+    }
+    info!("Packed database time: {}", now.elapsed().as_secs_f32());
+}
+
+fn write_marker_index(out_folder: &str, markers: &[Sketch]) {
+    let now = Instant::now();
+    info!("Writing marker index...");
+    if let Err(e) = db::write_marker_index(Path::new(out_folder), markers) {
+        error!("Could not write marker index: {}. Exiting.", e);
+        std::process::exit(1);
+    }
+    info!("Marker index time: {}", now.elapsed().as_secs_f32());
 }
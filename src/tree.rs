@@ -0,0 +1,208 @@
+use crate::params::*;
+use crate::types::*;
+use fxhash::FxHashMap;
+use log::*;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+//Dense 100 - ANI distances from triangle results. Pairs that were screened out
+//or have no ANI get `fill`.
+pub fn distance_matrix(
+    anis: &FxHashMap<usize, FxHashMap<usize, AniEstResult>>,
+    n: usize,
+    fill: f64,
+) -> Vec<Vec<f64>> {
+    let mut dist = vec![vec![fill; n]; n];
+    for (i, row) in dist.iter_mut().enumerate() {
+        row[i] = 0.;
+    }
+    for (i, row) in anis.iter() {
+        for (j, ani_res) in row.iter() {
+            if ani_res.ani == -1. || ani_res.ani.is_nan() {
+                continue;
+            }
+            let d = 100. - ani_res.ani as f64 * 100.;
+            dist[*i][*j] = d;
+            dist[*j][*i] = d;
+        }
+    }
+    dist
+}
+
+//Quotes names with characters that are reserved in Newick.
+pub fn newick_name(name: &str) -> String {
+    if name
+        .chars()
+        .any(|x| x.is_whitespace() || "()[]:;,'".contains(x))
+    {
+        format!("'{}'", name.replace('\'', "''"))
+    } else {
+        name.to_string()
+    }
+}
+
+//UPGMA with the nearest-neighbour chain algorithm; O(n^2) time. The tree is
+//rooted and ultrametric.
+pub fn upgma(mut dist: Vec<Vec<f64>>, names: &[String]) -> String {
+    let n = names.len();
+    if n == 1 {
+        return format!("{};", newick_name(&names[0]));
+    }
+    let mut subtrees = names.iter().map(|x| newick_name(x)).collect::<Vec<String>>();
+    let mut sizes = vec![1.; n];
+    let mut heights = vec![0.; n];
+    let mut active = vec![true; n];
+    let mut chain: Vec<usize> = vec![];
+    let mut num_active = n;
+    while num_active > 1 {
+        if chain.is_empty() {
+            chain.push(active.iter().position(|x| *x).unwrap());
+        }
+        let a = *chain.last().unwrap();
+        let prev = if chain.len() >= 2 {
+            Some(chain[chain.len() - 2])
+        } else {
+            None
+        };
+        //Ties go to the previous chain element so that the chain terminates.
+        let mut b = prev.unwrap_or(usize::MAX);
+        let mut best = prev.map(|x| dist[a][x]).unwrap_or(f64::INFINITY);
+        for x in 0..n {
+            if active[x] && x != a && dist[a][x] < best {
+                best = dist[a][x];
+                b = x;
+            }
+        }
+        if Some(b) != prev {
+            chain.push(b);
+            continue;
+        }
+        chain.pop();
+        chain.pop();
+
+        //Merged clusters keep the lower index, which is their first leaf, so
+        //children are written in input order.
+        let (a, b) = (usize::min(a, b), usize::max(a, b));
+        let height = dist[a][b] / 2.;
+        subtrees[a] = format!(
+            "({}:{:.4},{}:{:.4})",
+            subtrees[a],
+            f64::max(height - heights[a], 0.),
+            subtrees[b],
+            f64::max(height - heights[b], 0.)
+        );
+        subtrees[b] = String::new();
+        for x in 0..n {
+            if active[x] && x != a && x != b {
+                let d = (sizes[a] * dist[a][x] + sizes[b] * dist[b][x]) / (sizes[a] + sizes[b]);
+                dist[a][x] = d;
+                dist[x][a] = d;
+            }
+        }
+        sizes[a] += sizes[b];
+        heights[a] = height;
+        active[b] = false;
+        num_active -= 1;
+    }
+    let root = active.iter().position(|x| *x).unwrap();
+    format!("{};", subtrees[root])
+}
+
+//Saitou and Nei's neighbour-joining; O(n^3) time. The tree is unrooted and
+//written with a trifurcation at the last three nodes. Negative branch
+//lengths are set to 0.
+pub fn neighbor_joining(mut dist: Vec<Vec<f64>>, names: &[String]) -> String {
+    let n = names.len();
+    if n == 1 {
+        return format!("{};", newick_name(&names[0]));
+    }
+    let mut subtrees = names.iter().map(|x| newick_name(x)).collect::<Vec<String>>();
+    if n == 2 {
+        return format!(
+            "({}:{:.4},{}:{:.4});",
+            subtrees[0],
+            dist[0][1] / 2.,
+            subtrees[1],
+            dist[0][1] / 2.
+        );
+    }
+    let mut active = (0..n).collect::<Vec<usize>>();
+    let mut row_sums = (0..n).map(|i| dist[i].iter().sum::<f64>()).collect::<Vec<f64>>();
+    while active.len() > 3 {
+        let r = active.len() as f64;
+        let mut best = (f64::INFINITY, 0, 0);
+        for (p, i) in active.iter().enumerate() {
+            for j in active[p + 1..].iter() {
+                let q = (r - 2.) * dist[*i][*j] - row_sums[*i] - row_sums[*j];
+                if q < best.0 {
+                    best = (q, *i, *j);
+                }
+            }
+        }
+        let (_, a, b) = best;
+        let length_a = dist[a][b] / 2. + (row_sums[a] - row_sums[b]) / (2. * (r - 2.));
+        let length_b = dist[a][b] - length_a;
+        subtrees[a] = format!(
+            "({}:{:.4},{}:{:.4})",
+            subtrees[a],
+            f64::max(length_a, 0.),
+            subtrees[b],
+            f64::max(length_b, 0.)
+        );
+        subtrees[b] = String::new();
+        active.retain(|x| *x != b);
+        row_sums[a] = 0.;
+        for x in active.iter() {
+            if *x == a {
+                continue;
+            }
+            let d = (dist[a][*x] + dist[b][*x] - dist[a][b]) / 2.;
+            row_sums[*x] += d - dist[a][*x] - dist[b][*x];
+            row_sums[a] += d;
+            dist[a][*x] = d;
+            dist[*x][a] = d;
+        }
+    }
+    let (i, j, k) = (active[0], active[1], active[2]);
+    let length = |x: usize, y: usize, z: usize| f64::max((dist[x][y] + dist[x][z] - dist[y][z]) / 2., 0.);
+    format!(
+        "({}:{:.4},{}:{:.4},{}:{:.4});",
+        subtrees[i],
+        length(i, j, k),
+        subtrees[j],
+        length(j, i, k),
+        subtrees[k],
+        length(k, i, j)
+    )
+}
+
+pub fn write_tree(
+    anis: &FxHashMap<usize, FxHashMap<usize, AniEstResult>>,
+    sketches: &[Sketch],
+    file_name: &str,
+    use_contig_names: bool,
+    method: TreeMethod,
+    fill: f64,
+) {
+    let names = sketches
+        .iter()
+        .map(|x| {
+            if use_contig_names {
+                x.contigs[0].clone()
+            } else {
+                x.file_name.clone()
+            }
+        })
+        .collect::<Vec<String>>();
+    let dist = distance_matrix(anis, sketches.len(), fill);
+    let newick = match method {
+        TreeMethod::Upgma => upgma(dist, &names),
+        TreeMethod::Nj => neighbor_joining(dist, &names),
+    };
+    let mut handle = BufWriter::new(File::create(file_name).unwrap_or_else(|e| {
+        error!("Could not create {}: {}. Exiting.", file_name, e);
+        std::process::exit(1)
+    }));
+    writeln!(handle, "{}", newick).unwrap();
+    info!("Tree written to {}", file_name);
+}
@@ -1,8 +1,11 @@
 use crate::chain;
+use crate::checkpoint::{self, Checkpoint};
 use crate::file_io;
+use crate::grouping::GenomeGrouping;
 use crate::params::*;
 use crate::regression;
 use crate::screen;
+use crate::tree;
 use crate::types::*;
 use fxhash::FxHashMap;
 use log::*;
@@ -22,6 +25,13 @@ pub fn triangle(command_params: CommandParams, mut sketch_params: SketchParams)
         }
         ref_sketches = param_and_sketches.1;
         sketch_params = param_and_sketches.0;
+    } else if let Some(grouping) = GenomeGrouping::from_params(&command_params) {
+        ref_sketches = file_io::fastx_to_grouped_sketches(
+            &command_params.ref_files,
+            &grouping,
+            &sketch_params,
+            true,
+        );
     } else if command_params.individual_contig_r {
         ref_sketches = file_io::fastx_to_multiple_sketch_rewrite(
             &command_params.ref_files,
@@ -41,8 +51,7 @@ pub fn triangle(command_params: CommandParams, mut sketch_params: SketchParams)
     } else {
         screen_val = command_params.screen_val;
     }
-    let anis: Mutex<FxHashMap<usize, FxHashMap<usize, AniEstResult>>> =
-        Mutex::new(FxHashMap::default());
+    let mut anis: FxHashMap<usize, FxHashMap<usize, AniEstResult>> = FxHashMap::default();
 
     if ref_sketches.is_empty() {
         error!("No genomes/sketches found.");
@@ -50,90 +59,132 @@ pub fn triangle(command_params: CommandParams, mut sketch_params: SketchParams)
     }
     let kmer_to_sketch = screen::kmer_to_sketch_from_refs(&ref_sketches);
     let counter: Mutex<usize> = Mutex::new(0);
-    let first: Mutex<bool> = Mutex::new(true);
+    //Only -E output is written as it goes, so only it can be resumed.
+    let mut checkpoint = Checkpoint::open(
+        &command_params,
+        command_params.sparse
+            && !command_params.out_file_name.is_empty()
+            && command_params.output_format != OutputFormat::Json
+            && command_params.tree_file.is_empty(),
+    );
+    let row_name = |i: usize| checkpoint::query_name(&ref_sketches[i], command_params.individual_contig_r);
+    //Rows whose results are all in `anis`.
+    let mut finished = vec![];
+    let mut first_write = !checkpoint.resumed();
 
     let model = regression::get_model(sketch_params.c, command_params.learned_ani);
     if model.is_some() {
         info!("{}", LEARNED_INFO_HELP);
     }
-    (0..ref_sketches.len() - 1)
-        .collect::<Vec<usize>>()
-        .into_par_iter()
-        .for_each(|i| {
-            let ref_sketch_i = &ref_sketches[i];
-            //if command_params.screen {
-            let screened_refs = screen::screen_refs(
-                screen_val,
-                &kmer_to_sketch,
-                ref_sketch_i,
-                &sketch_params,
-                &ref_sketches,
-            );
-            debug!(
-                "{} has {} refs passing screening.",
-                ref_sketch_i.file_name,
-                screened_refs.len()
-            );
-            screened_refs.into_par_iter().for_each(|j| {
-                if j > i {
-                    let map_params = chain::map_params_from_sketch(
-                        ref_sketch_i,
-                        sketch_params.use_aa,
-                        &command_params,
-                    );
-                    let ref_sketch_j = &ref_sketches[j];
-                    let mut ani_res = chain::chain_seeds(ref_sketch_i, ref_sketch_j, map_params);
-                    if ani_res.ani > 0.1 {
-                        if command_params.learned_ani {
-                            let gbdt = model.as_ref().unwrap();
-                            regression::predict_from_ani_res(&mut ani_res, gbdt);
-                        }
-                        let mut locked = anis.lock().unwrap();
-                        let mapi = locked.entry(i).or_insert(FxHashMap::default());
-                        mapi.insert(j, ani_res);
-                    }
-                }
-            });
-
-            let c;
-            {
-                let mut locked = counter.lock().unwrap();
-                *locked += 1;
-                c = *locked;
-            }
-            if c % 100 == 0 && c != 0 {
-                info!("{} query sequences processed.", c);
-                if c % INTERMEDIATE_WRITE_COUNT == 0 && c != 0 {
-                    let moved_anis: FxHashMap<_,_>;
-                    {
-                        let mut locked = anis.lock().unwrap();
-                        moved_anis = std::mem::take(&mut locked);
-                        let mut locked = first.lock().unwrap();
-                        info!("Writing results for {} query sequences.", INTERMEDIATE_WRITE_COUNT);
-                        file_io::write_sparse_matrix(
-                            &moved_anis,
-                            &ref_sketches,
-                            &command_params.out_file_name,
-                            sketch_params.use_aa,
-                            command_params.est_ci,
-                            command_params.detailed_out,
-                            !*locked,
-                        );
-                        if *locked == true {
-                            *locked = false;
-                        }
+    //Rows are dealt out round robin so that shards get similar numbers of pairs.
+    let rows = (0..ref_sketches.len() - 1)
+        .filter(|i| command_params.num_shards == 0 || i % command_params.num_shards == command_params.shard)
+        .filter(|i| !checkpoint.is_done(&row_name(*i)))
+        .collect::<Vec<usize>>();
+    let row_anis = |i: usize| {
+        let ref_sketch_i = &ref_sketches[i];
+        //if command_params.screen {
+        let screened_refs = screen::screen_refs(
+            screen_val,
+            &kmer_to_sketch,
+            ref_sketch_i,
+            &sketch_params,
+            &ref_sketches,
+        );
+        debug!(
+            "{} has {} refs passing screening.",
+            ref_sketch_i.file_name,
+            screened_refs.len()
+        );
+        let row: Mutex<FxHashMap<usize, AniEstResult>> = Mutex::new(FxHashMap::default());
+        screened_refs.into_par_iter().for_each(|j| {
+            if j > i {
+                let map_params = chain::map_params_from_sketch(
+                    ref_sketch_i,
+                    sketch_params.use_aa,
+                    &command_params,
+                );
+                let ref_sketch_j = &ref_sketches[j];
+                let mut ani_res = chain::chain_seeds(ref_sketch_i, ref_sketch_j, map_params);
+                if ani_res.ani > 0.1 {
+                    if command_params.learned_ani {
+                        let gbdt = model.as_ref().unwrap();
+                        regression::predict_from_ani_res(&mut ani_res, gbdt);
                     }
+                    row.lock().unwrap().insert(j, ani_res);
                 }
             }
-
-            //
         });
-    let anis = anis.into_inner().unwrap();
+
+        let c;
+        {
+            let mut locked = counter.lock().unwrap();
+            *locked += 1;
+            c = *locked;
+        }
+        if c % 100 == 0 && c != 0 {
+            info!("{} query sequences processed.", c);
+        }
+        row.into_inner().unwrap()
+    };
+
+    //Rows are computed in chunks and written in row order after each chunk.
+    //Only sparse output can be written as it goes; the matrix and the tree
+    //need all results at the end.
+    let write_as_we_go = command_params.sparse
+        && command_params.output_format != OutputFormat::Json
+        && command_params.tree_file.is_empty()
+        && command_params.num_shards == 0;
+    for chunk in rows.chunks(INTERMEDIATE_WRITE_COUNT) {
+        let chunk_anis = chunk
+            .par_iter()
+            .map(|i| row_anis(*i))
+            .collect::<Vec<FxHashMap<usize, AniEstResult>>>();
+        for (i, row) in chunk.iter().zip(chunk_anis) {
+            if !row.is_empty() {
+                anis.insert(*i, row);
+            }
+            finished.push(row_name(*i));
+        }
+        if chunk.len() == INTERMEDIATE_WRITE_COUNT && write_as_we_go {
+            info!("Writing results for {} query sequences.", INTERMEDIATE_WRITE_COUNT);
+            file_io::write_sparse_matrix(
+                &anis,
+                &ref_sketches,
+                &command_params.out_file_name,
+                sketch_params.use_aa,
+                command_params.est_ci,
+                command_params.detailed_out,
+                command_params.output_format,
+                !first_write,
+            );
+            checkpoint.record(&finished);
+            first_write = false;
+            anis.clear();
+            finished.clear();
+        }
+    }
     let now_pred = Instant::now();
 
     debug!("Prediction time: {}", now_pred.elapsed().as_secs_f32());
 
-    if command_params.sparse {
+    if command_params.num_shards > 0 {
+        write_shard(anis, &ref_sketches, &sketch_params, &command_params);
+        info!("ANI triangle time: {}", now.elapsed().as_secs_f32());
+        return;
+    }
+    if command_params.binary {
+        if let Err(e) = file_io::write_sparse_coo(
+            &anis,
+            &ref_sketches,
+            &command_params.out_file_name,
+            command_params.individual_contig_r,
+        ) {
+            error!("Could not write {}: {}. Exiting.", command_params.out_file_name, e);
+            std::process::exit(1)
+        }
+        info!("Binary sparse matrix written to {}", command_params.out_file_name);
+    } else if command_params.sparse {
         file_io::write_sparse_matrix(
             &anis,
             &ref_sketches,
@@ -141,8 +192,11 @@ pub fn triangle(command_params: CommandParams, mut sketch_params: SketchParams)
             sketch_params.use_aa,
             command_params.est_ci,
             command_params.detailed_out,
-            !*first.lock().unwrap(),
+            command_params.output_format,
+            !first_write,
         );
+        checkpoint.record(&finished);
+        checkpoint.finish();
     } else {
         file_io::write_phyllip_matrix(
             &anis,
@@ -154,5 +208,243 @@ pub fn triangle(command_params: CommandParams, mut sketch_params: SketchParams)
             command_params.distance,
         );
     }
+    if !command_params.tree_file.is_empty() {
+        tree::write_tree(
+            &anis,
+            &ref_sketches,
+            &command_params.tree_file,
+            command_params.individual_contig_r,
+            command_params.tree_method,
+            command_params.tree_fill,
+        );
+    }
+    info!("ANI triangle time: {}", now.elapsed().as_secs_f32());
+}
+
+fn write_shard(
+    anis: FxHashMap<usize, FxHashMap<usize, AniEstResult>>,
+    ref_sketches: &[Sketch],
+    sketch_params: &SketchParams,
+    command_params: &CommandParams,
+) {
+    let mut pairs = anis
+        .into_iter()
+        .flat_map(|(i, row)| row.into_iter().map(move |(j, ani_res)| (i, j, ani_res)))
+        .collect::<Vec<(usize, usize, AniEstResult)>>();
+    pairs.sort_by_key(|x| (x.0, x.1));
+    let shard = file_io::TriangleShard {
+        shard: command_params.shard,
+        num_shards: command_params.num_shards,
+        use_contig_names: command_params.individual_contig_r,
+        names: ref_sketches
+            .iter()
+            .map(|x| (x.file_name.clone(), x.contigs[0].clone()))
+            .collect(),
+        anis: pairs,
+    };
+    if let Err(e) = file_io::write_triangle_shard(&command_params.out_file_name, sketch_params, &shard) {
+        error!("Could not write {}: {}. Exiting.", command_params.out_file_name, e);
+        std::process::exit(1)
+    }
+    info!(
+        "Shard {}/{} with {} pairs written to {}",
+        shard.shard,
+        shard.num_shards,
+        shard.anis.len(),
+        command_params.out_file_name
+    );
+}
+
+fn load_full_sketch(file: &str) -> Sketch {
+    file_io::read_sketch_file(file).map(|x| x.1).unwrap_or_else(|e| {
+        error!("Could not reload {}: {}. Exiting.", file, e);
+        std::process::exit(1)
+    })
+}
+
+//Triangle for very many genomes. Only markers are kept for all genomes; full
+//sketches are loaded for one block of rows at a time, and the columns that
+//pass screening for that block are loaded in chunks of the same size. Each
+//block's results are appended to a binary sparse matrix (see
+//file_io::SparseCooWriter) before the next block starts. Fasta inputs are
+//sketched once; their full sketches are written to <-o>.tmp_sketches.<pid>
+//and reloaded from there.
+pub fn triangle_stream(command_params: CommandParams, mut sketch_params: SketchParams) {
+    let now = Instant::now();
+    let is_sketch = command_params.refs_are_sketch;
+    let tmp_folder = format!("{}.tmp_sketches.{}", command_params.out_file_name, std::process::id());
+    if !is_sketch {
+        if let Err(e) = std::fs::create_dir(&tmp_folder) {
+            error!("Could not create {}: {}. Exiting.", tmp_folder, e);
+            std::process::exit(1)
+        }
+    }
+    let loaded: Mutex<Vec<(Sketch, String, SketchParams)>> = Mutex::new(vec![]);
+    command_params.ref_files.par_iter().enumerate().for_each(|(index, file)| {
+        let res = if is_sketch {
+            if file.contains("markers.bin") {
+                return;
+            }
+            match file_io::read_sketch_file(file) {
+                Ok((params, sketch)) => Some((Sketch::get_markers_only(&sketch), file.clone(), params)),
+                Err(e) => {
+                    error!("{}", e);
+                    None
+                }
+            }
+        } else {
+            file_io::fastx_to_sketches(&vec![file.clone()], &sketch_params, true)
+                .pop()
+                .map(|sketch| {
+                    let sketch_file = format!("{}/{}.sketch", tmp_folder, index);
+                    if let Err(e) = file_io::write_sketch_file(&sketch_file, &sketch_params, &sketch) {
+                        error!("Could not write {}: {}. Exiting.", sketch_file, e);
+                        std::process::exit(1)
+                    }
+                    (Sketch::get_markers_only(&sketch), sketch_file, sketch_params.clone())
+                })
+        };
+        if let Some(x) = res {
+            loaded.lock().unwrap().push(x);
+        }
+    });
+    let mut loaded = loaded.into_inner().unwrap();
+    if loaded.is_empty() {
+        error!("No genomes/sketches found.");
+        std::process::exit(1)
+    }
+    loaded.sort_by(|x, y| x.0.cmp(&y.0));
+    if is_sketch {
+        let params = &loaded[0].2;
+        if params.c != sketch_params.c {
+            warn!("Input parameter c = {} is not equal to the sketch parameter c = {}. Using sketch parameters.", sketch_params.c, params.c);
+        }
+        if let Some(x) = loaded.iter().find(|x| {
+            x.2.k != params.k || x.2.c != params.c || x.2.marker_c != params.marker_c || x.2.use_aa != params.use_aa
+        }) {
+            error!("{} was sketched with different parameters than {}. Exiting.", x.1, loaded[0].1);
+            std::process::exit(1)
+        }
+        sketch_params = params.clone();
+    }
+    let mut markers = vec![];
+    let mut sources = vec![];
+    for (sketch, source, _) in loaded {
+        markers.push(sketch);
+        sources.push(source);
+    }
+    let n = markers.len();
+    info!("Markers for {} genomes loaded in {} seconds.", n, now.elapsed().as_secs_f32());
+
+    let screen_val;
+    if command_params.screen_val == 0. {
+        if sketch_params.use_aa {
+            screen_val = SEARCH_AAI_CUTOFF_DEFAULT;
+        } else {
+            screen_val = SEARCH_ANI_CUTOFF_DEFAULT;
+        }
+    } else {
+        screen_val = command_params.screen_val;
+    }
+    let model = regression::get_model(sketch_params.c, command_params.learned_ani);
+    if model.is_some() {
+        info!("{}", LEARNED_INFO_HELP);
+    }
+    let kmer_to_sketch = screen::kmer_to_sketch_from_refs(&markers);
+    let names = markers.iter().map(|x| x.file_name.clone()).collect::<Vec<String>>();
+    let mut writer = file_io::SparseCooWriter::create(&command_params.out_file_name, &names)
+        .unwrap_or_else(|e| {
+            error!("Could not create {}: {}. Exiting.", command_params.out_file_name, e);
+            std::process::exit(1)
+        });
+
+    let block_size = command_params.block_size;
+    let mut num_written = 0;
+    for start in (0..n).step_by(block_size) {
+        let end = usize::min(start + block_size, n);
+        let screened = (start..end)
+            .into_par_iter()
+            .map(|i| {
+                let mut js = screen::screen_refs(
+                    screen_val,
+                    &kmer_to_sketch,
+                    &markers[i],
+                    &sketch_params,
+                    &markers,
+                )
+                .into_iter()
+                .filter(|j| *j > i)
+                .collect::<Vec<usize>>();
+                js.sort_unstable();
+                js
+            })
+            .collect::<Vec<Vec<usize>>>();
+        let rows = (start..end)
+            .into_par_iter()
+            .map(|i| load_full_sketch(&sources[i]))
+            .collect::<Vec<Sketch>>();
+        let mut columns = screened
+            .iter()
+            .flatten()
+            .filter(|j| **j >= end)
+            .copied()
+            .collect::<Vec<usize>>();
+        columns.sort_unstable();
+        columns.dedup();
+
+        //The first chunk is empty and stands for pairs within the row block.
+        let mut chunks = vec![&columns[0..0]];
+        chunks.extend(columns.chunks(block_size));
+        let results: Mutex<Vec<file_io::SparseEntry>> = Mutex::new(vec![]);
+        for chunk in chunks {
+            let chunk_sketches = chunk
+                .par_iter()
+                .map(|j| (*j, load_full_sketch(&sources[*j])))
+                .collect::<FxHashMap<usize, Sketch>>();
+            (start..end).into_par_iter().for_each(|i| {
+                let sketch_i = &rows[i - start];
+                screened[i - start].par_iter().for_each(|j| {
+                    let sketch_j = if chunk.is_empty() {
+                        if *j >= end {
+                            return;
+                        }
+                        &rows[j - start]
+                    } else {
+                        match chunk_sketches.get(j) {
+                            Some(x) => x,
+                            None => return,
+                        }
+                    };
+                    let map_params =
+                        chain::map_params_from_sketch(sketch_i, sketch_params.use_aa, &command_params);
+                    let mut ani_res = chain::chain_seeds(sketch_i, sketch_j, map_params);
+                    if ani_res.ani > 0.1 {
+                        if command_params.learned_ani {
+                            regression::predict_from_ani_res(&mut ani_res, model.as_ref().unwrap());
+                        }
+                        results.lock().unwrap().push(file_io::SparseEntry {
+                            i: i as u32,
+                            j: *j as u32,
+                            ani: ani_res.ani,
+                            af_i: ani_res.align_fraction_ref,
+                            af_j: ani_res.align_fraction_query,
+                        });
+                    }
+                });
+            });
+        }
+        let mut results = results.into_inner().unwrap();
+        results.sort_by_key(|x| (x.i, x.j));
+        writer.write_entries(&results).unwrap_or_else(|e| {
+            error!("Could not write to {}: {}. Exiting.", command_params.out_file_name, e);
+            std::process::exit(1)
+        });
+        num_written += results.len();
+        info!("{} of {} genomes processed; {} pairs written.", end, n, num_written);
+    }
+    if !is_sketch {
+        let _ = std::fs::remove_dir_all(&tmp_folder);
+    }
+    info!("Binary sparse matrix written to {}", command_params.out_file_name);
     info!("ANI triangle time: {}", now.elapsed().as_secs_f32());
 }
@@ -32,6 +32,7 @@ use serde::{Deserialize, Serialize};
 // bytecheck can be used to validate your data if you want
 use smallvec::SmallVec;
 use crate::params::*;
+use crate::db::PackedSeeds;
 use partitions::*;
 use std::collections::{HashMap, HashSet};
 use std::hash::{BuildHasherDefault, Hash, Hasher};
@@ -155,7 +156,11 @@ pub struct Sketch {
     pub c: usize,
     pub k: usize,
     pub contig_order: usize,
-    pub amino_acid: bool
+    pub amino_acid: bool,
+    //Set instead of kmer_seeds_k for references read from a packed database;
+    //never written to .sketch files.
+    #[serde(skip)]
+    pub packed_seeds: Option<PackedSeeds>,
 }
 
 impl Sketch{
@@ -173,10 +178,23 @@ impl Sketch{
             c : sketch.c,
             k : sketch.k,
             contig_order: sketch.contig_order,
-            amino_acid: sketch.amino_acid
+            amino_acid: sketch.amino_acid,
+            packed_seeds: None,
         }
     }
     
+    //Sketch in-memory (id, sequence) records as one genome, e.g.
+    //Sketch::from_records("bin.1", vec![("contig_1", seq1), ("contig_2", seq2)], &sketch_params).
+    //Records shorter than MIN_LENGTH_CONTIG are ignored; None is returned if nothing is left.
+    pub fn from_records<I, S, T>(name: &str, records: I, sketch_params: &SketchParams) -> Option<Sketch>
+    where
+        I: IntoIterator<Item = (S, T)>,
+        S: AsRef<[u8]>,
+        T: AsRef<[u8]>,
+    {
+        crate::file_io::records_to_sketch(name, records, sketch_params, true)
+    }
+
     pub fn new(marker_c: usize, c: usize, k: usize, file_name: String, amino_acid: bool) -> Sketch{
         assert!(marker_c >= c);
         let mut new_sketch = Sketch::default();
@@ -222,6 +240,7 @@ impl Default for Sketch {
             k: 0,
             contig_order:0,
             amino_acid: false,
+            packed_seeds: None,
         }
     }
 }
@@ -398,7 +417,9 @@ pub struct Orf{
     pub phase: u8
 }
 
-#[derive(Default, Clone, Debug)]
+//Serialized as is for --format json/jsonl, so the field names are part of the
+//output format.
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
 pub struct AniEstResult{
     pub ani: f32,
     pub align_fraction_query: f32,
@@ -422,3 +443,95 @@ pub struct AniEstResult{
     pub avg_chain_int_len: u32,
     pub total_bases_covered: u32, 
 }
+
+//A chain kept for an ANI estimate; see chain::chain_seeds_detailed. Ranges
+//are 0-based and end-exclusive, like PAF.
+#[derive(Default, Clone, Debug, Serialize)]
+pub struct ChainRecord {
+    pub query_file: String,
+    pub query_contig: String,
+    pub query_contig_len: GnPosition,
+    pub query_start: GnPosition,
+    pub query_end: GnPosition,
+    pub ref_file: String,
+    pub ref_contig: String,
+    pub ref_contig_len: GnPosition,
+    pub ref_start: GnPosition,
+    pub ref_end: GnPosition,
+    pub reverse: bool,
+    pub num_anchors: usize,
+    pub score: f64,
+    //Identity estimate of the query fragment the chain is in. None if the
+    //fragment was too short to be used for the ANI.
+    pub fragment_identity: Option<f32>,
+}
+
+//Identity of one fragment (a CHUNK_SIZE_DNA/AA piece of the genome with the
+//longer contigs) used for an ANI estimate. Fragments tile the query unless
+//fragmented_query is false; the other genome's range is spanned by the
+//fragment's chains.
+#[derive(Default, Clone, Debug, Serialize)]
+pub struct FragmentRecord {
+    pub query_file: String,
+    pub query_contig: String,
+    pub query_start: GnPosition,
+    pub query_end: GnPosition,
+    pub ref_file: String,
+    pub ref_contig: String,
+    pub ref_start: GnPosition,
+    pub ref_end: GnPosition,
+    pub identity: f32,
+    pub num_seeds: usize,
+    pub num_anchors: usize,
+    pub fragmented_query: bool,
+}
+
+//0-based, end-exclusive range on a contig.
+#[derive(Default, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct GenomeRegion {
+    pub contig: String,
+    pub start: GnPosition,
+    pub end: GnPosition,
+}
+
+//Everything chain::chain_seeds_detailed finds for one query/reference pair.
+//Aligned regions are the merged chains padded like for the aligned fraction;
+//unaligned regions are their complements over all contigs.
+#[derive(Default, Clone, Debug, Serialize)]
+pub struct AlignmentDetails {
+    pub query_file: String,
+    pub ref_file: String,
+    pub chains: Vec<ChainRecord>,
+    pub fragments: Vec<FragmentRecord>,
+    pub query_aligned: Vec<GenomeRegion>,
+    pub query_unaligned: Vec<GenomeRegion>,
+    pub ref_aligned: Vec<GenomeRegion>,
+    pub ref_unaligned: Vec<GenomeRegion>,
+}
+
+//Summary of a sketch folder or sketch files; see `skani info`.
+#[derive(Default, Clone, Debug, Serialize)]
+pub struct DbInfo {
+    pub skani_version: String,
+    pub k: usize,
+    pub c: usize,
+    pub marker_c: usize,
+    pub amino_acid: bool,
+    pub num_genomes: usize,
+    pub total_sequence_length: usize,
+    pub genomes: Vec<GenomeInfo>,
+    pub sketch_files_without_markers: Vec<String>,
+    pub markers_without_sketch_files: Vec<String>,
+}
+
+#[derive(Default, Clone, Debug, Serialize)]
+pub struct GenomeInfo {
+    pub file_name: String,
+    pub contig_order: usize,
+    pub sketch_file: Option<String>,
+    pub in_markers: bool,
+    pub num_contigs: usize,
+    pub total_sequence_length: usize,
+    pub num_markers: usize,
+    pub num_seeds: Option<usize>,
+}
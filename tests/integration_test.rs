@@ -53,6 +53,146 @@ fn a_test_sketch() {
     assert.success().code(0);
 }
 
+#[test]
+#[serial]
+fn test_sketch_append() {
+    Command::new("rm")
+        .arg("-r")
+        .arg("./tests/results/test_sketch_dir_append")
+        .status()
+        .unwrap();
+    let mut cmd = Command::cargo_bin("skani").unwrap();
+    let assert = cmd
+        .arg("sketch")
+        .arg("./test_files/e.coli-W.fasta.gz")
+        .arg("-o")
+        .arg("./tests/results/test_sketch_dir_append")
+        .assert();
+    assert.success().code(0);
+
+    let mut cmd = Command::cargo_bin("skani").unwrap();
+    let assert = cmd
+        .arg("sketch")
+        .arg("--append")
+        .arg("./test_files/e.coli-W.fasta.gz")
+        .arg("./test_files/o157_plasmid.fasta")
+        .arg("-o")
+        .arg("./tests/results/test_sketch_dir_append")
+        .assert();
+    assert.success().code(0);
+
+    let mut cmd = Command::cargo_bin("skani").unwrap();
+    let out = cmd
+        .arg("search")
+        .arg("-d")
+        .arg("./tests/results/test_sketch_dir_append")
+        .arg("./test_files/o157_plasmid.fasta")
+        .output();
+    let out_line = std::str::from_utf8(&out.as_ref().unwrap().stdout).unwrap();
+    assert!(out_line.contains("o157_plasmid.fasta\t./test_files/o157_plasmid.fasta"));
+
+    //The folder was sketched with the default c; --slow is c = 30.
+    let mut cmd = Command::cargo_bin("skani").unwrap();
+    let out = cmd
+        .arg("sketch")
+        .arg("--append")
+        .arg("--slow")
+        .arg("./test_files/o157_plasmid.fasta")
+        .arg("-o")
+        .arg("./tests/results/test_sketch_dir_append")
+        .output()
+        .unwrap();
+    assert!(!out.status.success());
+    let err_line = std::str::from_utf8(&out.stderr).unwrap();
+    assert!(err_line.contains("do not match the parameters"));
+}
+
+#[test]
+#[serial]
+fn test_sketch_remove() {
+    Command::new("rm")
+        .arg("-r")
+        .arg("./tests/results/test_sketch_dir_remove")
+        .status()
+        .unwrap();
+    let mut cmd = Command::cargo_bin("skani").unwrap();
+    let assert = cmd
+        .arg("sketch")
+        .arg("./test_files/e.coli-W.fasta.gz")
+        .arg("./test_files/o157_plasmid.fasta")
+        .arg("-o")
+        .arg("./tests/results/test_sketch_dir_remove")
+        .assert();
+    assert.success().code(0);
+
+    let mut cmd = Command::cargo_bin("skani").unwrap();
+    let assert = cmd
+        .arg("sketch")
+        .arg("--remove")
+        .arg("o157_plasmid.fasta.sketch")
+        .arg("-o")
+        .arg("./tests/results/test_sketch_dir_remove")
+        .assert();
+    assert.success().code(0);
+    assert!(!std::path::Path::new("./tests/results/test_sketch_dir_remove/o157_plasmid.fasta.sketch").exists());
+
+    let mut cmd = Command::cargo_bin("skani").unwrap();
+    let out = cmd
+        .arg("search")
+        .arg("-d")
+        .arg("./tests/results/test_sketch_dir_remove")
+        .arg("./test_files/o157_plasmid.fasta")
+        .output();
+    assert!(out.as_ref().unwrap().status.success());
+    let out_line = std::str::from_utf8(&out.as_ref().unwrap().stdout).unwrap();
+    assert!(!out_line.contains("o157_plasmid.fasta\t./test_files/o157_plasmid.fasta"));
+}
+
+#[test]
+#[serial]
+fn test_output_format() {
+    let mut cmd = Command::cargo_bin("skani").unwrap();
+    let out = cmd
+        .arg("dist")
+        .arg("./test_files/e.coli-W.fasta.gz")
+        .arg("./test_files/e.coli-W.fasta.gz")
+        .arg("--format")
+        .arg("json")
+        .output();
+    let json: serde_json::Value = serde_json::from_slice(&out.as_ref().unwrap().stdout).unwrap();
+    let results = json.as_array().unwrap();
+    assert!(results.len() == 1);
+    assert!(results[0]["ani"].as_f64().unwrap() > 0.99);
+    for key in ["ci_lower", "ci_upper", "std", "quant_50_contig_len_q", "avg_chain_int_len", "total_bases_covered"] {
+        assert!(results[0].get(key).is_some());
+    }
+
+    let mut cmd = Command::cargo_bin("skani").unwrap();
+    let out = cmd
+        .arg("triangle")
+        .arg("./test_files/e.coli-W.fasta.gz")
+        .arg("./test_files/e.coli-W.fasta.gz")
+        .arg("./test_files/o157_plasmid.fasta")
+        .arg("-E")
+        .arg("--format")
+        .arg("jsonl")
+        .output();
+    let out_lines = std::str::from_utf8(&out.as_ref().unwrap().stdout).unwrap();
+    assert!(out_lines.lines().count() == 1);
+    let json: serde_json::Value = serde_json::from_str(out_lines.lines().next().unwrap()).unwrap();
+    assert!(json["ref_file"] == "./test_files/e.coli-W.fasta.gz");
+
+    let mut cmd = Command::cargo_bin("skani").unwrap();
+    let assert = cmd
+        .arg("triangle")
+        .arg("./test_files/e.coli-W.fasta.gz")
+        .arg("./test_files/o157_plasmid.fasta")
+        .arg("--format")
+        .arg("json")
+        .assert();
+    assert.failure();
+}
+
 #[test]
 #[serial]
 fn test_search() {
@@ -631,3 +771,415 @@ fn test_triangle() {
 
 }
 
+
+#[test]
+#[serial]
+fn test_core() {
+    let mut cmd = Command::cargo_bin("skani").unwrap();
+    let assert = cmd
+        .arg("core")
+        .arg("./test_files/e.coli-W.fasta.gz")
+        .arg("./test_files/e.coli-W.fasta.gz")
+        .arg("./test_files/o157_plasmid.fasta")
+        .arg("--core-threshold")
+        .arg("60")
+        .arg("-o")
+        .arg("./tests/results/core");
+    assert.assert().success().code(0);
+    let fractions = std::fs::read_to_string("./tests/results/core.core_fraction.tsv").unwrap();
+    let lines = fractions.lines().collect::<Vec<&str>>();
+    assert!(lines.len() == 4);
+    assert!(lines[1].starts_with("./test_files/e.coli-W.fasta.gz") && lines[1].ends_with("\t1.0000\t1.0000"));
+    assert!(lines[3].starts_with("./test_files/o157_plasmid.fasta") && lines[3].contains("\tNA\t"));
+    let matrix = std::fs::read_to_string("./tests/results/core.presence_absence.tsv").unwrap();
+    let num_windows = matrix.lines().count() - 1;
+    let num_shared = matrix.lines().filter(|x| x.split('\t').nth(3) == Some("2")).count();
+    assert!(num_shared as f64 > 0.99 * num_windows as f64);
+
+    let mut cmd = Command::cargo_bin("skani").unwrap();
+    let assert = cmd
+        .arg("core")
+        .arg("./test_files/e.coli-W.fasta.gz")
+        .arg("--window")
+        .arg("0")
+        .arg("-o")
+        .arg("./tests/results/core");
+    assert.assert().failure();
+}
+
+#[test]
+#[serial]
+fn test_cluster() {
+    for method in ["single", "complete", "greedy"] {
+        let mut cmd = Command::cargo_bin("skani").unwrap();
+        let out = cmd
+            .arg("cluster")
+            .arg("./test_files/e.coli-W.fasta.gz")
+            .arg("./test_files/e.coli-W.fasta.gz")
+            .arg("./test_files/o157_plasmid.fasta")
+            .arg("--method")
+            .arg(method)
+            .output();
+        let out_lines = std::str::from_utf8(&out.as_ref().unwrap().stdout).unwrap();
+        let lines = out_lines.lines().collect::<Vec<&str>>();
+        assert!(lines.len() == 4);
+        assert!(lines[1].starts_with("0\t./test_files/e.coli-W.fasta.gz\t./test_files/e.coli-W.fasta.gz"));
+        assert!(lines[2].starts_with("0\t./test_files/e.coli-W.fasta.gz\t./test_files/e.coli-W.fasta.gz"));
+        assert!(lines[3].starts_with("1\t./test_files/o157_plasmid.fasta\t./test_files/o157_plasmid.fasta"));
+    }
+}
+
+#[test]
+#[serial]
+fn test_derep() {
+    std::fs::write(
+        "./tests/results/derep_quality.tsv",
+        "genome\tscore\no157_plasmid\t100\ne.coli-W\t50\n",
+    )
+    .unwrap();
+    let mut cmd = Command::cargo_bin("skani").unwrap();
+    let assert = cmd
+        .arg("derep")
+        .arg("./test_files/e.coli-W.fasta.gz")
+        .arg("./test_files/o157_plasmid.fasta")
+        .arg("--quality")
+        .arg("./tests/results/derep_quality.tsv")
+        .arg("--quality-column")
+        .arg("score")
+        .arg("-o")
+        .arg("./tests/results/derep");
+    assert.assert().success().code(0);
+    let reps = std::fs::read_to_string("./tests/results/derep.representatives.txt").unwrap();
+    assert!(reps.lines().next() == Some("./test_files/o157_plasmid.fasta"));
+    assert!(reps.lines().any(|x| x == "./test_files/e.coli-W.fasta.gz"));
+    let clusters = std::fs::read_to_string("./tests/results/derep.clusters.tsv").unwrap();
+    assert!(clusters.lines().next().unwrap().ends_with("\tScore"));
+    assert!(clusters.lines().count() == 3);
+}
+
+#[test]
+#[serial]
+fn test_triangle_tree() {
+    for method in ["upgma", "nj"] {
+        let mut cmd = Command::cargo_bin("skani").unwrap();
+        let assert = cmd
+            .arg("triangle")
+            .arg("./test_files/e.coli-W.fasta.gz")
+            .arg("./test_files/o157_plasmid.fasta")
+            .arg("-o")
+            .arg("./tests/results/tree_matrix.txt")
+            .arg("--tree")
+            .arg("./tests/results/tree.nwk")
+            .arg("--tree-method")
+            .arg(method);
+        assert.assert().success().code(0);
+        let tree = std::fs::read_to_string("./tests/results/tree.nwk").unwrap();
+        assert!(tree.starts_with('(') && tree.trim_end().ends_with(");"));
+        assert!(tree.contains("./test_files/e.coli-W.fasta.gz:"));
+        assert!(tree.contains("./test_files/o157_plasmid.fasta:"));
+    }
+}
+
+#[test]
+#[serial]
+fn test_triangle_stream() {
+    let mut cmd = Command::cargo_bin("skani").unwrap();
+    let assert = cmd
+        .arg("triangle")
+        .arg("-l")
+        .arg("./test_files/list.txt")
+        .arg("--stream")
+        .arg("--block-size")
+        .arg("1")
+        .arg("-o")
+        .arg("./tests/results/triangle_stream.bin");
+    assert.assert().success().code(0);
+    let coo = skani::file_io::read_sparse_coo("./tests/results/triangle_stream.bin").unwrap();
+    assert!(!coo.names.is_empty());
+    for entry in coo.entries.iter() {
+        assert!(entry.i < entry.j);
+        assert!(entry.ani > 0.1 && entry.ani <= 1.);
+    }
+
+    let mut cmd = Command::cargo_bin("skani").unwrap();
+    let assert = cmd
+        .arg("triangle")
+        .arg("-l")
+        .arg("./test_files/list.txt")
+        .arg("--binary")
+        .arg("-o")
+        .arg("./tests/results/triangle_binary.bin");
+    assert.assert().success().code(0);
+    let binary = skani::file_io::read_sparse_coo("./tests/results/triangle_binary.bin").unwrap();
+    assert!(binary.names == coo.names);
+    assert!(binary.entries == coo.entries);
+
+    let mut cmd = Command::cargo_bin("skani").unwrap();
+    let assert = cmd.arg("triangle").arg("-l").arg("./test_files/list.txt").arg("--stream");
+    assert.assert().failure();
+}
+
+#[test]
+#[serial]
+fn test_triangle_shard_merge() {
+    let mut shards = vec![];
+    for i in 0..3 {
+        let shard = format!("./tests/results/triangle_shard_{}", i);
+        let mut cmd = Command::cargo_bin("skani").unwrap();
+        let assert = cmd
+            .arg("triangle")
+            .arg("-l")
+            .arg("./test_files/list.txt")
+            .arg("--shard")
+            .arg(format!("{}/3", i))
+            .arg("-o")
+            .arg(&shard);
+        assert.assert().success().code(0);
+        shards.push(shard);
+    }
+
+    let mut cmd = Command::cargo_bin("skani").unwrap();
+    let assert = cmd
+        .arg("triangle")
+        .arg("-l")
+        .arg("./test_files/list.txt")
+        .arg("-o")
+        .arg("./tests/results/triangle_unsharded.txt");
+    assert.assert().success().code(0);
+
+    let mut cmd = Command::cargo_bin("skani").unwrap();
+    let assert = cmd
+        .arg("merge")
+        .args(&shards)
+        .arg("-o")
+        .arg("./tests/results/triangle_merged.txt");
+    assert.assert().success().code(0);
+    let unsharded = std::fs::read_to_string("./tests/results/triangle_unsharded.txt").unwrap();
+    let merged = std::fs::read_to_string("./tests/results/triangle_merged.txt").unwrap();
+    assert!(unsharded == merged);
+
+    let mut cmd = Command::cargo_bin("skani").unwrap();
+    let assert = cmd.arg("merge").arg(&shards[0]).arg(&shards[2]);
+    assert.assert().failure();
+}
+
+#[test]
+#[serial]
+fn test_dist_resume() {
+    let dist = |out: &str, resume: bool| {
+        let mut cmd = Command::cargo_bin("skani").unwrap();
+        cmd.arg("dist")
+            .arg("-q")
+            .arg("./test_files/e.coli-W.fasta.gz")
+            .arg("./test_files/o157_plasmid.fasta")
+            .arg("-r")
+            .arg("./test_files/o157_plasmid.fasta")
+            .arg("./test_files/e.coli-W.fasta.gz")
+            .arg("-o")
+            .arg(out);
+        if resume {
+            cmd.arg("--resume");
+        }
+        cmd.assert().success().code(0);
+    };
+    let sorted = |file: &str| {
+        let mut lines = std::fs::read_to_string(file)
+            .unwrap()
+            .lines()
+            .map(|x| x.to_string())
+            .collect::<Vec<String>>();
+        lines.sort();
+        lines
+    };
+    //A finished run leaves no checkpoint behind.
+    dist("./tests/results/resume_full.tsv", false);
+    assert!(!std::path::Path::new("./tests/results/resume_full.tsv.checkpoint").exists());
+
+    //As if the run was killed after the e.coli-W query was written.
+    let full = std::fs::read_to_string("./tests/results/resume_full.tsv").unwrap();
+    assert!(full.lines().count() == 3);
+    let partial = full
+        .lines()
+        .filter(|x| x.starts_with("Ref_file") || x.contains("\t./test_files/e.coli-W.fasta.gz\t"))
+        .map(|x| format!("{}\n", x))
+        .collect::<String>();
+    std::fs::write("./tests/results/resume_partial.tsv", partial).unwrap();
+    std::fs::write(
+        "./tests/results/resume_partial.tsv.checkpoint",
+        "./test_files/e.coli-W.fasta.gz\n",
+    )
+    .unwrap();
+    dist("./tests/results/resume_partial.tsv", true);
+    assert!(sorted("./tests/results/resume_full.tsv") == sorted("./tests/results/resume_partial.tsv"));
+    assert!(!std::path::Path::new("./tests/results/resume_partial.tsv.checkpoint").exists());
+}
+
+#[test]
+#[serial]
+fn test_deterministic_order() {
+    //Copies of the plasmid tie at 100% ANI, so ties have to be ordered too.
+    std::fs::copy("./test_files/o157_plasmid.fasta", "./tests/results/order_plasmid_a.fa").unwrap();
+    std::fs::copy("./test_files/o157_plasmid.fasta", "./tests/results/order_plasmid_b.fa").unwrap();
+    let run = |mode: &str, threads: &str, out: &str| {
+        let mut cmd = Command::cargo_bin("skani").unwrap();
+        cmd.arg(mode).arg("-t").arg(threads);
+        if mode == "dist" {
+            cmd.arg("-q");
+        } else {
+            cmd.arg("-E");
+        }
+        cmd.arg("./tests/results/order_plasmid_b.fa")
+            .arg("./test_files/e.coli-W.fasta.gz")
+            .arg("./test_files/o157_plasmid.fasta");
+        if mode == "dist" {
+            cmd.arg("-r")
+                .arg("./tests/results/order_plasmid_a.fa")
+                .arg("./test_files/e.coli-W.fasta.gz")
+                .arg("./test_files/o157_plasmid.fasta")
+                .arg("./tests/results/order_plasmid_b.fa");
+        } else {
+            cmd.arg("./tests/results/order_plasmid_a.fa");
+        }
+        cmd.arg("-o").arg(out);
+        cmd.assert().success().code(0);
+        std::fs::read_to_string(out).unwrap()
+    };
+    for mode in ["dist", "triangle"] {
+        let one = run(mode, "1", &format!("./tests/results/order_{}_t1.tsv", mode));
+        let many = run(mode, "8", &format!("./tests/results/order_{}_t8.tsv", mode));
+        assert!(one == many);
+    }
+
+    //Queries in input order; tied references ordered by file name.
+    let out = std::fs::read_to_string("./tests/results/order_dist_t1.tsv").unwrap();
+    let pairs = out
+        .lines()
+        .skip(1)
+        .map(|x| {
+            let fields = x.split('\t').collect::<Vec<&str>>();
+            (fields[1], fields[0])
+        })
+        .collect::<Vec<(&str, &str)>>();
+    assert!(
+        pairs
+            == vec![
+                ("./tests/results/order_plasmid_b.fa", "./test_files/o157_plasmid.fasta"),
+                ("./tests/results/order_plasmid_b.fa", "./tests/results/order_plasmid_a.fa"),
+                ("./tests/results/order_plasmid_b.fa", "./tests/results/order_plasmid_b.fa"),
+                ("./test_files/e.coli-W.fasta.gz", "./test_files/e.coli-W.fasta.gz"),
+                ("./test_files/o157_plasmid.fasta", "./test_files/o157_plasmid.fasta"),
+                ("./test_files/o157_plasmid.fasta", "./tests/results/order_plasmid_a.fa"),
+                ("./test_files/o157_plasmid.fasta", "./tests/results/order_plasmid_b.fa"),
+            ]
+    );
+}
+
+#[test]
+#[serial]
+fn test_dist_stdin() {
+    let mut cmd = Command::cargo_bin("skani").unwrap();
+    cmd.arg("dist")
+        .arg("./test_files/e.coli-W.fasta.gz")
+        .arg("./test_files/e.coli-W.fasta.gz")
+        .arg("-o")
+        .arg("./tests/results/dist_file.tsv");
+    cmd.assert().success().code(0);
+
+    //Gzipped fasta from stdin.
+    let mut cmd = Command::cargo_bin("skani").unwrap();
+    cmd.arg("dist")
+        .arg("-")
+        .arg("./test_files/e.coli-W.fasta.gz")
+        .arg("-o")
+        .arg("./tests/results/dist_stdin.tsv")
+        .stdin(std::fs::File::open("./test_files/e.coli-W.fasta.gz").unwrap());
+    cmd.assert().success().code(0);
+    let from_file = std::fs::read_to_string("./tests/results/dist_file.tsv").unwrap();
+    let from_stdin = std::fs::read_to_string("./tests/results/dist_stdin.tsv").unwrap();
+    assert!(from_file.lines().count() == 2);
+    assert!(from_stdin == from_file.replacen("\t./test_files/e.coli-W.fasta.gz\t", "\t-\t", 1));
+
+    //Stdin only works for queries.
+    let mut cmd = Command::cargo_bin("skani").unwrap();
+    cmd.arg("triangle").arg("-").arg("./test_files/o157_plasmid.fasta");
+    cmd.assert().failure();
+}
+
+#[test]
+#[serial]
+fn test_triangle_genome_regex() {
+    let plasmid = std::fs::read_to_string("./test_files/o157_plasmid.fasta").unwrap();
+    let seq = plasmid.split_once('\n').unwrap().1;
+    std::fs::write(
+        "./tests/results/multi_genome.fa",
+        format!(">bin_1_c1\n{}>bin_2_c1\n{}", seq, seq),
+    )
+    .unwrap();
+    let mut cmd = Command::cargo_bin("skani").unwrap();
+    cmd.arg("triangle")
+        .arg("-E")
+        .arg("./tests/results/multi_genome.fa")
+        .arg("--genome-regex")
+        .arg("^(bin_\\d+)_")
+        .arg("-o")
+        .arg("./tests/results/triangle_genome_regex.tsv");
+    cmd.assert().success().code(0);
+    let out = std::fs::read_to_string("./tests/results/triangle_genome_regex.tsv").unwrap();
+    let fields = out.lines().nth(1).unwrap().split('\t').collect::<Vec<&str>>();
+    assert!(fields[0] == "bin_1" && fields[1] == "bin_2");
+
+    //bin_1 in a second file would overwrite bin_1.sketch.
+    std::fs::write("./tests/results/multi_genome_b.fa", format!(">bin_1_c2\n{}", seq)).unwrap();
+    let _ = std::fs::remove_dir_all("./tests/results/test_sketch_dir_regex");
+    let mut cmd = Command::cargo_bin("skani").unwrap();
+    let out = cmd
+        .arg("sketch")
+        .arg("./tests/results/multi_genome.fa")
+        .arg("./tests/results/multi_genome_b.fa")
+        .arg("--genome-regex")
+        .arg("^(bin_\\d+)_")
+        .arg("-o")
+        .arg("./tests/results/test_sketch_dir_regex")
+        .output()
+        .unwrap();
+    assert!(!out.status.success());
+    assert!(std::str::from_utf8(&out.stderr).unwrap().contains("each genome's records must be in one file"));
+}
+
+#[test]
+#[serial]
+fn test_search_individual_contig() {
+    let plasmid = std::fs::read_to_string("./test_files/o157_plasmid.fasta").unwrap();
+    let seq = plasmid.split_once('\n').unwrap().1;
+    std::fs::write(
+        "./tests/results/multi_record.fa",
+        format!(">rec_1\n{}>rec_2\n{}", seq, seq),
+    )
+    .unwrap();
+    let _ = std::fs::remove_dir_all("./tests/results/test_sketch_dir_i");
+    let mut cmd = Command::cargo_bin("skani").unwrap();
+    cmd.arg("sketch")
+        .arg("-i")
+        .arg("./tests/results/multi_record.fa")
+        .arg("-o")
+        .arg("./tests/results/test_sketch_dir_i");
+    cmd.assert().success().code(0);
+
+    let mut cmd = Command::cargo_bin("skani").unwrap();
+    let out = cmd
+        .arg("search")
+        .arg("-d")
+        .arg("./tests/results/test_sketch_dir_i")
+        .arg("./test_files/o157_plasmid.fasta")
+        .output()
+        .unwrap();
+    assert!(out.status.success());
+    let out = std::str::from_utf8(&out.stdout).unwrap();
+    let mut ref_names = out
+        .lines()
+        .skip(1)
+        .map(|x| x.split('\t').nth(5).unwrap())
+        .collect::<Vec<&str>>();
+    ref_names.sort();
+    assert!(ref_names == vec!["rec_1", "rec_2"]);
+}
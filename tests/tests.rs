@@ -5,6 +5,21 @@ use skani::regression::*;
 use skani::file_io::*;
 use skani::params::*;
 use skani::types::*;
+use skani::engine::*;
+use skani::error::*;
+use skani::info::*;
+use skani::db::*;
+use skani::screen::*;
+use skani::search::*;
+use skani::serve::*;
+use skani::core_genome::*;
+use skani::cluster::*;
+use skani::derep::*;
+use skani::tree::*;
+use skani::merge::*;
+use skani::checkpoint::*;
+use skani::grouping::*;
+use skani::sketch::sketch_file_name;
 fn default_params(mode: Mode) -> (CommandParams, SketchParams) {
     let cmd_params = CommandParams {
         screen: false,
@@ -28,13 +43,51 @@ fn default_params(mode: Mode) -> (CommandParams, SketchParams) {
         learned_ani: true,
         learned_ani_cmd: false,
         detailed_out: false,
-        distance: false
+        distance: false,
+        append: false,
+        remove: false,
+        output_format: OutputFormat::Tsv,
+        packed: false,
+        marker_index: false,
+        socket_path: String::new(),
+        chains_file: String::new(),
+        fragment_ani_file: String::new(),
+        aligned_bed_prefix: String::new(),
+        core_ref: String::new(),
+        core_window: 0,
+        core_threshold: 0.,
+        cluster_method: ClusterMethod::Single,
+        cluster_ani: 0.,
+        quality_file: String::new(),
+        quality_column: String::new(),
+        derep_out_dir: String::new(),
+        derep_symlink: false,
+        tree_file: String::new(),
+        tree_method: TreeMethod::Upgma,
+        tree_fill: 0.,
+        stream: false,
+        block_size: 0,
+        shard: 0,
+        num_shards: 0,
+        resume: false,
+        binary: false,
+        genome_map: String::new(),
+        genome_regex: String::new(),
     };
 
     let sketch_params = SketchParams::new(1000, 125, 15, false, false);
     return (cmd_params, sketch_params);
 }
 
+//Empty folder for one test's files under tests/results. The process id keeps
+//concurrent test runs apart.
+fn test_folder(name: &str) -> std::path::PathBuf {
+    let folder = std::path::Path::new("./tests/results").join(format!("{}_{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&folder);
+    std::fs::create_dir_all(&folder).unwrap();
+    folder
+}
+
 #[test]
 fn code_ecoli_test_simple() {
     let (mut command_params, sketch_params) = default_params(Mode::Dist);
@@ -151,3 +204,789 @@ fn NNN_test_code(){
     fmh_seeds(str1, &sketch_params, 0, &mut new_sketch1, true);
     assert!(new_sketch1.kmer_seeds_k.unwrap().len() == 0);
 }
+
+#[test]
+fn engine_dist_test() {
+    let (_, sketch_params) = default_params(Mode::Dist);
+    let engine = AniEngine::from_sketch_params(sketch_params).unwrap();
+    let sketches = engine
+        .sketch_files(&vec!["./test_files/e.coli-W.fasta.gz".to_string()])
+        .unwrap();
+    let results = engine.dist(&sketches, &sketches).unwrap();
+    assert!(results.len() == 1);
+    assert!(results[0].ani >= 0.99);
+
+    let marker_sketch = Sketch::get_markers_only(&sketches[0]);
+    match engine.compare(&sketches[0], &marker_sketch) {
+        Err(SkaniError::BadSketch { .. }) => {}
+        _ => panic!("Marker-only sketch should not be comparable"),
+    }
+    match engine.dist(&[], &sketches) {
+        Err(SkaniError::EmptyInput(_)) => {}
+        _ => panic!("Empty queries should be an error"),
+    }
+    let other_engine =
+        AniEngine::from_sketch_params(SketchParams::new(1000, 200, 15, false, false)).unwrap();
+    match other_engine.compare(&sketches[0], &sketches[0]) {
+        Err(SkaniError::ParameterMismatch(_)) => {}
+        _ => panic!("Sketches with different c should not be comparable"),
+    }
+}
+
+#[test]
+fn sketch_from_records_test() {
+    let (_, sketch_params) = default_params(Mode::Dist);
+    let file_sketch =
+        fastx_to_sketches(&vec!["./test_files/o157_plasmid.fasta".to_string()], &sketch_params, true)[0].clone();
+    let mut records = vec![];
+    let mut reader = needletail::parse_fastx_file("./test_files/o157_plasmid.fasta").unwrap();
+    while let Some(record) = reader.next() {
+        let record = record.unwrap();
+        records.push((record.id().to_vec(), record.seq().to_vec()));
+    }
+    records.push((b"short".to_vec(), b"ACGT".to_vec()));
+    let record_sketch =
+        Sketch::from_records("./test_files/o157_plasmid.fasta", records, &sketch_params).unwrap();
+    assert!(record_sketch == file_sketch);
+
+    let empty: Vec<(&str, &str)> = vec![("short", "ACGT")];
+    assert!(Sketch::from_records("empty", empty, &sketch_params).is_none());
+}
+
+#[test]
+fn alignment_details_test() {
+    let (_, sketch_params) = default_params(Mode::Dist);
+    let engine = AniEngine::from_sketch_params(sketch_params).unwrap();
+    let mut reader = needletail::parse_fastx_file("./test_files/e.coli-W.fasta.gz").unwrap();
+    let genome = reader.next().unwrap().unwrap().seq().to_vec();
+    let ref_sketch = engine.sketch_records("ref", vec![("ref_contig", &genome)]).unwrap();
+    let forward = genome[100000..300000].to_vec();
+    let reverse = genome[500000..700000]
+        .iter()
+        .rev()
+        .map(|x| match x {
+            b'A' => b'T',
+            b'C' => b'G',
+            b'G' => b'C',
+            b'T' => b'A',
+            _ => b'N',
+        })
+        .collect::<Vec<u8>>();
+    let query_sketch = engine
+        .sketch_records("query", vec![("forward", forward), ("reverse", reverse)])
+        .unwrap();
+
+    let (ani_res, details) = engine.compare_detailed(&ref_sketch, &query_sketch).unwrap();
+    let chains = details.chains;
+    assert!(ani_res.ani > 0.99);
+    assert!(chains.iter().any(|x| x.query_contig == "forward"));
+    assert!(chains.iter().any(|x| x.query_contig == "reverse"));
+    for chain in chains.iter() {
+        assert!(chain.ref_contig == "ref_contig" && chain.ref_file == "ref");
+        assert!(chain.query_start < chain.query_end && chain.query_end <= 200000);
+        assert!(chain.fragment_identity.unwrap() > 0.99);
+        //Chains are padded by k + c like the aligned fraction, and clipped at contig ends.
+        let slack = 200;
+        if chain.query_contig == "forward" {
+            assert!(!chain.reverse);
+            assert!((chain.ref_end as i64 - chain.query_end as i64 - 100000).abs() <= slack);
+            assert!((chain.ref_start as i64 - chain.query_start as i64 - 100000).abs() <= slack);
+        } else {
+            assert!(chain.reverse);
+            assert!((chain.ref_start as i64 - (700000 - chain.query_end as i64)).abs() <= slack);
+            assert!((chain.ref_end as i64 - (700000 - chain.query_start as i64)).abs() <= slack);
+        }
+    }
+
+    assert!(!details.fragments.is_empty());
+    for fragment in details.fragments.iter() {
+        assert!(fragment.identity > 0.99 && fragment.num_anchors > 0);
+        assert!(fragment.num_anchors <= fragment.num_seeds);
+        assert!(fragment.query_start < fragment.query_end && fragment.query_end <= 200000);
+        assert!(fragment.ref_start < fragment.ref_end);
+    }
+
+    let total_len = |regions: &Vec<GenomeRegion>| regions.iter().map(|x| (x.end - x.start) as usize).sum::<usize>();
+    assert!(total_len(&details.query_aligned) + total_len(&details.query_unaligned) == 400000);
+    assert!(total_len(&details.query_unaligned) < 20000);
+    assert!(total_len(&details.ref_aligned) + total_len(&details.ref_unaligned) == genome.len());
+    //Padding by k + c can reach just past the ends of the sampled slices.
+    let slack = 200;
+    assert!(details.ref_aligned.iter().all(|x| (x.start + slack >= 100000 && x.end <= 300000 + slack)
+        || (x.start + slack >= 500000 && x.end <= 700000 + slack)));
+    assert!(details.ref_unaligned.iter().any(|x| x.start == 0 && x.end + slack >= 100000));
+    for regions in [&details.query_aligned, &details.ref_unaligned] {
+        for window in regions.windows(2) {
+            assert!(window[0].contig != window[1].contig || window[0].end < window[1].start);
+        }
+    }
+
+    let (_, switched_details) = engine.compare_detailed(&query_sketch, &ref_sketch).unwrap();
+    assert!(switched_details.chains.len() == chains.len());
+    assert!(switched_details.chains.iter().all(|x| x.query_contig == "ref_contig"));
+    assert!(switched_details.fragments.len() == details.fragments.len());
+    assert!(switched_details.fragments[0].fragmented_query != details.fragments[0].fragmented_query);
+}
+
+#[test]
+fn core_window_test() {
+    let mut ref_sketch = Sketch::default();
+    ref_sketch.contigs = vec!["a".to_string(), "b".to_string()];
+    ref_sketch.contig_lengths = vec![2500, 1000];
+    let windows = core_windows(&ref_sketch, 1000);
+    assert!(windows.len() == 4);
+    assert!(windows[2] == GenomeRegion { contig: "a".to_string(), start: 2000, end: 2500 });
+    assert!(windows[3] == GenomeRegion { contig: "b".to_string(), start: 0, end: 1000 });
+
+    let region = |contig: &str, start, end| GenomeRegion { contig: contig.to_string(), start, end };
+    let aligned = vec![region("a", 0, 400), region("a", 600, 1400), region("a", 2300, 2500), region("b", 0, 499)];
+    let present = window_presence(&windows, &aligned);
+    assert!(present == vec![true, false, false, false]);
+    assert!(window_presence(&windows, &[]) == vec![false; 4]);
+}
+
+#[test]
+fn cluster_genomes_test() {
+    let edge = |i, j, ani| ClusterEdge { i, j, ani, af_i: 1., af_j: 1. };
+    let edges = vec![edge(0, 1, 0.99), edge(1, 2, 0.96), edge(3, 4, 0.97)];
+    let order = vec![5, 4, 3, 2, 1, 0];
+    let single = cluster_genomes(6, &edges, ClusterMethod::Single, &order);
+    assert!(single == vec![1, 1, 1, 4, 4, 5]);
+    let complete = cluster_genomes(6, &edges, ClusterMethod::Complete, &order);
+    assert!(complete[0] == 1 && complete[1] == 1 && complete[2] == 2 && complete[5] == 5);
+    assert!(complete[3] == 4 && complete[4] == 4);
+    let greedy = cluster_genomes(6, &edges, ClusterMethod::Greedy, &vec![2, 1, 0, 3, 4, 5]);
+    assert!(greedy == vec![0, 2, 2, 3, 3, 5]);
+
+    //Pairs from a `triangle --binary` matrix must pass both thresholds.
+    let entry = |i, j, ani, af_i, af_j| SparseEntry { i, j, ani, af_i, af_j };
+    let matrix = SparseCoo {
+        names: vec!["a".to_string(), "b".to_string(), "c".to_string(), "d".to_string()],
+        entries: vec![entry(0, 1, 0.99, 0.9, 0.9), entry(0, 2, 0.99, 0.9, 0.1), entry(1, 2, 0.90, 0.9, 0.9), entry(2, 3, 0.97, 0.5, 0.6)],
+    };
+    let edges = matrix_edges(&matrix, 0.95, 0.15);
+    assert!(edges.iter().map(|x| (x.i, x.j)).collect::<Vec<_>>() == vec![(0, 1), (2, 3)]);
+    assert!(edges[1] == ClusterEdge { i: 2, j: 3, ani: 0.97, af_i: 0.5, af_j: 0.6 });
+    assert!(degree_order(5, &[edge(1, 2, 0.99), edge(2, 3, 0.99)]) == vec![2, 1, 3, 0, 4]);
+
+    let mut sketch = Sketch::default();
+    sketch.contig_lengths = vec![10, 50, 20, 20];
+    assert!(n50(&sketch) == 50);
+    sketch.contig_lengths = vec![10, 30, 20, 20];
+    assert!(n50(&sketch) == 20);
+}
+
+#[test]
+fn derep_quality_test() {
+    assert!(genome_stem("./bins/bin.1.fa.gz") == "bin.1");
+    assert!(genome_stem("bin.2.fasta") == "bin.2");
+    assert!(genome_stem("bin.3") == "bin.3");
+
+    let folder = test_folder("quality");
+    let file = folder.join("quality.tsv");
+    let file = file.to_str().unwrap();
+    std::fs::write(file, "Bin Id\tCompleteness\tContamination\tScore\nbin.1\t90\t2\t5\nbin.2.fa\t80\t0\t7\n").unwrap();
+    let quality = read_quality_file(file, "").unwrap();
+    assert!(quality["bin.1"] == 80. && quality["bin.2"] == 80.);
+    let quality = read_quality_file(file, "score").unwrap();
+    assert!(quality["bin.1"] == 5. && quality["bin.2"] == 7.);
+    assert!(read_quality_file(file, "N50").is_err());
+    std::fs::write(file, "genome\tscore\nbin.1\tNA\n").unwrap();
+    assert!(read_quality_file(file, "").is_err());
+    assert!(read_quality_file(file, "score").is_err());
+    //nan and inf parse as f64 but are not usable scores.
+    std::fs::write(file, "genome\tscore\nbin.1\tnan\n").unwrap();
+    assert!(read_quality_file(file, "score").is_err());
+    std::fs::write(file, "genome\tscore\nbin.1\tinf\n").unwrap();
+    assert!(read_quality_file(file, "score").is_err());
+    std::fs::remove_dir_all(&folder).unwrap();
+
+    let mut short = Sketch::default();
+    short.file_name = "bins/bin.1.fa".to_string();
+    short.contig_lengths = vec![1000, 1000];
+    short.total_sequence_length = 2000;
+    let mut long = short.clone();
+    long.file_name = "bins/bin.2.fa".to_string();
+    long.contig_lengths = vec![4000];
+    long.total_sequence_length = 4000;
+    let sketches = vec![short, long];
+    let scores = genome_scores(&sketches, &Default::default());
+    assert!(scores[1] > scores[0]);
+    let quality = [("bin.1".to_string(), 10.)].into_iter().collect();
+    let scores = genome_scores(&sketches, &quality);
+    assert!(scores[0] > scores[1]);
+}
+
+#[test]
+fn tree_test() {
+    let names = ["a", "b", "c", "d", "e"].iter().map(|x| x.to_string()).collect::<Vec<String>>();
+    //Additive distances; neighbor-joining recovers the tree exactly.
+    let dist = vec![
+        vec![0., 5., 9., 9., 8.],
+        vec![5., 0., 10., 10., 9.],
+        vec![9., 10., 0., 8., 7.],
+        vec![9., 10., 8., 0., 3.],
+        vec![8., 9., 7., 3., 0.],
+    ];
+    assert!(
+        neighbor_joining(dist, &names)
+            == "(((a:2.0000,b:3.0000):3.0000,c:4.0000):2.0000,d:2.0000,e:1.0000);"
+    );
+
+    let dist = vec![
+        vec![0., 2., 10., 10.],
+        vec![2., 0., 10., 10.],
+        vec![10., 10., 0., 4.],
+        vec![10., 10., 4., 0.],
+    ];
+    assert!(upgma(dist, &names[..4]) == "((a:1.0000,b:1.0000):4.0000,(c:2.0000,d:2.0000):3.0000);");
+    assert!(upgma(vec![vec![0.]], &names[..1]) == "a;");
+    assert!(newick_name("dir/genome 1.fa") == "'dir/genome 1.fa'");
+    assert!(newick_name("it's") == "'it''s'");
+
+    let mut anis = fxhash::FxHashMap::default();
+    let mut row = fxhash::FxHashMap::default();
+    row.insert(1, AniEstResult { ani: 0.97, ..Default::default() });
+    row.insert(2, AniEstResult { ani: f32::NAN, ..Default::default() });
+    anis.insert(0, row);
+    let dist = distance_matrix(&anis, 3, 50.);
+    assert!((dist[1][0] - 3.).abs() < 1e-4 && dist[0][2] == 50. && dist[1][2] == 50. && dist[2][2] == 0.);
+}
+
+#[test]
+fn sketch_file_header_test() {
+    let (_, sketch_params) = default_params(Mode::Sketch);
+    let sketch = fastx_to_sketches(&vec!["./test_files/o157_plasmid.fasta".to_string()], &sketch_params, true)[0].clone();
+    let folder = test_folder("header");
+    let file = folder.join("header.sketch");
+    let file = file.to_str().unwrap();
+    write_sketch_file(file, &sketch_params, &sketch).unwrap();
+    let header = read_sketch_header(file).unwrap().unwrap();
+    assert!(header.kind == SketchFileKind::Sketch);
+    assert!(header.c == 125 && header.k == 15 && header.marker_c == 1000);
+    let (read_params, read_sketch) = read_sketch_file(file).unwrap();
+    assert!(read_params == sketch_params);
+    assert!(read_sketch == sketch);
+    match read_marker_file(file) {
+        Err(SkaniError::BadSketch { .. }) => {}
+        _ => panic!("A .sketch file should not load as markers.bin"),
+    }
+
+    let mut bytes = std::fs::read(file).unwrap();
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0xff;
+    std::fs::write(file, &bytes).unwrap();
+    match read_sketch_file(file) {
+        Err(SkaniError::BadSketch { reason, .. }) => assert!(reason.contains("checksum")),
+        _ => panic!("Corrupted sketch should fail the checksum"),
+    }
+
+    bytes[8] = 99;
+    std::fs::write(file, &bytes).unwrap();
+    match read_sketch_file(file) {
+        Err(SkaniError::IncompatibleVersion { found, .. }) => assert!(found == 99),
+        _ => panic!("Unknown format version should be rejected"),
+    }
+    std::fs::remove_dir_all(&folder).unwrap();
+
+    //Old sketches without a header still load.
+    assert!(read_sketch_header("./test_files/e.coli-o157.fasta.sketch").unwrap().is_none());
+    assert!(read_sketch_file("./test_files/e.coli-o157.fasta.sketch").is_ok());
+}
+
+#[test]
+fn db_info_test() {
+    let (_, sketch_params) = default_params(Mode::Sketch);
+    let sketches = fastx_to_sketches(
+        &vec!["./test_files/o157_plasmid.fasta".to_string(), "./test_files/e.coli-W.fasta.gz".to_string()],
+        &sketch_params,
+        true,
+    );
+    let folder = test_folder("db_info");
+    let folder = folder.to_str().unwrap();
+    let marker_file = format!("{}/markers.bin", folder);
+    let sketch_file = format!("{}/o157_plasmid.fasta.sketch", folder);
+    let orphan_file = format!("{}/orphan.sketch", folder);
+    let mut orphan = sketches[0].clone();
+    orphan.file_name = "orphan.fasta".to_string();
+    let sketch_files = sketches.iter().map(|x| format!("{}.sketch", base_name(&x.file_name))).collect::<Vec<_>>();
+    write_marker_file(&marker_file, &sketch_params, &sketches.iter().map(Sketch::get_markers_only).collect::<Vec<_>>(), &sketch_files).unwrap();
+    write_sketch_file(&sketch_file, &sketch_params, &sketches[1]).unwrap();
+    write_sketch_file(&orphan_file, &sketch_params, &orphan).unwrap();
+
+    let db_info = get_db_info(Some(&marker_file), &[sketch_file.clone(), orphan_file.clone()], true);
+    assert!(db_info.c == 125 && db_info.k == 15 && db_info.marker_c == 1000);
+    assert!(db_info.num_genomes == 3);
+    assert!(db_info.sketch_files_without_markers == vec![orphan_file]);
+    assert!(db_info.markers_without_sketch_files == vec![sketches[0].file_name.clone()]);
+    let genome = db_info.genomes.iter().find(|x| x.file_name == sketches[1].file_name).unwrap();
+    assert!(genome.in_markers);
+    assert!(genome.num_seeds == Some(sketches[1].kmer_seeds_k.as_ref().unwrap().len()));
+    std::fs::remove_dir_all(folder).unwrap();
+}
+
+#[test]
+fn packed_db_test() {
+    let (_, sketch_params) = default_params(Mode::Sketch);
+    let sketches = fastx_to_sketches(
+        &vec!["./test_files/o157_plasmid.fasta".to_string(), "./test_files/e.coli-W.fasta.gz".to_string()],
+        &sketch_params,
+        true,
+    );
+    let folder = test_folder("packed_db");
+    let mut sketch_files = vec![];
+    for (i, sketch) in sketches.iter().enumerate() {
+        let sketch_file = folder.join(format!("{}.sketch", i)).to_str().unwrap().to_string();
+        write_sketch_file(&sketch_file, &sketch_params, sketch).unwrap();
+        sketch_files.push(sketch_file);
+    }
+    let markers = sketches.iter().map(Sketch::get_markers_only).collect::<Vec<_>>();
+    assert!(!packed_db_exists(&folder));
+    write_packed_db(&folder, &sketch_params, &markers, &sketch_files).unwrap();
+    assert!(packed_db_exists(&folder));
+
+    let packed_db = PackedDb::open(&folder).unwrap();
+    assert!(packed_db.sketch_params == sketch_params);
+    assert!(packed_db.len() == 2);
+    for i in 0..sketches.len() {
+        assert!(packed_db.num_markers(i) == sketches[i].marker_seeds.len());
+        assert!(packed_db.sketch(i) == sketches[i]);
+        assert!(packed_db.check_markers_quickly(i, &sketches[i], 0.95));
+    }
+    assert!(!packed_db.check_markers_quickly(1, &sketches[0], 0.95));
+
+    //Chaining against seeds read in place gives the same result, whichever
+    //genome ends up as the reference.
+    let (command_params, _) = default_params(Mode::Search);
+    for i in 0..sketches.len() {
+        let mapped = packed_db.mapped_sketch(i);
+        assert!(mapped.kmer_seeds_k.is_none() && mapped.packed_seeds.is_some());
+        for query in sketches.iter() {
+            let map_params = map_params_from_sketch(&sketches[i], false, &command_params);
+            let expected = chain_seeds(&sketches[i], query, map_params.clone());
+            let res = chain_seeds(&mapped, query, map_params);
+            //Formatted so that NaN (no shared seeds) compares equal.
+            let fields = |x: &AniEstResult| format!("{:?}", (x.ani, x.align_fraction_ref, x.align_fraction_query));
+            assert!(fields(&res) == fields(&expected));
+        }
+    }
+    std::fs::remove_dir_all(&folder).unwrap();
+}
+
+#[test]
+fn marker_index_file_test() {
+    let (_, sketch_params) = default_params(Mode::Sketch);
+    let sketches = fastx_to_sketches(
+        &vec!["./test_files/o157_plasmid.fasta".to_string(), "./test_files/e.coli-W.fasta.gz".to_string()],
+        &sketch_params,
+        true,
+    );
+    let markers = sketches.iter().map(Sketch::get_markers_only).collect::<Vec<_>>();
+    let folder = test_folder("marker_index");
+    write_marker_index(&folder, &markers).unwrap();
+
+    let index_file = marker_index_file(&folder);
+    let fingerprint = marker_index_fingerprint(markers.iter().map(|x| &x.file_name));
+    assert!(MarkerIndexFile::open(&index_file, 3, fingerprint).is_err());
+    assert!(MarkerIndexFile::open(&index_file, 2, fingerprint.wrapping_add(1)).is_err());
+    let marker_index = MarkerIndexFile::open(&index_file, 2, fingerprint).unwrap();
+    let kmer_to_sketch = kmer_to_sketch_from_refs(&markers);
+    for sketch in sketches.iter() {
+        for marker in sketch.marker_seeds.iter() {
+            let mut from_file = vec![];
+            let mut from_table = vec![];
+            marker_index.for_each_sketch_with(*marker, &mut |x| from_file.push(x));
+            kmer_to_sketch.for_each_sketch_with(*marker, &mut |x| from_table.push(x));
+            assert!(from_file == from_table);
+        }
+        let mut screened = screen_refs(0.95, &marker_index, sketch, &sketch_params, &markers).into_iter().collect::<Vec<_>>();
+        let mut screened_table = screen_refs(0.95, &kmer_to_sketch, sketch, &sketch_params, &markers).into_iter().collect::<Vec<_>>();
+        screened.sort();
+        screened_table.sort();
+        assert!(screened == screened_table);
+    }
+    std::fs::remove_dir_all(&folder).unwrap();
+}
+
+#[test]
+fn serve_connection_test() {
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixStream;
+
+    let (mut command_params, sketch_params) = default_params(Mode::Serve);
+    let sketches = fastx_to_sketches(
+        &vec!["./test_files/o157_plasmid.fasta".to_string(), "./test_files/e.coli-W.fasta.gz".to_string()],
+        &sketch_params,
+        true,
+    );
+    let folder = test_folder("serve");
+    let folder_str = folder.to_str().unwrap();
+    for sketch in sketches.iter() {
+        write_sketch_file(&sketch_file_name(folder_str, sketch, false), &sketch_params, sketch).unwrap();
+    }
+    let markers = sketches.iter().map(Sketch::get_markers_only).collect::<Vec<_>>();
+    let sketch_files = sketches
+        .iter()
+        .map(|x| base_name(&sketch_file_name(folder_str, x, false)).to_string())
+        .collect::<Vec<_>>();
+    write_marker_file(&format!("{}/markers.bin", folder_str), &sketch_params, &markers, &sketch_files).unwrap();
+    command_params.ref_files = std::fs::read_dir(&folder)
+        .unwrap()
+        .map(|x| x.unwrap().path().to_str().unwrap().to_string())
+        .collect();
+    command_params.screen = true;
+    command_params.keep_refs = true;
+    let search_db = SearchDb::load(&command_params);
+
+    let query_with_limit = |bytes: &[u8], max_query_bytes: usize| {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let bytes = bytes.to_vec();
+        let client_thread = std::thread::spawn(move || {
+            client.write_all(&bytes).unwrap();
+            client.shutdown(std::net::Shutdown::Write).unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            response
+        });
+        handle_connection(&search_db, &command_params, server, max_query_bytes).unwrap();
+        client_thread.join().unwrap()
+    };
+    let query = |bytes: &[u8]| query_with_limit(bytes, SERVE_MAX_QUERY_BYTES);
+
+    //Same query twice; the second one uses the kept reference.
+    for _ in 0..2 {
+        let response = query(&std::fs::read("./test_files/e.coli-W.fasta.gz").unwrap());
+        let lines = response.lines().collect::<Vec<_>>();
+        assert!(lines.len() == 2);
+        assert!(lines[0].starts_with("Ref_file\tQuery_file\tANI"));
+        let fields = lines[1].split('\t').collect::<Vec<_>>();
+        assert!(fields[0] == "./test_files/e.coli-W.fasta.gz");
+        assert!(fields[1] == SERVE_QUERY_NAME);
+        assert!(fields[2] == "100.00");
+    }
+    assert!(search_db.num_kept_refs() == 1);
+
+    let ecoli_sketch = sketches.iter().find(|x| x.file_name.contains("e.coli-W")).unwrap();
+    let response = query(&std::fs::read(sketch_file_name(folder_str, ecoli_sketch, false)).unwrap());
+    assert!(response.lines().nth(1).unwrap().split('\t').nth(1) == Some("./test_files/e.coli-W.fasta.gz"));
+
+    assert!(query(b"not a genome").starts_with("ERROR: "));
+    assert!(query(b">short\nACGT\n").starts_with("ERROR: "));
+    let plasmid = std::fs::read("./test_files/o157_plasmid.fasta").unwrap();
+    assert!(query_with_limit(&plasmid, plasmid.len()).lines().count() == 2);
+    assert!(query_with_limit(&plasmid, plasmid.len() - 1).starts_with("ERROR: query is larger than"));
+    std::fs::remove_dir_all(&folder).unwrap();
+}
+
+#[test]
+fn search_individual_contig_test() {
+    let (mut command_params, sketch_params) = default_params(Mode::Search);
+    let folder = test_folder("search_individual");
+    let folder_str = folder.to_str().unwrap();
+
+    //Two records in one file, sketched individually as with `skani sketch -i`.
+    let plasmid = std::fs::read_to_string("./test_files/o157_plasmid.fasta").unwrap();
+    let plasmid_seq = plasmid.lines().skip(1).collect::<String>();
+    let multi_file = format!("{}/multi.fa", folder_str);
+    std::fs::write(
+        &multi_file,
+        format!(">plasmid\n{}\n>plasmid_half\n{}\n", plasmid_seq, &plasmid_seq[..plasmid_seq.len() / 2]),
+    )
+    .unwrap();
+    let sketches = fastx_to_multiple_sketch_rewrite(&vec![multi_file.clone()], &sketch_params, true);
+    assert!(sketches.len() == 2);
+    for sketch in sketches.iter() {
+        write_sketch_file(&sketch_file_name(folder_str, sketch, true), &sketch_params, sketch).unwrap();
+    }
+    let markers = sketches.iter().map(Sketch::get_markers_only).collect::<Vec<_>>();
+    let sketch_files = sketches
+        .iter()
+        .map(|x| base_name(&sketch_file_name(folder_str, x, true)).to_string())
+        .collect::<Vec<_>>();
+    let marker_file = format!("{}/markers.bin", folder_str);
+    write_marker_file(&marker_file, &sketch_params, &markers, &sketch_files).unwrap();
+    let (_, read_markers, read_sketch_files) = read_marker_file_with_sketch_files(&marker_file).unwrap();
+    assert!(read_markers.len() == 2 && read_sketch_files == Some(sketch_files));
+
+    command_params.ref_files = std::fs::read_dir(&folder)
+        .unwrap()
+        .map(|x| x.unwrap().path().to_str().unwrap().to_string())
+        .filter(|x| !x.ends_with(".fa"))
+        .collect();
+    command_params.screen = true;
+    command_params.keep_refs = true;
+    let search_db = SearchDb::load(&command_params);
+    let query = fastx_to_sketches(&vec!["./test_files/o157_plasmid.fasta".to_string()], &sketch_params, true);
+    //The second search uses the kept references; each record is kept separately.
+    for _ in 0..2 {
+        let mut anis = search_db.search_query(&query[0], &command_params);
+        anis.sort_by(|x, y| x.ref_contig.cmp(&y.ref_contig));
+        assert!(anis.len() == 2);
+        assert!(anis[0].ref_contig == "plasmid" && anis[1].ref_contig == "plasmid_half");
+        assert!(anis.iter().all(|x| x.ani > 0.99));
+    }
+    assert!(search_db.num_kept_refs() == 2);
+    std::fs::remove_dir_all(&folder).unwrap();
+}
+
+#[test]
+fn sparse_coo_test() {
+    let folder = test_folder("sparse_coo");
+    let file = folder.join("matrix.bin").to_str().unwrap().to_string();
+    let names = vec!["a.fa".to_string(), "b.fa".to_string(), "c.fa".to_string()];
+    let entries = vec![
+        SparseEntry { i: 0, j: 1, ani: 0.99, af_i: 0.8, af_j: 0.9 },
+        SparseEntry { i: 0, j: 2, ani: 0.95, af_i: 0.5, af_j: 0.6 },
+        SparseEntry { i: 1, j: 2, ani: 0.97, af_i: 0.7, af_j: 0.4 },
+    ];
+    let mut writer = SparseCooWriter::create(&file, &names).unwrap();
+    writer.write_entries(&entries[0..2]).unwrap();
+    writer.write_entries(&entries[2..]).unwrap();
+    drop(writer);
+    let coo = read_sparse_coo(&file).unwrap();
+    assert!(coo.names == names);
+    assert!(coo.entries == entries);
+
+    //Truncated records and a truncated name table are errors.
+    let bytes = std::fs::read(&file).unwrap();
+    std::fs::write(&file, &bytes[..bytes.len() - 3]).unwrap();
+    assert!(matches!(read_sparse_coo(&file), Err(SkaniError::BadMatrix { .. })));
+    std::fs::write(&file, &bytes[..30]).unwrap();
+    assert!(matches!(read_sparse_coo(&file), Err(SkaniError::BadMatrix { .. })));
+
+    //Triangle results with contig names; screened out pairs are dropped.
+    let mut anis = fxhash::FxHashMap::default();
+    let mut row = fxhash::FxHashMap::default();
+    row.insert(2, AniEstResult { ani: 0.95, align_fraction_ref: 0.5, align_fraction_query: 0.6, ..Default::default() });
+    row.insert(1, AniEstResult { ani: 0.99, align_fraction_ref: 0.8, align_fraction_query: 0.9, ..Default::default() });
+    anis.insert(0, row);
+    let mut row = fxhash::FxHashMap::default();
+    row.insert(2, AniEstResult { ani: -1., ..Default::default() });
+    anis.insert(1, row);
+    let sketches = names
+        .iter()
+        .map(|x| Sketch { file_name: x.clone(), contigs: vec![format!("{} contig", x)], ..Default::default() })
+        .collect::<Vec<Sketch>>();
+    write_sparse_coo(&anis, &sketches, &file, true).unwrap();
+    let coo = read_sparse_coo(&file).unwrap();
+    assert!(coo.names == vec!["a.fa contig", "b.fa contig", "c.fa contig"]);
+    assert!(coo.entries == entries[0..2]);
+    std::fs::remove_dir_all(&folder).unwrap();
+}
+
+#[test]
+fn combine_shards_test() {
+    let sketch_params = SketchParams::default();
+    let names = vec![
+        ("a.fa".to_string(), "a".to_string()),
+        ("b.fa".to_string(), "b".to_string()),
+        ("c.fa".to_string(), "c".to_string()),
+    ];
+    let pair = |i: usize, j: usize| (i, j, AniEstResult { ani: 0.99, ..Default::default() });
+    let shard = |shard: usize, anis: Vec<(usize, usize, AniEstResult)>| TriangleShard {
+        shard,
+        num_shards: 2,
+        use_contig_names: false,
+        names: names.clone(),
+        anis,
+    };
+    let shards = || {
+        vec![
+            ("s1".to_string(), sketch_params.clone(), shard(1, vec![pair(1, 2)])),
+            ("s0".to_string(), sketch_params.clone(), shard(0, vec![pair(0, 2), pair(0, 1)])),
+        ]
+    };
+    let (_, merged) = combine_shards(shards()).unwrap();
+    assert!(merged.names == names);
+    assert!(merged.anis.iter().map(|x| (x.0, x.1)).collect::<Vec<_>>() == vec![(0, 1), (0, 2), (1, 2)]);
+
+    let mut missing = shards();
+    missing.pop();
+    assert!(combine_shards(missing).unwrap_err().contains("0/2"));
+    let mut duplicated = shards();
+    duplicated[0].2.shard = 0;
+    assert!(combine_shards(duplicated).is_err());
+    let mut other_genomes = shards();
+    other_genomes[1].2.names.pop();
+    assert!(combine_shards(other_genomes).is_err());
+    //Corrupt shard files are errors, not panics.
+    let mut bad_shard = shards();
+    bad_shard[0].2.shard = 5;
+    assert!(combine_shards(bad_shard).unwrap_err().contains("5/2"));
+    let mut bad_pair = shards();
+    bad_pair[0].2.anis.push(pair(1, 3));
+    assert!(combine_shards(bad_pair).is_err());
+}
+
+#[test]
+fn checkpoint_test() {
+    let folder = test_folder("checkpoint");
+    let (mut command_params, _) = default_params(Mode::Dist);
+    command_params.out_file_name = folder.join("out.tsv").to_str().unwrap().to_string();
+
+    let mut checkpoint = Checkpoint::open(&command_params, true);
+    assert!(!checkpoint.resumed());
+    checkpoint.record(&["a.fa".to_string(), "b.fa\tcontig_1".to_string()]);
+    drop(checkpoint);
+
+    //No output file, so there is nothing to resume.
+    command_params.resume = true;
+    let checkpoint = Checkpoint::open(&command_params, true);
+    assert!(!checkpoint.resumed() && !checkpoint.is_done("a.fa"));
+    drop(checkpoint);
+
+    let mut checkpoint = Checkpoint::open(&command_params, true);
+    checkpoint.record(&["a.fa".to_string()]);
+    drop(checkpoint);
+    std::fs::write(&command_params.out_file_name, "").unwrap();
+    let mut checkpoint = Checkpoint::open(&command_params, true);
+    assert!(checkpoint.resumed() && checkpoint.is_done("a.fa") && !checkpoint.is_done("c.fa"));
+    checkpoint.record(&["c.fa".to_string()]);
+    drop(checkpoint);
+    let checkpoint = Checkpoint::open(&command_params, true);
+    assert!(checkpoint.is_done("a.fa") && checkpoint.is_done("c.fa"));
+    checkpoint.finish();
+    assert!(!std::path::Path::new(&checkpoint_file(&command_params.out_file_name)).exists());
+
+    let mut sketch = Sketch::default();
+    sketch.file_name = "b.fa".to_string();
+    sketch.contigs = vec!["contig_1".to_string()];
+    assert!(query_name(&sketch, false) == "b.fa");
+    assert!(query_name(&sketch, true) == "b.fa\tcontig_1");
+    std::fs::remove_dir_all(&folder).unwrap();
+}
+
+#[test]
+fn output_order_test() {
+    let res = |query: &str, reference: &str, ani: f32| AniEstResult {
+        ani,
+        query_file: query.to_string(),
+        query_contig: query.to_string(),
+        ref_file: reference.to_string(),
+        ref_contig: reference.to_string(),
+        ..Default::default()
+    };
+    //Queries keep their input order; ties in ANI are ordered by reference.
+    let anis = vec![
+        res("q2.fa", "r2.fa", 0.98),
+        res("q1.fa", "r3.fa", 0.95),
+        res("q2.fa", "r1.fa", 0.98),
+        res("q1.fa", "r1.fa", 0.97),
+        res("q2.fa", "r3.fa", 0.99),
+    ];
+    let mut buf = vec![];
+    write_query_ref_list_to(&mut buf, &anis, 1000, false, false, false, OutputFormat::Tsv, false);
+    let pairs = String::from_utf8(buf)
+        .unwrap()
+        .lines()
+        .skip(1)
+        .map(|x| {
+            let fields = x.split('\t').collect::<Vec<&str>>();
+            format!("{} {}", fields[1], fields[0])
+        })
+        .collect::<Vec<String>>();
+    assert!(pairs == vec!["q2.fa r3.fa", "q2.fa r1.fa", "q2.fa r2.fa", "q1.fa r1.fa", "q1.fa r3.fa"]);
+
+    let folder = test_folder("output_order");
+    let file = folder.join("output_order.tsv");
+    let file = file.to_str().unwrap();
+    let mut sparse: fxhash::FxHashMap<usize, fxhash::FxHashMap<usize, AniEstResult>> = fxhash::FxHashMap::default();
+    for i in (0..20).rev() {
+        for j in (i + 1..20).rev() {
+            let name = |x: usize| format!("g{}.fa", x);
+            sparse.entry(i).or_insert(fxhash::FxHashMap::default()).insert(j, res(&name(j), &name(i), 0.99));
+        }
+    }
+    write_sparse_matrix(&sparse, &vec![], file, false, false, false, OutputFormat::Tsv, false);
+    let pairs = std::fs::read_to_string(file)
+        .unwrap()
+        .lines()
+        .skip(1)
+        .map(|x| {
+            let fields = x.split('\t').collect::<Vec<&str>>();
+            (fields[0].to_string(), fields[1].to_string())
+        })
+        .collect::<Vec<(String, String)>>();
+    let mut expected = vec![];
+    for i in 0..20 {
+        for j in i + 1..20 {
+            expected.push((format!("g{}.fa", i), format!("g{}.fa", j)));
+        }
+    }
+    assert!(pairs == expected);
+    std::fs::remove_dir_all(&folder).unwrap();
+}
+
+#[test]
+fn open_fastx_test() {
+    let folder = test_folder("open_fastx");
+    let gz = folder.join("a.fa.gz").to_str().unwrap().to_string();
+    std::fs::copy("./test_files/e.coli-W.fasta.gz", &gz).unwrap();
+    let mut reader = open_fastx(&gz).unwrap();
+    assert!(reader.next().unwrap().unwrap().id().starts_with(b"NC_017664.1"));
+
+    //Fasta with fewer bytes than the zstd magic.
+    let short = folder.join("short.fa").to_str().unwrap().to_string();
+    std::fs::write(&short, ">a").unwrap();
+    assert!(open_fastx(&short).is_ok());
+
+    let zst = folder.join("a.fa.zst").to_str().unwrap().to_string();
+    let fasta = std::fs::read("./test_files/o157_plasmid.fasta").unwrap();
+    std::fs::write(&zst, zstd::encode_all(&fasta[..], 3).unwrap()).unwrap();
+    let mut reader = open_fastx(&zst).unwrap();
+    let record = reader.next().unwrap().unwrap();
+    let expected = std::fs::read_to_string("./test_files/o157_plasmid.fasta").unwrap();
+    assert!(record.seq().len() == expected.lines().skip(1).map(|x| x.len()).sum::<usize>());
+    assert!(reader.next().is_none());
+
+    //Truncated zstd data is an error.
+    std::fs::write(&zst, [0x28, 0xB5, 0x2F, 0xFD, 0, 0, 0, 0]).unwrap();
+    assert!(open_fastx(&zst).is_err());
+    std::fs::remove_dir_all(&folder).unwrap();
+}
+
+#[test]
+fn genome_grouping_test() {
+    let folder = test_folder("genome_grouping");
+    let plasmid = std::fs::read_to_string("./test_files/o157_plasmid.fasta").unwrap();
+    let seq = plasmid.split_once('\n').unwrap().1;
+    let multi = folder.join("multi.fa").to_str().unwrap().to_string();
+    std::fs::write(
+        &multi,
+        format!(">bin_1_c1 x\n{}>bin_2_c1\n{}>bin_1_c2\n{}>unbinned\n{}", seq, seq, seq, seq),
+    )
+    .unwrap();
+    let map_file = folder.join("map.tsv").to_str().unwrap().to_string();
+    std::fs::write(&map_file, "# contig\tbin\nbin_1_c1\tbin_1\nbin_2_c1\tbin_2\n\nbin_1_c2\tbin_1\n").unwrap();
+
+    let map = read_genome_map(&map_file).unwrap();
+    assert!(map.len() == 3 && map["bin_1_c2"] == "bin_1");
+    let grouping = GenomeGrouping::Map(map);
+    assert!(grouping.genome("bin_1_c1 x") == Some("bin_1".to_string()));
+    assert!(grouping.genome("unbinned").is_none());
+    let regex = GenomeGrouping::Regex(regex::Regex::new("^(bin_\\d+)_").unwrap());
+    assert!(regex.genome("bin_2_c1") == Some("bin_2".to_string()));
+    assert!(regex.genome("unbinned").is_none());
+
+    //The same contig in two genomes is an error.
+    std::fs::write(&map_file, "c1\tbin_1\nc1\tbin_2\n").unwrap();
+    assert!(read_genome_map(&map_file).is_err());
+
+    let sketch_params = SketchParams::new(1000, 125, 15, false, false);
+    for grouping in [grouping, regex] {
+        let sketches = fastx_to_grouped_sketches(&vec![multi.clone()], &grouping, &sketch_params, true);
+        assert!(sketches.len() == 2);
+        assert!(sketches[0].file_name == "bin_1" && sketches[0].contigs.len() == 2);
+        assert!(sketches[1].file_name == "bin_2" && sketches[1].contigs.len() == 1);
+    }
+
+    //Files without any grouped record are one genome.
+    let sketches = fastx_to_grouped_sketches(
+        &vec!["./test_files/o157_plasmid.fasta".to_string()],
+        &GenomeGrouping::Regex(regex::Regex::new("^(bin_\\d+)_").unwrap()),
+        &sketch_params,
+        true,
+    );
+    assert!(sketches.len() == 1 && sketches[0].file_name == "./test_files/o157_plasmid.fasta");
+    std::fs::remove_dir_all(&folder).unwrap();
+}